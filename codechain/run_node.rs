@@ -33,7 +33,7 @@ use clap::ArgMatches;
 use clogger::{self, LoggerConfig};
 use cnetwork::{Filters, NetworkConfig, NetworkControl, NetworkService, SocketAddr};
 use creactor::EventLoop;
-use csync::{BlockSyncExtension, ParcelSyncExtension, SnapshotService};
+use csync::{BlockSyncConfig, BlockSyncExtension, ParcelSyncExtension, SnapshotService};
 use ctrlc::CtrlC;
 use fdlimit::raise_fd_limit;
 use parking_lot::{Condvar, Mutex};
@@ -248,7 +248,11 @@ pub fn run_node(matches: ArgMatches) -> Result<(), String> {
             }
 
             if config.network.sync.unwrap() {
-                let sync = BlockSyncExtension::new(client.client());
+                let sync_config = BlockSyncConfig {
+                    network_id: scheme.params().network_id,
+                    ..BlockSyncConfig::default()
+                };
+                let sync = BlockSyncExtension::new(client.client(), sync_config);
                 service.register_extension(sync.clone());
                 client.client().add_notify(sync.clone());
             }
@@ -519,6 +519,16 @@ impl BlockChainClient for Client {
     fn custom_handlers(&self) -> Vec<Arc<ActionHandler>> {
         self.state_db.read().custom_handlers().to_vec()
     }
+
+    fn pruning_floor(&self) -> Option<BlockNumber> {
+        let state_db = self.state_db.read();
+        let journal_db = state_db.journal_db();
+        if journal_db.is_pruned() {
+            journal_db.earliest_era()
+        } else {
+            None
+        }
+    }
 }
 
 pub struct Importer {
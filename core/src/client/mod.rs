@@ -226,6 +226,9 @@ pub trait BlockChainClient: Sync + Send + AccountData + BlockChain + ImportBlock
     fn transaction_invoice(&self, id: TransactionId) -> Option<TransactionInvoice>;
 
     fn custom_handlers(&self) -> Vec<Arc<ActionHandler>>;
+
+    /// The lowest block number whose state is kept, or `None` if no state is pruned.
+    fn pruning_floor(&self) -> Option<BlockNumber>;
 }
 
 /// Result of import block operation.
@@ -33,7 +33,7 @@
 use std::collections::HashMap;
 use std::mem;
 use std::sync::atomic::{AtomicUsize, Ordering as AtomicOrder};
-use std::sync::Arc;
+use std::sync::{Arc, Weak};
 
 use ckey::{public_to_address, Address, Generator, NetworkId, Public, Random};
 use cmerkle::skewed_merkle_root;
@@ -54,13 +54,13 @@ use super::super::blockchain::ParcelAddress;
 use super::super::blockchain_info::BlockChainInfo;
 use super::super::client::ImportResult;
 use super::super::client::{
-    AccountData, Balance, BlockChain, BlockChainClient, BlockInfo, BlockProducer, BlockStatus, ChainInfo, ImportBlock,
-    ImportSealedBlock, MiningBlockChainClient, Nonce, ParcelInfo, PrepareOpenBlock, RegularKeyOwner, ReopenBlock,
-    StateOrBlock, TransactionInfo,
+    AccountData, Balance, BlockChain, BlockChainClient, BlockInfo, BlockProducer, BlockStatus, ChainInfo, ChainNotify,
+    ImportBlock, ImportSealedBlock, MiningBlockChainClient, Nonce, ParcelInfo, PrepareOpenBlock, RegularKeyOwner,
+    ReopenBlock, StateOrBlock, TransactionInfo,
 };
 use super::super::db::{COL_STATE, NUM_COLUMNS};
 use super::super::encoded;
-use super::super::error::BlockImportError;
+use super::super::error::{BlockImportError, ImportError};
 use super::super::header::Header as BlockHeader;
 use super::super::miner::{Miner, MinerService, ParcelImportResult};
 use super::super::parcel::{LocalizedParcel, SignedParcel};
@@ -73,6 +73,8 @@ pub struct TestBlockChainClient {
     pub blocks: RwLock<HashMap<H256, Bytes>>,
     /// Mapping of numbers to hashes.
     pub numbers: RwLock<HashMap<usize, H256>>,
+    /// Headers imported without bodies.
+    pub headers: RwLock<HashMap<H256, Bytes>>,
     /// Genesis block hash.
     pub genesis_hash: H256,
     /// Last block hash.
@@ -99,6 +101,8 @@ pub struct TestBlockChainClient {
     pub latest_block_timestamp: RwLock<u64>,
     /// Pruning history size to report.
     pub history: RwLock<Option<u64>>,
+    /// Actors notified on imported headers.
+    notify: RwLock<Vec<Weak<ChainNotify>>>,
 }
 
 impl Default for TestBlockChainClient {
@@ -135,6 +139,7 @@ impl TestBlockChainClient {
         let mut client = TestBlockChainClient {
             blocks: RwLock::new(HashMap::new()),
             numbers: RwLock::new(HashMap::new()),
+            headers: RwLock::new(HashMap::new()),
             genesis_hash,
             extra_data,
             last_hash: RwLock::new(genesis_hash),
@@ -148,6 +153,7 @@ impl TestBlockChainClient {
             scheme,
             latest_block_timestamp: RwLock::new(10_000_000),
             history: RwLock::new(None),
+            notify: RwLock::new(Vec::new()),
         };
 
         // insert genesis hash.
@@ -156,6 +162,11 @@ impl TestBlockChainClient {
         client
     }
 
+    /// Adds an actor to be notified on imported headers. Unlike `Client`, it's notified synchronously.
+    pub fn add_notify(&self, target: Arc<ChainNotify>) {
+        self.notify.write().push(Arc::downgrade(&target));
+    }
+
     /// Set the balance of account `address` to `balance`.
     pub fn set_balance(&self, address: Address, balance: U256) {
         self.balances.write().insert(address, balance);
@@ -189,6 +200,7 @@ impl TestBlockChainClient {
             header.set_score(From::from(n));
             header.set_parent_hash(self.last_hash.read().clone());
             header.set_number(n as BlockNumber);
+            header.set_timestamp(n as u64);
             header.set_extra_data(self.extra_data.clone());
             let mut parcels = Vec::new();
             for _ in 0..parcel_length {
@@ -378,7 +390,13 @@ impl ChainInfo for TestBlockChainClient {
 impl BlockInfo for TestBlockChainClient {
     fn block_header(&self, id: BlockId) -> Option<encoded::Header> {
         self.block_hash(id)
-            .and_then(|hash| self.blocks.read().get(&hash).map(|r| Rlp::new(r).at(0).as_raw().to_vec()))
+            .and_then(|hash| {
+                self.blocks
+                    .read()
+                    .get(&hash)
+                    .map(|r| Rlp::new(r).at(0).as_raw().to_vec())
+                    .or_else(|| self.headers.read().get(&hash).cloned())
+            })
             .map(encoded::Header::new)
     }
 
@@ -387,7 +405,7 @@ impl BlockInfo for TestBlockChainClient {
     }
 
     fn best_header(&self) -> encoded::Header {
-        unimplemented!()
+        self.best_block_header()
     }
 
     fn block(&self, id: BlockId) -> Option<encoded::Block> {
@@ -434,7 +452,7 @@ impl ImportBlock for TestBlockChainClient {
                 *score = *score + header.score().clone();
             }
             mem::replace(&mut *self.last_hash.write(), h.clone());
-            mem::replace(&mut *self.last_parcels_root.write(), h.clone());
+            mem::replace(&mut *self.last_parcels_root.write(), header.parcels_root().clone());
             self.blocks.write().insert(h.clone(), b);
             self.numbers.write().insert(number, h.clone());
             let mut parent_hash = header.parent_hash().clone();
@@ -453,8 +471,21 @@ impl ImportBlock for TestBlockChainClient {
         Ok(h)
     }
 
-    fn import_header(&self, _bytes: Bytes) -> Result<H256, BlockImportError> {
-        unimplemented!()
+    fn import_header(&self, bytes: Bytes) -> Result<H256, BlockImportError> {
+        let header = Rlp::new(&bytes).as_val::<BlockHeader>();
+        let h = header.hash();
+        if self.block_header(BlockId::Hash(h)).is_some() {
+            return Err(BlockImportError::Import(ImportError::AlreadyInChain))
+        }
+        self.headers.write().insert(h, bytes);
+
+        // Every imported header is treated as the new best header
+        for np in self.notify.read().iter() {
+            if let Some(n) = np.upgrade() {
+                n.new_headers(vec![h], vec![], vec![h], vec![], vec![], 0);
+            }
+        }
+        Ok(h)
     }
 }
 
@@ -503,8 +534,14 @@ impl BlockChainClient for TestBlockChainClient {
         }
     }
 
-    fn block_total_score(&self, _id: BlockId) -> Option<U256> {
-        Some(U256::zero())
+    fn block_total_score(&self, id: BlockId) -> Option<U256> {
+        let mut header: BlockHeader = self.block_header(id)?.decode();
+        let mut total_score = *header.score();
+        while header.number() > 0 {
+            header = self.block_header(BlockId::Hash(*header.parent_hash()))?.decode();
+            total_score = total_score + *header.score();
+        }
+        Some(total_score)
     }
 
     fn block_hash(&self, id: BlockId) -> Option<H256> {
@@ -530,6 +567,10 @@ impl BlockChainClient for TestBlockChainClient {
     fn custom_handlers(&self) -> Vec<Arc<ActionHandler>> {
         unimplemented!()
     }
+
+    fn pruning_floor(&self) -> Option<BlockNumber> {
+        self.history.read().map(|history| self.chain_info().best_block_number.saturating_sub(history))
+    }
 }
 
 impl super::EngineClient for TestBlockChainClient {
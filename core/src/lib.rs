@@ -85,11 +85,11 @@ pub use client::{
 };
 pub use consensus::EngineType;
 pub use db::COL_STATE;
-pub use error::{BlockImportError, Error, ImportError};
+pub use error::{BlockError, BlockImportError, Error, ImportError};
 pub use header::{Header, Seal};
 pub use miner::{Miner, MinerOptions, MinerService, Stratum, StratumConfig, StratumError};
 pub use parcel::{LocalizedParcel, SignedParcel, UnverifiedParcel};
 pub use scheme::Scheme;
 pub use service::ClientService;
 pub use shard_validator::{ShardValidator, ShardValidatorClient, ShardValidatorConfig};
-pub use types::{BlockId, ParcelId};
+pub use types::{BlockId, BlockStatus, ParcelId};
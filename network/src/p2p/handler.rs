@@ -263,14 +263,22 @@ impl Handler {
                 match msg.body() {
                     NegotiationBody::Request {
                         ref extension_name,
-                        ..
+                        ref extension_versions,
                     } => {
                         let seq = msg.seq();
-                        // FIXME: version negotiation
-                        const VERSION: Version = 0;
-                        if self.connections.enqueue_negotiation_allowed(stream, seq, VERSION) {
+                        // The highest version supported by both sides is used, and the first one if there's none
+                        const DEFAULT_VERSION: Version = 0;
+                        let version = client
+                            .extension_versions()
+                            .into_iter()
+                            .filter(|&(ref name, _)| name == extension_name)
+                            .flat_map(|(_, versions)| versions)
+                            .filter(|version| extension_versions.contains(version))
+                            .max()
+                            .unwrap_or(DEFAULT_VERSION);
+                        if self.connections.enqueue_negotiation_allowed(stream, seq, version) {
                             let node_id = self.connections.node_id(&stream).ok_or(Error::InvalidStream(*stream))?;
-                            client.on_node_added(&extension_name, &node_id, VERSION);
+                            client.on_node_added(&extension_name, &node_id, version);
                         } else {
                             return Err(format!("Cannot enqueue negotiation message for {}", stream).into())
                         }
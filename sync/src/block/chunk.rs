@@ -0,0 +1,174 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::HashMap;
+use std::time::{Duration, Instant};
+
+use cnetwork::NodeId;
+
+struct Chunks {
+    total: u64,
+    received: HashMap<u64, Vec<u8>>,
+    received_bytes: usize,
+    first_received_at: Instant,
+}
+
+impl Chunks {
+    fn new(total: u64, now: Instant) -> Self {
+        Self {
+            total,
+            received: HashMap::new(),
+            received_bytes: 0,
+            first_received_at: now,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ChunkError {
+    /// The peer is sending more chunked messages at once than allowed
+    TooManyMessages,
+    /// The chunks received so far are larger than a message can be, so they're discarded
+    TooLarge,
+}
+
+/// Reassembles messages which are split into chunks by the sender
+pub struct ChunkAssembler {
+    timeout: Duration,
+    max_messages_per_peer: usize,
+    max_message_bytes: usize,
+    chunks: HashMap<(NodeId, u64), Chunks>,
+}
+
+impl ChunkAssembler {
+    pub fn new(timeout: Duration, max_messages_per_peer: usize, max_message_bytes: usize) -> Self {
+        Self {
+            timeout,
+            max_messages_per_peer,
+            max_message_bytes,
+            chunks: HashMap::new(),
+        }
+    }
+
+    /// Returns the original message bytes once every chunk of the message is received
+    pub fn insert(
+        &mut self,
+        from: NodeId,
+        id: u64,
+        seq: u64,
+        total: u64,
+        data: Vec<u8>,
+        now: Instant,
+    ) -> Result<Option<Vec<u8>>, ChunkError> {
+        debug_assert!(seq < total);
+        if !self.chunks.contains_key(&(from, id)) {
+            let pending = self.chunks.keys().filter(|(peer, _)| *peer == from).count();
+            if pending >= self.max_messages_per_peer {
+                return Err(ChunkError::TooManyMessages)
+            }
+        }
+        let (is_completed, is_too_large) = {
+            let chunks = self.chunks.entry((from, id)).or_insert_with(|| Chunks::new(total, now));
+            if chunks.total != total {
+                *chunks = Chunks::new(total, now);
+            }
+            chunks.received_bytes += data.len();
+            if let Some(previous) = chunks.received.insert(seq, data) {
+                chunks.received_bytes -= previous.len();
+            }
+            (chunks.received.len() as u64 == total, chunks.received_bytes > self.max_message_bytes)
+        };
+        if is_too_large {
+            self.chunks.remove(&(from, id));
+            return Err(ChunkError::TooLarge)
+        }
+        if !is_completed {
+            return Ok(None)
+        }
+
+        let mut chunks = self.chunks.remove(&(from, id)).expect("Completed chunks must exist");
+        Ok(Some((0..total).flat_map(|seq| chunks.received.remove(&seq).expect("Every chunk is received")).collect()))
+    }
+
+    /// Discards incomplete messages whose first chunk arrived earlier than the timeout
+    pub fn remove_expired(&mut self, now: Instant) {
+        let timeout = self.timeout;
+        self.chunks.retain(|_, chunks| now.duration_since(chunks.first_received_at) < timeout);
+    }
+
+    pub fn remove_peer(&mut self, id: &NodeId) {
+        self.chunks.retain(|(from, _), _| from != id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use cnetwork::{NodeId, SocketAddr};
+
+    use super::{ChunkAssembler, ChunkError};
+
+    fn node_id() -> NodeId {
+        SocketAddr::v4(127, 0, 0, 1, 3485).into()
+    }
+
+    #[test]
+    fn reassemble_chunks() {
+        let mut assembler = ChunkAssembler::new(Duration::from_secs(10), 2, 1024);
+        let now = Instant::now();
+        assert_eq!(Ok(None), assembler.insert(node_id(), 0, 0, 3, vec![1, 2], now));
+        assert_eq!(Ok(None), assembler.insert(node_id(), 0, 1, 3, vec![3, 4], now));
+        assert_eq!(Ok(Some(vec![1, 2, 3, 4, 5])), assembler.insert(node_id(), 0, 2, 3, vec![5], now));
+    }
+
+    #[test]
+    fn reassemble_out_of_order_chunks() {
+        let mut assembler = ChunkAssembler::new(Duration::from_secs(10), 2, 1024);
+        let now = Instant::now();
+        assert_eq!(Ok(None), assembler.insert(node_id(), 0, 2, 3, vec![5], now));
+        assert_eq!(Ok(None), assembler.insert(node_id(), 1, 0, 2, vec![6], now));
+        assert_eq!(Ok(None), assembler.insert(node_id(), 0, 0, 3, vec![1, 2], now));
+        assert_eq!(Ok(Some(vec![1, 2, 3, 4, 5])), assembler.insert(node_id(), 0, 1, 3, vec![3, 4], now));
+        assert_eq!(Ok(Some(vec![6, 7])), assembler.insert(node_id(), 1, 1, 2, vec![7], now));
+    }
+
+    #[test]
+    fn incomplete_chunks_expire() {
+        let timeout = Duration::from_secs(10);
+        let mut assembler = ChunkAssembler::new(timeout, 2, 1024);
+        let now = Instant::now();
+        assert_eq!(Ok(None), assembler.insert(node_id(), 0, 0, 3, vec![1, 2], now));
+        assert_eq!(Ok(None), assembler.insert(node_id(), 0, 2, 3, vec![5], now));
+
+        let later = now + timeout + Duration::from_secs(1);
+        assembler.remove_expired(later);
+        assert_eq!(Ok(None), assembler.insert(node_id(), 0, 1, 3, vec![3, 4], later));
+    }
+
+    #[test]
+    fn chunked_messages_are_limited_in_number_and_size() {
+        let mut assembler = ChunkAssembler::new(Duration::from_secs(10), 2, 4);
+        let now = Instant::now();
+        assert_eq!(Ok(None), assembler.insert(node_id(), 0, 0, 2, vec![1], now));
+        assert_eq!(Ok(None), assembler.insert(node_id(), 1, 0, 2, vec![1], now));
+        assert_eq!(Err(ChunkError::TooManyMessages), assembler.insert(node_id(), 2, 0, 2, vec![1], now));
+
+        assert_eq!(Err(ChunkError::TooLarge), assembler.insert(node_id(), 0, 1, 2, vec![2, 3, 4, 5], now));
+        // The discarded message doesn't count anymore
+        assert_eq!(Ok(None), assembler.insert(node_id(), 2, 0, 2, vec![1], now));
+    }
+}
@@ -0,0 +1,228 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::sync::Arc;
+use std::time::Duration;
+
+use ckey::NetworkId;
+use cnetwork::NodeId;
+use ctypes::BlockNumber;
+
+use super::extension::SyncPhase;
+use super::fork_choice::{ForkChoice, HighestScore};
+use super::seal::SealVerifier;
+
+/// How downloaded headers are verified before being imported
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum VerificationLevel {
+    /// Every header is verified against its parent
+    Full,
+    /// Headers below the checkpoint are only checked to be linked to their parents
+    LightBelowCheckpoint(BlockNumber),
+    /// Headers are only checked to be linked to their parents, and the rest is left to the client
+    None,
+}
+
+impl VerificationLevel {
+    pub fn is_full(&self, number: BlockNumber) -> bool {
+        match *self {
+            VerificationLevel::Full => true,
+            VerificationLevel::LightBelowCheckpoint(checkpoint) => number >= checkpoint,
+            VerificationLevel::None => false,
+        }
+    }
+}
+
+pub struct Config {
+    /// Peers on a different network are ignored even if their genesis is the same
+    pub network_id: NetworkId,
+    /// Soft fork features advertised to peers
+    pub features: Vec<u32>,
+    /// Block numbers of the hard forks in ascending order, which peers on the same genesis must agree on
+    pub fork_blocks: Vec<BlockNumber>,
+    /// Peers which don't advertise a fork id, including the versions sending only scores and hashes, are rejected
+    /// instead of being treated as older versions
+    pub require_fork_id: bool,
+    /// Amount of reputation a penalized peer recovers on each sync timer tick
+    pub reputation_decay: i64,
+    /// Shuffle the hashes of each body request to spread the load across the chain
+    pub shuffle_body_request: bool,
+    /// Nodes added after this many peers are ignored, or no limit if `None`
+    pub target_peers: Option<usize>,
+    /// The pivot peer is pinned to this peer while it's connected
+    pub pivot_peer: Option<NodeId>,
+    /// Trusted peers which are always requested before the others
+    pub preferred_peers: Vec<NodeId>,
+    /// Download headers of a peer whose best block differs from ours with the same total score
+    pub evaluate_equal_score_fork: bool,
+    /// Requests are not sent to a peer once the estimated size of its pending responses reaches this
+    pub max_inflight_bytes: usize,
+    /// Split pending bodies evenly across all idle peers instead of filling one request at a time
+    pub aggressive: bool,
+    /// Bodies requests, both sent and served, contain at most this many hashes
+    pub max_body_request_length: usize,
+    /// Bodies aren't requested while the downloaded bodies waiting to be imported take this many bytes
+    pub max_staged_body_bytes: usize,
+    /// Size of a bodies response served to a peer without reputation
+    pub min_body_response_bytes: usize,
+    /// Size of a bodies response served to a peer with the highest reputation
+    pub max_body_response_bytes: usize,
+    /// Peers claiming a better chain are not treated as ahead of us until they prove their total score,
+    /// which is verified with `seal_verifier`. Claims which can't be verified are applied unproven
+    pub verify_score_proof: bool,
+    /// Headers responses are truncated to fit in this size
+    pub max_header_response_bytes: usize,
+    /// Number of recently served headers kept in memory
+    pub header_cache_size: usize,
+    /// Sync toward the highest peer as of a moment, and apply newer statuses only after reaching it
+    pub lock_sync_target: bool,
+    /// Headers are downloaded along a skeleton of sparse headers while a peer is estimated to be this many blocks ahead
+    pub skeleton_threshold: u64,
+    /// The skeleton is downloaded near the best block of a peer, whose header is requested by its hash,
+    /// and the older headers are downloaded in sequence up to the skeleton
+    pub tip_first: bool,
+    /// Headers resent by a peer are discarded cheaply while they're at most this many blocks behind the downloaded ones
+    pub header_skip_lookback: u64,
+    /// Peers are dropped if no common ancestor is found within this many blocks behind our best block
+    pub max_ancestor_search_depth: u64,
+    /// Headers aren't requested while they lead the best block by this many blocks
+    pub max_header_lead: u64,
+    /// A headers request which isn't answered within this timeout is sent again
+    pub header_request_timeout: Duration,
+    /// Bodies of a request which isn't answered within this timeout are requested again
+    pub body_request_timeout: Duration,
+    /// Peers whose last response took longer than this are requested only after the others
+    pub slow_peer_latency: Duration,
+    /// A peer which has served less than this ratio of the bytes served to it is a leech
+    pub leech_fairness_ratio: f64,
+    /// Peers aren't judged as leeches until this many bytes are served to them
+    pub min_leech_bytes: usize,
+    /// Leeches are requested after the other peers
+    pub deprioritize_leeches: bool,
+    /// The sync timer ticks at this interval while there is nothing to download and no peer is ahead of us
+    pub idle_timer_interval: Duration,
+    /// A peer with a different genesis is probed again once after this delay, or dropped at once if `None`
+    pub genesis_mismatch_retry: Option<Duration>,
+    /// Peers which don't send a valid status in this long after connected are dropped, or never if `None`
+    pub handshake_timeout: Option<Duration>,
+    /// Isolation is reported after there has been no peer to sync with for this long
+    pub no_peers_window: Duration,
+    /// Peers ahead of us are dropped if the best block doesn't advance for this long, or never if `None`
+    pub stall_window: Option<Duration>,
+    /// Called with the new phase whenever the sync phase changes
+    pub on_phase_change: Option<Box<Fn(SyncPhase) + Send + Sync>>,
+    /// `on_sync_deadline` is called once if sync hasn't completed this long after starting, or never if `None`
+    pub sync_deadline: Option<Duration>,
+    /// Called when the sync deadline has passed, e.g. to fail a test harness fast
+    pub on_sync_deadline: Option<Box<Fn() + Send + Sync>>,
+    /// Peer diversity is reported as low if fewer peers than this are connected while we're behind
+    pub min_diverse_peers: usize,
+    /// Called when peer diversity is low, e.g. to connect to more peers
+    pub on_low_peer_diversity: Option<Box<Fn() + Send + Sync>>,
+    /// Only observe the statuses of peers, never requesting, importing or announcing blocks
+    pub observer: bool,
+    /// Requests from peers are still served in observer mode
+    pub observer_serves_requests: bool,
+    /// Record every received message to replay it later
+    pub record_messages: bool,
+    /// Received messages larger than this are dropped without being decoded
+    pub max_message_bytes: usize,
+    /// Number of the latest block hashes announced after imports, which are sent after the status if more than one
+    pub announcement_depth: usize,
+    /// Status broadcasts after imports are coalesced to at most one in this window, or sent on every import if `None`
+    pub status_broadcast_window: Option<Duration>,
+    /// Small messages to the same peer are sent together once in this window, or one by one if `None`
+    pub batch_window: Option<Duration>,
+    /// Messages of each type exchanged in this window are counted for the message histogram
+    pub message_histogram_window: Duration,
+    /// Messages larger than this are split into chunks
+    pub chunk_bytes: usize,
+    /// Incomplete chunked messages are discarded after this timeout
+    pub chunk_timeout: Duration,
+    /// Verification of downloaded headers, which is lighter below a trusted checkpoint if configured
+    pub verification_level: VerificationLevel,
+    /// Seals of downloaded headers are verified by this before being imported, or left to the client if `None`
+    pub seal_verifier: Option<Arc<SealVerifier>>,
+    /// Number of threads kept for verifying the seals of downloaded headers
+    pub seal_verification_parallelism: usize,
+    /// Decides whether a peer's chain is better than ours
+    pub fork_choice: Arc<ForkChoice>,
+    /// Reports the load of the node from 0 to 1, and fewer peers are requested on each tick as it gets higher
+    pub load_probe: Box<Fn() -> f64 + Send + Sync>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            network_id: NetworkId::default(),
+            features: Vec::new(),
+            fork_blocks: Vec::new(),
+            require_fork_id: false,
+            reputation_decay: 1,
+            shuffle_body_request: false,
+            target_peers: None,
+            pivot_peer: None,
+            preferred_peers: Vec::new(),
+            evaluate_equal_score_fork: false,
+            max_inflight_bytes: 4 * 1024 * 1024,
+            aggressive: false,
+            max_body_request_length: 128,
+            max_staged_body_bytes: 64 * 1024 * 1024,
+            min_body_response_bytes: 128 * 1024,
+            max_body_response_bytes: 4 * 1024 * 1024,
+            verify_score_proof: false,
+            max_header_response_bytes: 1024 * 1024,
+            header_cache_size: 1024,
+            lock_sync_target: false,
+            skeleton_threshold: 1024,
+            tip_first: false,
+            header_skip_lookback: 128,
+            max_ancestor_search_depth: 1024,
+            max_header_lead: 4096,
+            header_request_timeout: Duration::from_secs(10),
+            body_request_timeout: Duration::from_secs(30),
+            slow_peer_latency: Duration::from_secs(5),
+            leech_fairness_ratio: 0.1,
+            min_leech_bytes: 1024 * 1024,
+            deprioritize_leeches: false,
+            idle_timer_interval: Duration::from_secs(10),
+            genesis_mismatch_retry: None,
+            handshake_timeout: None,
+            no_peers_window: Duration::from_secs(60),
+            stall_window: None,
+            on_phase_change: None,
+            sync_deadline: None,
+            on_sync_deadline: None,
+            min_diverse_peers: 3,
+            on_low_peer_diversity: None,
+            observer: false,
+            observer_serves_requests: false,
+            record_messages: false,
+            max_message_bytes: 16 * 1024 * 1024,
+            announcement_depth: 1,
+            status_broadcast_window: None,
+            batch_window: None,
+            message_histogram_window: Duration::from_secs(60),
+            chunk_bytes: 1024 * 1024,
+            chunk_timeout: Duration::from_secs(15),
+            verification_level: VerificationLevel::Full,
+            seal_verifier: None,
+            seal_verification_parallelism: 4,
+            fork_choice: Arc::new(HighestScore),
+            load_probe: Box::new(|| 0.0),
+        }
+    }
+}
@@ -17,8 +17,12 @@
 use std::collections::{HashMap, HashSet};
 
 use ccore::{Header, UnverifiedParcel};
+use cmerkle::skewed_merkle_root;
 use primitives::H256;
+use rand::{thread_rng, Rng};
+use rlp::Encodable;
 
+use super::super::config::Config;
 use super::super::message::RequestMessage;
 
 #[derive(Clone)]
@@ -30,51 +34,136 @@ struct Target {
 }
 
 pub struct BodyDownloader {
+    shuffle_request: bool,
+    max_request_length: usize,
+    max_staged_bytes: usize,
     targets: Vec<Target>,
     downloading: HashSet<H256>,
-    downloaded: HashMap<H256, Vec<UnverifiedParcel>>,
+    downloaded: HashMap<H256, (Vec<UnverifiedParcel>, usize)>,
+    staged_bytes: usize,
 }
 
 impl BodyDownloader {
-    pub fn new() -> Self {
+    pub fn new(config: &Config) -> Self {
         Self {
+            shuffle_request: config.shuffle_body_request,
+            max_request_length: config.max_body_request_length,
+            max_staged_bytes: config.max_staged_body_bytes,
             targets: Vec::new(),
             downloading: HashSet::new(),
             downloaded: HashMap::new(),
+            staged_bytes: 0,
         }
     }
 
+    /// Bodies waiting for the bodies of their ancestors can't be imported, so they shouldn't pile up without limit
+    fn is_over_budget(&self) -> bool {
+        if self.staged_bytes >= self.max_staged_bytes {
+            ctrace!(SYNC, "Staged bodies take {} bytes, pausing bodies requests", self.staged_bytes);
+            return true
+        }
+        false
+    }
+
     pub fn create_request(&mut self) -> Option<RequestMessage> {
-        const MAX_BODY_REQEUST_LENGTH: usize = 128;
+        if self.is_over_budget() {
+            return None
+        }
+        let hashes = self.pending_hashes(self.max_request_length);
+        if hashes.is_empty() {
+            None
+        } else {
+            Some(self.mark_as_downloading(hashes))
+        }
+    }
+
+    /// Splits pending bodies into at most `count` requests which don't overlap each other
+    pub fn create_requests(&mut self, count: usize) -> Vec<RequestMessage> {
+        if self.is_over_budget() {
+            return Vec::new()
+        }
+        let hashes = self.pending_hashes(self.max_request_length * count);
+        if hashes.is_empty() {
+            return Vec::new()
+        }
+        let length = (hashes.len() + count - 1) / count;
+        hashes.chunks(length).map(|chunk| self.mark_as_downloading(chunk.to_vec())).collect()
+    }
+
+    fn pending_hashes(&self, limit: usize) -> Vec<H256> {
         let mut hashes = Vec::new();
         for t in &self.targets {
+            if hashes.len() >= limit {
+                break
+            }
             if !self.downloading.contains(&t.hash) && !self.downloaded.contains_key(&t.hash) {
                 hashes.push(t.hash);
             }
-            if hashes.len() >= MAX_BODY_REQEUST_LENGTH {
-                break
-            }
         }
-        if hashes.is_empty() {
-            None
-        } else {
-            self.downloading.extend(&hashes);
-            Some(RequestMessage::Bodies(hashes))
+        hashes
+    }
+
+    fn mark_as_downloading(&mut self, mut hashes: Vec<H256>) -> RequestMessage {
+        self.downloading.extend(&hashes);
+        if self.shuffle_request {
+            thread_rng().shuffle(&mut hashes);
         }
+        RequestMessage::Bodies(hashes)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.targets.is_empty()
+    }
+
+    /// Number of targets whose bodies aren't requested yet, and of the others
+    pub fn pending_counts(&self) -> (usize, usize) {
+        // Downloading and downloaded bodies may be left for hashes which aren't targets anymore
+        let requested = self
+            .targets
+            .iter()
+            .filter(|t| self.downloading.contains(&t.hash) || self.downloaded.contains_key(&t.hash))
+            .count();
+        (self.targets.len() - requested, requested)
     }
 
-    pub fn import_bodies(&mut self, hashes: Vec<H256>, bodies: Vec<Vec<UnverifiedParcel>>) {
+    /// Bodies can be fewer than hashes, and the remaining hashes will be requested again
+    /// Returns the hashes whose bodies are duplicated or don't match their headers, which will be requested again too.
+    /// Empty bodies which don't match are taken as unavailable, and aren't returned
+    pub fn import_bodies(&mut self, hashes: Vec<H256>, bodies: Vec<Vec<UnverifiedParcel>>) -> Vec<H256> {
+        debug_assert!(bodies.len() <= hashes.len());
+        self.reset_downloading(&hashes[bodies.len()..]);
+        let mut invalid = Vec::new();
+        let mut received = HashSet::new();
         for (hash, body) in hashes.into_iter().zip(bodies) {
-            if self.downloading.remove(&hash) {
-                if body.is_empty() {
-                    let target = self.targets.iter().find(|t| t.hash == hash).expect("Downloading target must exist");
-                    if target.parent_root != target.parcels_root {
-                        continue
-                    }
-                }
-                self.downloaded.insert(hash, body);
+            // Bodies already received, in this response or before, are skipped
+            if !self.downloading.remove(&hash) || self.downloaded.contains_key(&hash) {
+                continue
+            }
+            let encoded: Vec<_> = body.iter().map(|p| p.rlp_bytes()).collect();
+            // A parcel can't be in two blocks, so only empty bodies can be repeated
+            let body_hash = skewed_merkle_root(H256::zero(), encoded.iter());
+            if !body.is_empty() && !received.insert(body_hash) {
+                cdebug!(SYNC, "Body of {} is a duplicate of another body in the response", hash);
+                invalid.push(hash);
+                continue
             }
+            let target = self.targets.iter().find(|t| t.hash == hash).expect("Downloading target must exist");
+            let size = encoded.iter().map(|bytes| bytes.len()).sum();
+            let parcels_root = skewed_merkle_root(target.parent_root, encoded.into_iter());
+            // Older versions answer a body they don't have with an empty one, which is requested again
+            if body.is_empty() && parcels_root != target.parcels_root {
+                cdebug!(SYNC, "Body of {} is unavailable", hash);
+                continue
+            }
+            if parcels_root != target.parcels_root {
+                cdebug!(SYNC, "Body of {} doesn't match its parcels root", hash);
+                invalid.push(hash);
+                continue
+            }
+            self.staged_bytes += size;
+            self.downloaded.insert(hash, (body, size));
         }
+        invalid
     }
 
     pub fn add_target(&mut self, header: &Header, parent: &Header) {
@@ -97,26 +186,296 @@ impl BodyDownloader {
                 self.targets.remove(index);
             }
             self.downloading.remove(hash);
-            self.downloaded.remove(hash);
+            self.take_downloaded(hash);
         }
     }
 
+    /// Removes the targets descending from the block, which can't be imported without it
+    pub fn remove_descendants(&mut self, hash: &H256) -> Vec<H256> {
+        let mut ancestors = HashSet::new();
+        ancestors.insert(*hash);
+        let mut descendants = Vec::new();
+        for t in &self.targets {
+            if ancestors.contains(&t.parent_hash) {
+                ancestors.insert(t.hash);
+                descendants.push(t.hash);
+            }
+        }
+        self.remove_target(&descendants);
+        descendants
+    }
+
+    fn take_downloaded(&mut self, hash: &H256) -> Option<Vec<UnverifiedParcel>> {
+        let (body, size) = self.downloaded.remove(hash)?;
+        self.staged_bytes -= size;
+        Some(body)
+    }
+
     pub fn reset_downloading(&mut self, hashes: &[H256]) {
         for hash in hashes {
             self.downloading.remove(&hash);
         }
     }
 
-    pub fn drain(&mut self) -> Vec<(H256, Vec<UnverifiedParcel>)> {
-        let mut result = Vec::new();
-        for t in &self.targets {
-            if let Some(body) = self.downloaded.remove(&t.hash) {
-                result.push((t.hash, body));
-            } else {
-                break
+    /// Parent of the block whose body is imported next
+    pub fn first_parent(&self) -> Option<H256> {
+        self.targets.first().map(|target| target.parent_hash)
+    }
+
+    /// Takes the body of the first target if it's downloaded, which is imported next
+    pub fn pop_downloaded(&mut self) -> Option<(H256, Vec<UnverifiedParcel>)> {
+        let hash = self.targets.first()?.hash;
+        let body = self.take_downloaded(&hash)?;
+        self.targets.remove(0);
+        Some((hash, body))
+    }
+
+    /// The block is downloaded again before the others, since its body couldn't be imported
+    pub fn retry_first(&mut self, header: &Header, parent: &Header) {
+        ctrace!(SYNC, "Retry download target: {}", header.hash());
+        self.targets.insert(
+            0,
+            Target {
+                hash: header.hash(),
+                parent_hash: parent.hash(),
+                parcels_root: *header.parcels_root(),
+                parent_root: *parent.parcels_root(),
+            },
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use ccore::{Header, SignedParcel, UnverifiedParcel};
+    use ckey::{Generator, NetworkId, Random};
+    use cmerkle::skewed_merkle_root;
+    use ctypes::parcel::{Action, Parcel};
+    use primitives::{H256, U256};
+    use rlp::Encodable;
+
+    use super::super::super::config::Config;
+    use super::super::super::message::RequestMessage;
+    use super::BodyDownloader;
+
+    fn chain(length: u64) -> Vec<Header> {
+        let mut headers: Vec<Header> = Vec::new();
+        for number in 0..length {
+            let mut header = Header::default();
+            header.set_number(number);
+            if let Some(parent) = headers.last() {
+                header.set_parent_hash(parent.hash());
             }
+            headers.push(header);
         }
-        self.targets.drain(0..result.len());
-        result
+        headers
+    }
+
+    fn drain(downloader: &mut BodyDownloader) -> Vec<(H256, Vec<UnverifiedParcel>)> {
+        let mut drained = Vec::new();
+        while let Some(downloaded) = downloader.pop_downloaded() {
+            drained.push(downloaded);
+        }
+        drained
+    }
+
+    fn requested_hashes(downloader: &mut BodyDownloader) -> Vec<H256> {
+        match downloader.create_request() {
+            Some(RequestMessage::Bodies(hashes)) => hashes,
+            _ => panic!("Body request must be created"),
+        }
+    }
+
+    fn parcel() -> UnverifiedParcel {
+        let keypair = Random.generate().unwrap();
+        let parcel = Parcel {
+            nonce: U256::zero(),
+            fee: U256::from(10),
+            network_id: NetworkId::default(),
+            action: Action::AssetTransactionGroup {
+                transactions: vec![],
+                changes: vec![],
+                signatures: vec![],
+            },
+        };
+        SignedParcel::new_with_sign(parcel, keypair.private()).into()
+    }
+
+    #[test]
+    fn duplicated_body_is_not_recorded() {
+        let bodies = vec![vec![parcel()], vec![parcel()]];
+        let mut headers = vec![Header::default()];
+        for (number, body) in bodies.iter().enumerate() {
+            let mut header = Header::default();
+            header.set_number(number as u64 + 1);
+            header.set_parent_hash(headers[number].hash());
+            header.set_parcels_root(skewed_merkle_root(
+                *headers[number].parcels_root(),
+                body.iter().map(|p| p.rlp_bytes()),
+            ));
+            headers.push(header);
+        }
+
+        let mut downloader = BodyDownloader::new(&Config::default());
+        for neighbors in headers.windows(2) {
+            downloader.add_target(&neighbors[1], &neighbors[0]);
+        }
+        let requested = requested_hashes(&mut downloader);
+        let duplicated = vec![bodies[0].clone(), bodies[0].clone()];
+        let invalid = downloader.import_bodies(requested.clone(), duplicated.clone());
+        assert_eq!(vec![headers[2].hash()], invalid);
+        // The same response received again is skipped
+        assert!(downloader.import_bodies(requested, duplicated).is_empty());
+
+        assert_eq!(vec![(headers[1].hash(), bodies[0].clone())], drain(&mut downloader));
+        assert_eq!(vec![headers[2].hash()], requested_hashes(&mut downloader));
+    }
+
+    #[test]
+    fn bodies_arriving_out_of_order_are_drained_in_order() {
+        let headers = chain(4);
+        let mut downloader = BodyDownloader::new(&Config::default());
+        for neighbors in headers.windows(2) {
+            downloader.add_target(&neighbors[1], &neighbors[0]);
+        }
+        let requested = requested_hashes(&mut downloader);
+        let hashes: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+        assert_eq!(hashes, requested);
+
+        downloader.import_bodies(vec![hashes[2]], vec![Vec::new()]);
+        assert!(drain(&mut downloader).is_empty());
+        downloader.import_bodies(vec![hashes[0]], vec![Vec::new()]);
+        assert_eq!(vec![(hashes[0], Vec::new())], drain(&mut downloader));
+        downloader.import_bodies(vec![hashes[1]], vec![Vec::new()]);
+        assert_eq!(vec![(hashes[1], Vec::new()), (hashes[2], Vec::new())], drain(&mut downloader));
+    }
+
+    #[test]
+    fn descendants_of_a_block_are_removed() {
+        let headers = chain(4);
+        let mut downloader = BodyDownloader::new(&Config::default());
+        for neighbors in headers.windows(2) {
+            downloader.add_target(&neighbors[1], &neighbors[0]);
+        }
+        let hashes: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+        downloader.import_bodies(requested_hashes(&mut downloader), vec![Vec::new(); 3]);
+
+        assert_eq!(hashes[1..].to_vec(), downloader.remove_descendants(&hashes[0]));
+        assert_eq!(vec![(hashes[0], Vec::new())], drain(&mut downloader));
+        assert!(downloader.is_empty());
+    }
+
+    #[test]
+    fn shuffle_request_preserves_hashes() {
+        let headers = chain(101);
+        let expected: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+
+        let mut downloader = BodyDownloader::new(&Config::default());
+        let mut shuffled_downloader = BodyDownloader::new(&Config {
+            shuffle_body_request: true,
+            ..Config::default()
+        });
+        for neighbors in headers.windows(2) {
+            downloader.add_target(&neighbors[1], &neighbors[0]);
+            shuffled_downloader.add_target(&neighbors[1], &neighbors[0]);
+        }
+
+        assert_eq!(expected, requested_hashes(&mut downloader));
+
+        let shuffled = requested_hashes(&mut shuffled_downloader);
+        assert_ne!(expected, shuffled);
+        let expected_set: HashSet<_> = expected.into_iter().collect();
+        let shuffled_set: HashSet<_> = shuffled.into_iter().collect();
+        assert_eq!(expected_set, shuffled_set);
+    }
+
+    #[test]
+    fn bodies_are_requested_up_to_max_length() {
+        let headers = chain(11);
+        let mut downloader = BodyDownloader::new(&Config {
+            max_body_request_length: 4,
+            ..Config::default()
+        });
+        for neighbors in headers.windows(2) {
+            downloader.add_target(&neighbors[1], &neighbors[0]);
+        }
+
+        let lengths: Vec<_> = (0..3).map(|_| requested_hashes(&mut downloader).len()).collect();
+        assert_eq!(vec![4, 4, 2], lengths);
+        assert_eq!(None, downloader.create_request());
+    }
+
+    #[test]
+    fn bodies_are_not_requested_while_staged_bodies_exceed_budget() {
+        let bodies = vec![vec![parcel()], vec![parcel()], vec![parcel()]];
+        let mut headers = vec![Header::default()];
+        for (number, body) in bodies.iter().enumerate() {
+            let mut header = Header::default();
+            header.set_number(number as u64 + 1);
+            header.set_parent_hash(headers[number].hash());
+            header.set_parcels_root(skewed_merkle_root(
+                *headers[number].parcels_root(),
+                body.iter().map(|p| p.rlp_bytes()),
+            ));
+            headers.push(header);
+        }
+
+        let mut downloader = BodyDownloader::new(&Config {
+            max_body_request_length: 1,
+            max_staged_body_bytes: 1,
+            ..Config::default()
+        });
+        for neighbors in headers.windows(2) {
+            downloader.add_target(&neighbors[1], &neighbors[0]);
+        }
+        let first = requested_hashes(&mut downloader);
+        let second = requested_hashes(&mut downloader);
+        // The second body can't be imported until the first one arrives
+        downloader.import_bodies(second, vec![bodies[1].clone()]);
+        assert_eq!(None, downloader.create_request());
+        assert!(downloader.create_requests(2).is_empty());
+
+        downloader.import_bodies(first, vec![bodies[0].clone()]);
+        assert_eq!(2, drain(&mut downloader).len());
+        assert_eq!(vec![headers[3].hash()], requested_hashes(&mut downloader));
+    }
+
+    #[test]
+    fn unserved_bodies_are_requested_again() {
+        let headers = chain(11);
+        let expected: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+
+        let mut downloader = BodyDownloader::new(&Config::default());
+        for neighbors in headers.windows(2) {
+            downloader.add_target(&neighbors[1], &neighbors[0]);
+        }
+        let requested = requested_hashes(&mut downloader);
+        downloader.import_bodies(requested, vec![Vec::new(); 4]);
+
+        assert_eq!(expected[4..].to_vec(), requested_hashes(&mut downloader));
+        assert_eq!(4, drain(&mut downloader).len());
+    }
+
+    #[test]
+    fn empty_body_of_non_empty_block_is_taken_as_unavailable() {
+        let body = vec![parcel()];
+        let genesis = Header::default();
+        let mut header = Header::default();
+        header.set_number(1);
+        header.set_parent_hash(genesis.hash());
+        header.set_parcels_root(skewed_merkle_root(*genesis.parcels_root(), body.iter().map(|p| p.rlp_bytes())));
+
+        let mut downloader = BodyDownloader::new(&Config::default());
+        downloader.add_target(&header, &genesis);
+        let requested = requested_hashes(&mut downloader);
+        assert!(downloader.import_bodies(requested.clone(), vec![Vec::new()]).is_empty());
+        // A non-empty body which doesn't match is invalid
+        let requested = requested_hashes(&mut downloader);
+        assert_eq!(requested.clone(), downloader.import_bodies(requested, vec![vec![parcel()]]));
+        let requested = requested_hashes(&mut downloader);
+        assert!(downloader.import_bodies(requested, vec![body.clone()]).is_empty());
+        assert_eq!(vec![(header.hash(), body)], drain(&mut downloader));
     }
 }
@@ -14,19 +14,22 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
+use std::collections::{HashMap, VecDeque};
 use std::sync::Arc;
-use std::time::Instant;
+use std::time::{Duration, Instant};
 
 use ccore::encoded::Header;
-use ccore::{BlockChainClient, BlockId};
+use ccore::{BlockChainClient, BlockId, ChainInfo};
+use cnetwork::NodeId;
 use primitives::{H256, U256};
 
+use super::super::config::Config;
+use super::super::fork_choice::{ChainTip, ForkChoice};
 use super::super::message::RequestMessage;
 
 const MAX_HEADER_REQUEST_LENGTH: u64 = 128;
+const SKELETON_LENGTH: u64 = 16;
 const MAX_RETRY: usize = 3;
-const MAX_WAIT: u64 = 15;
 
 #[derive(Clone)]
 struct Pivot {
@@ -34,18 +37,60 @@ struct Pivot {
     total_score: U256,
 }
 
+/// Headers from a header of the skeleton to the next one, which can be downloaded from any peer
+#[derive(Clone)]
+struct Segment {
+    start: Header,
+    end: Header,
+    assignee: Option<(NodeId, Instant)>,
+    headers: Option<Vec<Header>>,
+    mismatches: usize,
+}
+
+impl Segment {
+    fn is_requested(&self, timeout: Duration) -> bool {
+        self.assignee.map_or(false, |(_, requested_at)| requested_at.elapsed() < timeout)
+    }
+
+    fn is_requested_from(&self, id: &NodeId, timeout: Duration) -> bool {
+        self.assignee.map_or(false, |(assignee, _)| assignee == *id) && self.is_requested(timeout)
+    }
+}
+
 #[derive(Clone)]
 pub struct HeaderDownloader {
     // NOTE: Use this member as minimum as possible.
     client: Arc<BlockChainClient>,
+    fork_choice: Arc<ForkChoice>,
 
     total_score: U256,
     best_hash: H256,
+    evaluate_equal_score: bool,
+    request_timeout: Duration,
+    max_lead: u64,
+    skeleton_threshold: u64,
+    tip_first: bool,
+    skip_lookback: u64,
+    max_ancestor_depth: u64,
 
     pivot: Pivot,
     request_time: Option<Instant>,
     downloaded: HashMap<H256, Header>,
     trial: usize,
+    /// Number of blocks the pivot has moved back while searching for a common ancestor
+    ancestor_depth: u64,
+    is_disjoint: bool,
+    /// Segments between the headers of the skeleton, which are imported in order once filled
+    skeleton: VecDeque<Segment>,
+    is_skeleton_requested: bool,
+    /// A skeleton whose segments couldn't be filled isn't requested again until the downloader is reset
+    is_skeleton_rejected: bool,
+    /// The peer's chain contains a block which isn't imported, so nothing is requested until it changes
+    is_rejected: bool,
+    /// Best header of the peer, near which the skeleton starts in the tip-first mode
+    tip: Option<Header>,
+    /// The best header is requested only once for each status, and the skeleton starts from the pivot without it
+    is_tip_requested: bool,
 }
 
 impl HeaderDownloader {
@@ -53,37 +98,88 @@ impl HeaderDownloader {
         self.total_score
     }
 
-    pub fn new(client: Arc<BlockChainClient>, total_score: U256, best_hash: H256) -> Self {
-        let best_header_hash = client.best_block_header().hash();
-        let best_score = client.block_total_score(BlockId::Latest).expect("Best block always exist");
+    pub fn best_hash(&self) -> H256 {
+        self.best_hash
+    }
 
+    pub fn new(client: Arc<BlockChainClient>, config: &Config, total_score: U256, best_hash: H256) -> Self {
+        let pivot = best_pivot(&*client);
         Self {
             client,
+            fork_choice: config.fork_choice.clone(),
 
             total_score,
             best_hash,
+            evaluate_equal_score: config.evaluate_equal_score_fork,
+            request_timeout: config.header_request_timeout,
+            max_lead: config.max_header_lead,
+            skeleton_threshold: config.skeleton_threshold,
+            tip_first: config.tip_first,
+            skip_lookback: config.header_skip_lookback,
+            max_ancestor_depth: config.max_ancestor_search_depth,
 
-            pivot: Pivot {
-                hash: best_header_hash,
-                total_score: best_score,
-            },
+            pivot,
             request_time: None,
             downloaded: HashMap::new(),
             trial: 0,
+            ancestor_depth: 0,
+            is_disjoint: false,
+            skeleton: VecDeque::new(),
+            is_skeleton_requested: false,
+            is_skeleton_rejected: false,
+            is_rejected: false,
+            tip: None,
+            is_tip_requested: false,
         }
     }
 
+    /// Restarts downloading from our best block, discarding the downloaded headers
+    pub fn reanchor(&mut self) {
+        let pivot = best_pivot(&*self.client);
+        self.reset(pivot);
+    }
+
+    /// Restarts downloading from the genesis block, discarding the downloaded headers
+    pub fn reanchor_to_genesis(&mut self) {
+        let pivot = genesis_pivot(&*self.client);
+        self.reset(pivot);
+    }
+
+    fn reset(&mut self, pivot: Pivot) {
+        self.pivot = pivot;
+        self.request_time = None;
+        self.downloaded.clear();
+        self.trial = 0;
+        self.ancestor_depth = 0;
+        self.is_disjoint = false;
+        self.skeleton.clear();
+        self.is_skeleton_requested = false;
+        self.is_skeleton_rejected = false;
+        self.is_rejected = false;
+    }
+
     pub fn update(&mut self, total_score: U256, best_hash: H256) {
+        // A new best block may be on another branch
+        if best_hash != self.best_hash {
+            self.is_rejected = false;
+            self.tip = None;
+            self.is_tip_requested = false;
+        }
         self.total_score = total_score;
         self.best_hash = best_hash;
     }
 
     fn is_valid(&self) -> bool {
-        self.trial < MAX_RETRY
+        self.trial < MAX_RETRY && !self.is_disjoint && !self.is_rejected
     }
 
     fn is_expired(&self) -> bool {
-        self.request_time.map_or(false, |time| (Instant::now() - time).as_secs() > MAX_WAIT)
+        self.request_time.map_or(false, |time| time.elapsed() >= self.request_timeout)
+    }
+
+    /// Whether a request created by `create_request` is waiting for the response
+    pub fn is_requesting(&self) -> bool {
+        self.request_time.is_some() && !self.is_expired()
     }
 
     /// Find header from download cache, and then from blockchain
@@ -96,7 +192,19 @@ impl HeaderDownloader {
     }
 
     pub fn is_idle(&self) -> bool {
-        let can_request = self.request_time.is_none() && self.total_score > self.pivot.total_score;
+        let peer = ChainTip {
+            total_score: self.total_score,
+            best_hash: self.best_hash,
+        };
+        let pivot = ChainTip {
+            total_score: self.pivot.total_score,
+            best_hash: self.pivot.hash,
+        };
+        let is_ahead = self.fork_choice.is_better(&peer, &pivot);
+        let is_tied = self.evaluate_equal_score
+            && self.total_score == self.pivot.total_score
+            && self.best_hash != self.pivot.hash;
+        let can_request = self.request_time.is_none() && (is_ahead || is_tied);
 
         self.is_valid() && (can_request || self.is_expired())
     }
@@ -105,37 +213,268 @@ impl HeaderDownloader {
         if !self.is_idle() {
             return None
         }
+        // Segments of the skeleton are requested from any peer with `create_segment_request`
+        if !self.skeleton.is_empty() && !self.is_backfilling() {
+            return None
+        }
 
         let pivot_number = self.pivot_header().number();
+        let best_block_number = self.client.chain_info().best_block_number;
+        if pivot_number.saturating_sub(best_block_number) >= self.max_lead {
+            ctrace!(SYNC, "Headers are not requested until the blocks up to #{} are imported", pivot_number);
+            return None
+        }
 
         self.request_time = Some(Instant::now());
 
+        // The headers before a skeleton near the tip end at its first header
+        if let Some(segment) = self.skeleton.front() {
+            return Some(RequestMessage::Headers {
+                start_number: pivot_number,
+                max_count: ::std::cmp::min(
+                    MAX_HEADER_REQUEST_LENGTH,
+                    segment.start.number().saturating_sub(pivot_number) + 1,
+                ),
+                skip: 0,
+                reverse: false,
+            })
+        }
+
+        self.is_skeleton_requested = !self.is_skeleton_rejected && self.estimated_gap() > self.skeleton_threshold;
+        if self.is_skeleton_requested && self.tip_first {
+            if self.tip.is_none() && !self.is_tip_requested {
+                ctrace!(SYNC, "Requesting the best header {}", self.best_hash);
+                self.is_skeleton_requested = false;
+                self.is_tip_requested = true;
+                return Some(RequestMessage::HeadersByHashes(vec![self.best_hash]))
+            }
+            let span = (SKELETON_LENGTH - 1) * (MAX_HEADER_REQUEST_LENGTH - 1);
+            let tip_number = self.tip.as_ref().map_or(0, |tip| tip.number());
+            if tip_number > pivot_number + span {
+                ctrace!(SYNC, "Requesting a skeleton of headers up to #{}", tip_number);
+                return Some(RequestMessage::Headers {
+                    start_number: tip_number - span,
+                    max_count: SKELETON_LENGTH,
+                    skip: MAX_HEADER_REQUEST_LENGTH - 2,
+                    reverse: false,
+                })
+            }
+        }
+        if self.is_skeleton_requested {
+            ctrace!(SYNC, "Requesting a skeleton of headers from #{}", pivot_number);
+            // Each segment is downloaded with a single request, which starts from the end of the previous one
+            return Some(RequestMessage::Headers {
+                start_number: pivot_number,
+                max_count: SKELETON_LENGTH,
+                skip: MAX_HEADER_REQUEST_LENGTH - 2,
+                reverse: false,
+            })
+        }
+
         Some(RequestMessage::Headers {
             start_number: pivot_number,
             max_count: MAX_HEADER_REQUEST_LENGTH,
+            skip: 0,
+            reverse: false,
+        })
+    }
+
+    /// Number of blocks to the peer's best block, estimated from the score of the pivot
+    fn estimated_gap(&self) -> u64 {
+        if self.total_score <= self.pivot.total_score {
+            return 0
+        }
+        let score = ::std::cmp::max(self.pivot_header().score(), U256::one());
+        let gap = (self.total_score - self.pivot.total_score) / score;
+        if gap > U256::from(u64::max_value()) {
+            u64::max_value()
+        } else {
+            gap.low_u64()
+        }
+    }
+
+    /// Whether the headers before a skeleton near the tip are downloaded in sequence
+    pub fn is_backfilling(&self) -> bool {
+        self.skeleton.front().map_or(false, |segment| segment.start.hash() != self.pivot.hash)
+    }
+
+    /// The best header of the peer requested by its hash, which is `None` if the peer didn't serve it
+    pub fn import_tip(&mut self, tip: Option<Header>) {
+        if !self.is_tip_requested || self.tip.is_some() {
+            return
+        }
+        self.tip = tip;
+        self.request_time = None;
+        self.trial = 0;
+    }
+
+    /// Whether the best header is requested and not served yet
+    pub fn is_tip_requested(&self) -> bool {
+        self.is_tip_requested && self.tip.is_none()
+    }
+
+    /// Expects the first header is the pivot, or the first header of a skeleton near the tip
+    fn import_skeleton(&mut self, mut headers: Vec<Header>) {
+        let mut start = headers.remove(0);
+        let mut skeleton = VecDeque::new();
+        for end in headers {
+            if end.number() <= start.number() || end.number() - start.number() >= MAX_HEADER_REQUEST_LENGTH {
+                cdebug!(SYNC, "Skeleton header #{} is too far from #{}", end.number(), start.number());
+                self.is_skeleton_rejected = true;
+                return
+            }
+            skeleton.push_back(Segment {
+                start,
+                end: end.clone(),
+                assignee: None,
+                headers: None,
+                mismatches: 0,
+            });
+            start = end;
+        }
+        if skeleton.is_empty() {
+            cdebug!(SYNC, "Skeleton has no header after the pivot");
+            self.is_skeleton_rejected = true;
+            return
+        }
+        cdebug!(SYNC, "Skeleton of {} segments is downloaded", skeleton.len());
+        self.skeleton = skeleton;
+    }
+
+    /// Whether a segment of the skeleton is being downloaded from the peer
+    pub fn is_segment_requested_from(&self, id: &NodeId) -> bool {
+        let timeout = self.request_timeout;
+        self.skeleton.iter().any(|segment| segment.is_requested_from(id, timeout))
+    }
+
+    /// Requests the first segment which is neither filled nor being downloaded, from the given peer
+    pub fn create_segment_request(&mut self, assignee: &NodeId) -> Option<RequestMessage> {
+        let timeout = self.request_timeout;
+        let segment = match self
+            .skeleton
+            .iter_mut()
+            .find(|segment| segment.headers.is_none() && !segment.is_requested(timeout))
+        {
+            Some(segment) => segment,
+            None => return None,
+        };
+        segment.assignee = Some((*assignee, Instant::now()));
+        Some(RequestMessage::Headers {
+            start_number: segment.start.number(),
+            max_count: segment.end.number() - segment.start.number() + 1,
+            skip: 0,
+            reverse: false,
         })
     }
 
+    /// Fills the segment requested from the peer with the headers, which must start and end at the skeleton headers.
+    /// The filled segments are moved to the downloaded headers in order.
+    /// Returns `None` if no segment is requested from the peer, or whether the headers match the segment
+    pub fn import_segment(&mut self, from: &NodeId, headers: &[Header]) -> Option<bool> {
+        let index = match self.skeleton.iter().position(|segment| segment.assignee.map_or(false, |(id, _)| id == *from))
+        {
+            Some(index) => index,
+            None => return None,
+        };
+        let is_matched = {
+            let segment = &mut self.skeleton[index];
+            segment.assignee = None;
+            let length = segment.end.number() - segment.start.number() + 1;
+            let is_matched = headers.len() as u64 == length
+                && headers.first().map(|header| header.hash()) == Some(segment.start.hash())
+                && headers.last().map(|header| header.hash()) == Some(segment.end.hash());
+            if is_matched {
+                segment.headers = Some(headers.to_vec());
+            } else {
+                segment.mismatches += 1;
+            }
+            is_matched
+        };
+        if !is_matched && self.skeleton[index].mismatches >= MAX_RETRY {
+            cdebug!(
+                SYNC,
+                "Segment from #{} doesn't match the skeleton, downloading headers in sequence",
+                self.skeleton[index].start.number()
+            );
+            self.skeleton.clear();
+            self.is_skeleton_rejected = true;
+        }
+
+        self.import_filled_segments();
+        Some(is_matched)
+    }
+
+    /// Moves the filled segments to the downloaded headers once the pivot reaches them
+    fn import_filled_segments(&mut self) {
+        if let Some(start) = self.skeleton.front().map(|segment| segment.start.clone()) {
+            let pivot_header = self.pivot_header();
+            if pivot_header.number() >= start.number() && pivot_header.hash() != start.hash() {
+                cdebug!(SYNC, "Skeleton from #{} isn't on the chain of the pivot", start.number());
+                self.skeleton.clear();
+                self.is_skeleton_rejected = true;
+                return
+            }
+        }
+        while self.skeleton.front().map_or(false, |segment| segment.headers.is_some() && !self.is_backfilling()) {
+            let segment = self.skeleton.pop_front().expect("Segment exists");
+            self.extend_pivot(segment.headers.expect("Segment is filled"));
+        }
+    }
+
+    /// Expects the first header is the pivot
+    fn extend_pivot(&mut self, headers: Vec<Header>) {
+        for header in headers.iter() {
+            // Headers which are already canonical don't need to be imported again
+            if self.is_canonical(header) {
+                continue
+            }
+            self.downloaded.insert(header.hash(), header.clone());
+        }
+
+        // FIXME: skip known headers
+        let new_scores = headers[1..].iter().fold(U256::zero(), |acc, header| acc + header.score());
+        self.pivot = Pivot {
+            hash: headers.last().expect("Last downloaded header must exist").hash(),
+            total_score: self.pivot.total_score + new_scores,
+        }
+    }
+
     /// Imports headers and mark success
     /// Expects importing headers matches requested header
     pub fn import_headers(&mut self, headers: Vec<Header>) {
         let first_header_hash = headers.first().expect("First header must exist").hash();
-        if first_header_hash == self.pivot.hash {
-            for header in headers.iter() {
-                self.downloaded.insert(header.hash(), header.clone());
+        if self.is_skeleton_requested {
+            self.is_skeleton_requested = false;
+            let is_near_tip = self.tip.as_ref().map(|tip| tip.hash()) == headers.last().map(|header| header.hash())
+                && headers.first().map_or(false, |header| header.number() > self.pivot_header().number());
+            if first_header_hash == self.pivot.hash || is_near_tip {
+                self.import_skeleton(headers);
+            } else {
+                // The common ancestor is searched with sequential requests
+                cdebug!(SYNC, "Skeleton doesn't start from the pivot {}", self.pivot.hash);
+                self.is_skeleton_rejected = true;
             }
-
-            // FIXME: skip known headers
-            let new_scores = headers[1..].iter().fold(U256::zero(), |acc, header| acc + header.score());
-            self.pivot = Pivot {
-                hash: headers.last().expect("Last downloaded header must exist").hash(),
-                total_score: self.pivot.total_score + new_scores,
+        } else if self.is_behind_pivot(&headers) {
+            cdebug!(SYNC, "Headers up to #{} are already processed", headers.last().expect("Headers exist").number());
+        } else if first_header_hash == self.pivot.hash {
+            // A skeleton is tried again from the common ancestor
+            if self.ancestor_depth > 0 {
+                self.is_skeleton_rejected = false;
             }
+            self.ancestor_depth = 0;
+            self.extend_pivot(headers);
+            self.import_filled_segments();
         } else {
             let pivot_header = self.pivot_header();
-            self.pivot = Pivot {
-                hash: pivot_header.parent_hash(),
-                total_score: self.pivot.total_score - pivot_header.score(),
+            if pivot_header.number() == 0 || self.ancestor_depth >= self.max_ancestor_depth {
+                cdebug!(SYNC, "No common ancestor is found within {} blocks", self.ancestor_depth);
+                self.is_disjoint = true;
+            } else {
+                self.ancestor_depth += 1;
+                self.pivot = Pivot {
+                    hash: pivot_header.parent_hash(),
+                    total_score: self.pivot.total_score - pivot_header.score(),
+                }
             }
         }
 
@@ -143,6 +482,45 @@ impl HeaderDownloader {
         self.trial = 0;
     }
 
+    /// A peer may send again the headers processed before it reconnected, which are discarded
+    /// without being processed if the last one is known and at most `skip_lookback` blocks behind the pivot
+    fn is_behind_pivot(&self, headers: &[Header]) -> bool {
+        let last = headers.last().expect("Last header must exist");
+        let pivot_number = self.pivot_header().number();
+        if last.number() >= pivot_number || last.number() + self.skip_lookback < pivot_number {
+            return false
+        }
+        self.downloaded.contains_key(&last.hash()) || self.is_canonical(last)
+    }
+
+    fn is_canonical(&self, header: &Header) -> bool {
+        self.client.block_hash(BlockId::Number(header.number())) == Some(header.hash())
+    }
+
+    /// The peer's chain doesn't share any block with ours within the search depth, so nothing is requested anymore
+    pub fn is_disjoint(&self) -> bool {
+        self.is_disjoint
+    }
+
+    /// Stops downloading the peer's chain, which descends from a block which isn't imported
+    pub fn reject(&mut self) {
+        self.is_rejected = true;
+    }
+
+    /// Whether headers are downloaded along a skeleton
+    pub fn is_following_skeleton(&self) -> bool {
+        self.is_skeleton_requested || !self.skeleton.is_empty()
+    }
+
+    /// Total score of the peer's best block, which is known once its header is downloaded
+    pub fn downloaded_best_score(&self) -> Option<U256> {
+        if self.pivot.hash == self.best_hash {
+            Some(self.pivot.total_score)
+        } else {
+            None
+        }
+    }
+
     pub fn downloaded(&self) -> Vec<Header> {
         self.downloaded.values().cloned().collect()
     }
@@ -153,3 +531,273 @@ impl HeaderDownloader {
         }
     }
 }
+
+fn best_pivot(client: &BlockChainClient) -> Pivot {
+    Pivot {
+        hash: client.best_block_header().hash(),
+        total_score: client.block_total_score(BlockId::Latest).expect("Best block always exist"),
+    }
+}
+
+fn genesis_pivot(client: &BlockChainClient) -> Pivot {
+    let genesis_hash = client.chain_info().genesis_hash;
+    Pivot {
+        hash: genesis_hash,
+        total_score: client.block_total_score(BlockId::Hash(genesis_hash)).expect("Genesis block always exist"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ccore::{BlockId, BlockInfo, ChainInfo, TestBlockChainClient};
+    use cnetwork::{NodeId, SocketAddr};
+    use primitives::U256;
+
+    use super::super::super::config::Config;
+    use super::super::super::message::RequestMessage;
+    use super::HeaderDownloader;
+
+    fn node_id(port: u16) -> NodeId {
+        SocketAddr::v4(127, 0, 0, 1, port).into()
+    }
+
+    #[test]
+    fn headers_already_in_chain_are_not_downloaded() {
+        let local = Arc::new(TestBlockChainClient::new());
+        local.add_blocks(5, 0);
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+
+        let mut downloader = HeaderDownloader::new(
+            local.clone(),
+            &Config::default(),
+            U256::max_value(),
+            remote.chain_info().best_block_hash,
+        );
+        // The client imports some blocks while the downloader still points to #5
+        local.add_blocks(2, 0);
+
+        let headers = (5..11).map(|number| remote.block_header(BlockId::Number(number)).unwrap()).collect();
+        downloader.import_headers(headers);
+
+        let mut downloaded: Vec<_> = downloader.downloaded().iter().map(|header| header.number()).collect();
+        downloaded.sort();
+        assert_eq!(vec![8, 9, 10], downloaded);
+    }
+
+    #[test]
+    fn skeleton_is_requested_only_beyond_threshold() {
+        let local = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(300, 0);
+        let config = Config {
+            skeleton_threshold: 10,
+            ..Config::default()
+        };
+        let pivot_score = local.block_total_score(BlockId::Latest).unwrap();
+        let block_score = ::std::cmp::max(local.best_block_header().score(), U256::one());
+        let request_skip = |downloader: &mut HeaderDownloader| match downloader.create_request() {
+            Some(RequestMessage::Headers {
+                skip,
+                ..
+            }) => skip,
+            request => panic!("Unexpected request: {:?}", request),
+        };
+
+        let best_hash = remote.chain_info().best_block_hash;
+        let mut near = HeaderDownloader::new(local.clone(), &config, pivot_score + block_score * 5.into(), best_hash);
+        assert_eq!(0, request_skip(&mut near));
+
+        let mut far = HeaderDownloader::new(local.clone(), &config, pivot_score + block_score * 20.into(), best_hash);
+        assert_eq!(126, request_skip(&mut far));
+        let skeleton =
+            vec![0, 127, 254].into_iter().map(|number| remote.block_header(BlockId::Number(number)).unwrap());
+        far.import_headers(skeleton.collect());
+        assert_eq!(2, far.skeleton.len());
+        assert_eq!(None, far.create_request());
+    }
+
+    #[test]
+    fn segments_are_downloaded_from_several_peers_and_imported_in_order() {
+        let local = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(300, 0);
+        let config = Config {
+            skeleton_threshold: 10,
+            ..Config::default()
+        };
+        let remote_info = remote.chain_info();
+        let mut downloader =
+            HeaderDownloader::new(local.clone(), &config, remote_info.total_score, remote_info.best_block_hash);
+        downloader.create_request();
+        let skeleton =
+            vec![0, 127, 254].into_iter().map(|number| remote.block_header(BlockId::Number(number)).unwrap());
+        downloader.import_headers(skeleton.collect());
+        let headers = |from: u64, to: u64| -> Vec<_> {
+            (from..to + 1).map(|number| remote.block_header(BlockId::Number(number)).unwrap()).collect()
+        };
+
+        let (first, second) = (node_id(3485), node_id(3486));
+        let request_start = |downloader: &mut HeaderDownloader, id: &NodeId| match downloader.create_segment_request(id)
+        {
+            Some(RequestMessage::Headers {
+                start_number,
+                max_count,
+                ..
+            }) => (start_number, max_count),
+            request => panic!("Unexpected request: {:?}", request),
+        };
+        assert_eq!((0, 128), request_start(&mut downloader, &first));
+        assert_eq!((127, 128), request_start(&mut downloader, &second));
+        assert!(downloader.is_segment_requested_from(&second));
+        assert_eq!(None, downloader.create_segment_request(&node_id(3487)));
+
+        // A segment which doesn't end at the skeleton header is requested again
+        assert_eq!(Some(false), downloader.import_segment(&second, &headers(127, 253)));
+        assert_eq!((127, 128), request_start(&mut downloader, &second));
+        assert_eq!(Some(true), downloader.import_segment(&second, &headers(127, 254)));
+        assert!(downloader.downloaded().is_empty());
+
+        assert_eq!(Some(true), downloader.import_segment(&first, &headers(0, 127)));
+        assert_eq!(None, downloader.import_segment(&first, &headers(0, 127)));
+        assert_eq!(254, downloader.downloaded().len());
+        assert!(!downloader.is_following_skeleton());
+    }
+
+    #[test]
+    fn headers_near_tip_are_requested_before_older_ones() {
+        let local = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(2500, 0);
+        let config = Config {
+            tip_first: true,
+            ..Config::default()
+        };
+        let remote_info = remote.chain_info();
+        let mut downloader =
+            HeaderDownloader::new(local.clone(), &config, remote_info.total_score, remote_info.best_block_hash);
+        let headers = |from: u64, to: u64| -> Vec<_> {
+            (from..to + 1).map(|number| remote.block_header(BlockId::Number(number)).unwrap()).collect()
+        };
+        let request_range = |request: Option<RequestMessage>| match request {
+            Some(RequestMessage::Headers {
+                start_number,
+                max_count,
+                skip,
+                ..
+            }) => (start_number, start_number + (max_count - 1) * (skip + 1)),
+            request => panic!("Unexpected request: {:?}", request),
+        };
+
+        assert_eq!(
+            Some(RequestMessage::HeadersByHashes(vec![remote_info.best_block_hash])),
+            downloader.create_request()
+        );
+        assert!(downloader.is_tip_requested());
+        downloader.import_tip(remote.block_header(BlockId::Number(2500)));
+        assert_eq!((595, 2500), request_range(downloader.create_request()));
+        let skeleton = (0..16).map(|index| remote.block_header(BlockId::Number(595 + index * 127)).unwrap());
+        downloader.import_headers(skeleton.collect());
+        assert_eq!(15, downloader.skeleton.len());
+
+        // The segments near the tip are requested from peers, while the older headers are downloaded in sequence
+        let peer = node_id(3485);
+        assert_eq!((595, 722), request_range(downloader.create_segment_request(&peer)));
+        assert_eq!(Some(true), downloader.import_segment(&peer, &headers(595, 722)));
+        assert!(downloader.downloaded().is_empty());
+        let mut pivot_number = 0;
+        while downloader.is_backfilling() {
+            let (start, end) = request_range(downloader.create_request());
+            assert_eq!(pivot_number, start);
+            assert!(end <= 595);
+            downloader.import_headers(headers(start, end));
+            pivot_number = end;
+        }
+        assert_eq!(722, downloader.downloaded().len());
+        assert_eq!(14, downloader.skeleton.len());
+    }
+
+    #[test]
+    fn forked_peer_far_ahead_is_searched_for_common_ancestor() {
+        let local = Arc::new(TestBlockChainClient::new());
+        local.add_blocks(5, 0);
+        let remote = TestBlockChainClient::new_with_extra_data(vec![1]);
+        remote.add_blocks(1200, 0);
+        let remote_info = remote.chain_info();
+        let mut downloader = HeaderDownloader::new(
+            local.clone(),
+            &Config::default(),
+            remote_info.total_score,
+            remote_info.best_block_hash,
+        );
+
+        for _ in 0..10 {
+            let headers = match downloader.create_request() {
+                Some(RequestMessage::Headers {
+                    start_number,
+                    max_count,
+                    skip,
+                    ..
+                }) => (0..max_count)
+                    .map(|index| start_number + index * (skip + 1))
+                    .filter_map(|number| remote.block_header(BlockId::Number(number)))
+                    .collect(),
+                request => panic!("Unexpected request: {:?}", request),
+            };
+            downloader.import_headers(headers);
+            if !downloader.downloaded().is_empty() {
+                break
+            }
+        }
+        let first_hash = remote.block_hash(BlockId::Number(1)).unwrap();
+        assert!(downloader.downloaded().iter().any(|header| header.hash() == first_hash));
+    }
+
+    #[test]
+    fn headers_behind_pivot_are_discarded() {
+        let local = Arc::new(TestBlockChainClient::new());
+        local.add_blocks(10, 0);
+        let best_hash = local.chain_info().best_block_hash;
+        let mut downloader = HeaderDownloader::new(local.clone(), &Config::default(), U256::max_value(), best_hash);
+
+        let headers = (2..6).map(|number| local.block_header(BlockId::Number(number)).unwrap()).collect();
+        downloader.import_headers(headers);
+        assert!(downloader.downloaded().is_empty());
+        match downloader.create_request() {
+            Some(RequestMessage::Headers {
+                start_number,
+                ..
+            }) => assert_eq!(10, start_number),
+            request => panic!("Unexpected request: {:?}", request),
+        }
+    }
+
+    #[test]
+    fn headers_are_not_requested_beyond_lead() {
+        let local = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+
+        let config = Config {
+            max_header_lead: 4,
+            ..Config::default()
+        };
+        let mut downloader =
+            HeaderDownloader::new(local.clone(), &config, U256::max_value(), remote.chain_info().best_block_hash);
+        assert!(downloader.create_request().is_some());
+        let headers = (0..11).map(|number| remote.block_header(BlockId::Number(number)).unwrap()).collect();
+        downloader.import_headers(headers);
+        assert_eq!(None, downloader.create_request());
+
+        local.add_blocks(7, 0);
+        match downloader.create_request() {
+            Some(RequestMessage::Headers {
+                start_number,
+                ..
+            }) => assert_eq!(10, start_number),
+            request => panic!("Unexpected request: {:?}", request),
+        }
+    }
+}
@@ -14,38 +14,196 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
-use std::collections::HashMap;
-use std::sync::atomic::{AtomicUsize, Ordering};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{sync_channel, Receiver, SyncSender, TrySendError};
 use std::sync::Arc;
+use std::time::{Duration as StdDuration, Instant};
 
-use ccore::encoded::Header as EncodedHeader;
+use ccore::encoded::{Body as EncodedBody, Header as EncodedHeader};
 use ccore::{
-    Block, BlockChainClient, BlockId, BlockImportError, BlockInfo, ChainInfo, ChainNotify, Client, Header, ImportBlock,
-    ImportError, Seal, UnverifiedParcel,
+    Block, BlockChainClient, BlockError, BlockId, BlockImportError, BlockInfo, BlockStatus, ChainInfo, ChainNotify,
+    Header, ImportBlock, ImportError, Seal, UnverifiedParcel,
 };
+use ckey::NetworkId;
 use cnetwork::{Api, NetworkExtension, NodeId, TimerToken};
 use ctoken_generator::TokenGenerator;
 use ctypes::parcel::Action;
+use ctypes::util::unexpected::{Mismatch, OutOfBounds};
 use ctypes::BlockNumber;
+use lru_cache::LruCache;
 use parking_lot::{Mutex, RwLock};
 use primitives::{H256, U256};
 use rand::{thread_rng, Rng};
-use rlp::{Encodable, UntrustedRlp};
+use rlp::{Encodable, RlpStream, UntrustedRlp};
 use time::Duration;
 
+use super::chunk::ChunkAssembler;
+use super::config::{Config, VerificationLevel};
 use super::downloader::{BodyDownloader, HeaderDownloader};
+use super::fork_choice::ChainTip;
+use super::fork_id::ForkId;
 use super::message::{Message, RequestMessage, ResponseMessage};
+use super::meter::{BandwidthMeter, MessageCounter};
+use super::seal::{SealVerifier, SealVerifierPool};
 
-const SYNC_TIMER_TOKEN: TimerToken = 0;
+pub const SYNC_TIMER_TOKEN: TimerToken = 0;
 const SYNC_EXPIRE_TOKEN_BEGIN: TimerToken = SYNC_TIMER_TOKEN + 1;
 const SYNC_EXPIRE_TOKEN_LIMIT: usize = 1000;
 const SYNC_EXPIRE_TOKEN_END: TimerToken = SYNC_EXPIRE_TOKEN_BEGIN + SYNC_EXPIRE_TOKEN_LIMIT;
+const BATCH_FLUSH_TOKEN: TimerToken = SYNC_EXPIRE_TOKEN_END + 1;
+const STATUS_BROADCAST_TOKEN: TimerToken = BATCH_FLUSH_TOKEN + 1;
 
 const SYNC_TIMER_INTERVAL: i64 = 1000;
-const SYNC_EXPIRE_REQUEST_INTERVAL: i64 = 15000;
 
 const SNAPSHOT_PERIOD: u64 = (1 << 14);
 
+const INVALID_MESSAGE_PENALTY: i64 = 100;
+const VALID_RESPONSE_REWARD: i64 = 1;
+const UNKNOWN_RESPONSE_PENALTY: i64 = 10;
+const MAX_REPUTATION: i64 = 100;
+
+const MAX_CHUNKS: u64 = 1024;
+/// Chunked messages from a peer which can be reassembled at the same time
+const MAX_CHUNKED_MESSAGES_PER_PEER: usize = 4;
+const MAX_BATCHED_MESSAGE_BYTES: usize = 1024;
+
+/// Version of the extension whose Status has only the scores and the hashes
+const LEGACY_VERSION: u64 = 0;
+
+const BANDWIDTH_WINDOW_SECS: u64 = 10;
+
+const ESTIMATED_HEADER_BYTES: usize = 512;
+const ESTIMATED_BODY_BYTES: usize = 16 * 1024;
+
+const MAX_HEADERS_BY_HASHES_LENGTH: usize = 128;
+const MAX_SCORE_PROOF_LENGTH: usize = 1024;
+const MAX_SCORE_PROOF_PAGES: usize = 8;
+
+const IMPORTED_TIP_LIFETIME_SECS: u64 = 10;
+const ANNOUNCED_BLOCK_LIFETIME_SECS: u64 = 10;
+const REMOVED_PEER_LIFETIME_SECS: u64 = 10;
+const DISCONNECTED_PEER_LIFETIME_SECS: u64 = 60;
+
+const IMPORTED_CHANNEL_CAPACITY: usize = 1024;
+
+const REQUEST_LOG_LENGTH: usize = 32;
+const FORK_CHOICE_LOG_LENGTH: usize = 64;
+
+const MAX_HEADER_BODY_RATIO: f64 = 64.0;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncStatus {
+    /// There has been no peer to sync with for longer than `no_peers_window`
+    NoPeers,
+    /// Some peers have higher total score than ours
+    Syncing,
+    Synced,
+    /// All the peers ahead of us are gone before we catch up with them
+    Stalled(StallReason),
+}
+
+/// What the sync is busy with, which is updated on every tick
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPhase {
+    /// No peer is ahead of us and nothing is left to import
+    Idle,
+    /// Headers are downloaded from where the peers' chains diverge from ours
+    AncestorDiscovery,
+    /// Sparse headers are downloaded to be filled in afterward
+    HeaderSkeleton,
+    /// Bodies of the downloaded headers are being downloaded
+    BodyFill,
+    /// Every downloaded block is waiting to be imported
+    Finalization,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StallReason {
+    /// The peers are disconnected
+    PeersRemoved,
+    /// The peers are dropped since our best block didn't advance for the stall window
+    NoProgress,
+}
+
+/// Progress of the headers download compared to the bodies download
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct SyncMetrics {
+    /// Headers whose bodies aren't requested yet
+    pub pending_headers: usize,
+    /// Bodies being downloaded or waiting to be imported
+    pub pending_bodies: usize,
+}
+
+impl SyncMetrics {
+    /// Pending headers per pending body, which is high when headers are downloaded far ahead of bodies
+    pub fn header_body_ratio(&self) -> f64 {
+        self.pending_headers as f64 / ::std::cmp::max(self.pending_bodies, 1) as f64
+    }
+}
+
+/// A request recently sent to a peer
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestLogEntry {
+    pub request_id: u64,
+    pub sent_at: Instant,
+    /// `None` if the request hasn't been answered
+    pub answered_at: Option<Instant>,
+}
+
+/// Bytes of the responses exchanged with a peer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PeerTraffic {
+    /// Served by the peer to us
+    pub served: usize,
+    /// Served by us to the peer
+    pub requested: usize,
+}
+
+impl PeerTraffic {
+    /// Bytes served by the peer per byte served to it, or `None` if nothing has been served to it
+    pub fn fairness_ratio(&self) -> Option<f64> {
+        if self.requested == 0 {
+            return None
+        }
+        Some(self.served as f64 / self.requested as f64)
+    }
+}
+
+/// Messages of each type exchanged with all peers in the recent window, apart from batches and chunks framing them
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MessageHistogram {
+    pub received: HashMap<&'static str, usize>,
+    pub sent: HashMap<&'static str, usize>,
+}
+
+/// What is known about a connected peer
+#[derive(Clone, Debug, PartialEq)]
+pub struct PeerState {
+    pub total_score: U256,
+    pub best_hash: H256,
+    pub reputation: i64,
+    /// Blocks downloaded from the peer which are imported successfully
+    pub contributed_blocks: u64,
+}
+
+/// What is learned about a peer, which is kept for a while after it's disconnected
+struct PeerStats {
+    reputation: i64,
+    latency: Option<StdDuration>,
+    contributed_blocks: u64,
+    disconnected_at: Instant,
+}
+
+/// A peer's chain chosen over ours when deciding where to request blocks from
+#[derive(Clone, Debug, PartialEq)]
+pub struct ForkChoiceEntry {
+    pub peer: NodeId,
+    pub peer_tip: ChainTip,
+    pub our_tip: ChainTip,
+    pub decided_at: Instant,
+}
+
 #[derive(Debug, PartialEq)]
 pub struct TokenInfo {
     node_id: NodeId,
@@ -53,579 +211,6149 @@ pub struct TokenInfo {
 }
 
 pub struct Extension {
+    config: Config,
+    /// Verifies seals with `config.seal_verifier` if it's configured
+    seal_verifier_pool: Option<SealVerifierPool>,
     requests: RwLock<HashMap<NodeId, Vec<(u64, RequestMessage)>>>,
     header_downloaders: RwLock<HashMap<NodeId, HeaderDownloader>>,
+    /// Headers requests for a segment of a skeleton, which may be followed by another peer's downloader
+    segment_requests: Mutex<HashMap<NodeId, u64>>,
     body_downloader: Mutex<BodyDownloader>,
     tokens: RwLock<HashMap<NodeId, TimerToken>>,
     tokens_info: RwLock<HashMap<TimerToken, TokenInfo>>,
     token_generator: Mutex<TokenGenerator>,
-    client: Arc<Client>,
+    client: Arc<BlockChainClient>,
     api: RwLock<Option<Arc<Api>>>,
     last_request: AtomicUsize,
+    traced_peer: RwLock<Option<NodeId>>,
+    reputations: RwLock<HashMap<NodeId, i64>>,
+    block_filter: RwLock<Box<Fn(&Header) -> bool + Send + Sync>>,
+    archive: RwLock<Option<Box<Fn(&H256) -> Option<EncodedBody> + Send + Sync>>>,
+    trace_hook: RwLock<Option<Box<Fn(&str) + Send + Sync>>>,
+    chunk_assembler: Mutex<ChunkAssembler>,
+    last_chunk: AtomicUsize,
+    download_meter: Mutex<BandwidthMeter>,
+    upload_meter: Mutex<BandwidthMeter>,
+    recorded_messages: Mutex<Vec<(NodeId, Vec<u8>)>>,
+    received_messages: Mutex<MessageCounter>,
+    sent_messages: Mutex<MessageCounter>,
+    import_meter: Mutex<BandwidthMeter>,
+    no_peers_since: Mutex<Option<Instant>>,
+    last_progress: Mutex<(BlockNumber, Instant)>,
+    sync_started_at: Instant,
+    sync_phase: Mutex<SyncPhase>,
+    /// Set once sync has completed or the deadline has passed
+    is_watchdog_done: AtomicBool,
+    imported_tip: Mutex<Option<(ChainTip, Instant)>>,
+    peer_features: RwLock<HashMap<NodeId, Vec<u32>>>,
+    header_cache: Mutex<LruCache<BlockNumber, EncodedHeader>>,
+    announced_best: Mutex<HashMap<NodeId, H256>>,
+    pivot_peer: RwLock<Option<NodeId>>,
+    peer_meters: Mutex<HashMap<NodeId, BandwidthMeter>>,
+    peer_traffic: Mutex<HashMap<NodeId, PeerTraffic>>,
+    contributed_blocks: Mutex<HashMap<NodeId, u64>>,
+    /// Peers which served the bodies of the blocks not imported yet
+    body_contributors: Mutex<HashMap<H256, NodeId>>,
+    /// Blocks rejected by the block filter or below the pruning floor, and their descendants, which aren't downloaded
+    rejected_blocks: Mutex<HashSet<H256>>,
+    import_subscribers: Mutex<Vec<SyncSender<H256>>>,
+    request_sent_at: Mutex<HashMap<u64, Instant>>,
+    peer_latencies: Mutex<HashMap<NodeId, StdDuration>>,
+    /// Versions of the extension negotiated with the peers
+    peer_versions: Mutex<HashMap<NodeId, u64>>,
+    request_logs: Mutex<HashMap<NodeId, VecDeque<RequestLogEntry>>>,
+    is_timer_idle: AtomicBool,
+    announced_blocks: Mutex<HashMap<H256, (EncodedBody, Instant)>>,
+    /// Bodies of the downloaded blocks which are queued but not imported yet
+    pending_blocks: Mutex<HashMap<H256, EncodedBody>>,
+    stalled: Mutex<Option<(StallReason, U256)>>,
+    unproven_claims: Mutex<HashMap<NodeId, ChainTip>>,
+    /// Pages of score proofs which don't reach a block we know yet, and the number of them
+    score_proofs: Mutex<HashMap<NodeId, (usize, Vec<Header>)>>,
+    recently_removed: Mutex<HashMap<NodeId, Instant>>,
+    disconnected_peers: Mutex<HashMap<NodeId, PeerStats>>,
+    outbound_batches: Mutex<HashMap<NodeId, Vec<Message>>>,
+    is_flush_scheduled: AtomicBool,
+    last_status_broadcast: Mutex<Option<Instant>>,
+    is_status_broadcast_scheduled: AtomicBool,
+    sync_target: Mutex<Option<U256>>,
+    deferred_statuses: Mutex<HashMap<NodeId, (U256, H256)>>,
+    fork_choice_log: Mutex<VecDeque<ForkChoiceEntry>>,
+    /// Peers with a different genesis, and when they will be probed again or `None` if they already were
+    genesis_mismatches: Mutex<HashMap<NodeId, Option<Instant>>>,
+    /// Bodies missing in the next bodies response of each peer
+    unavailable_bodies: Mutex<HashMap<NodeId, HashSet<H256>>>,
+    /// Bodies pruned by each peer, which are requested from the others
+    pruned_bodies: Mutex<HashMap<NodeId, HashSet<H256>>>,
+    /// Peers which haven't sent a valid status since connected
+    pending_handshakes: Mutex<HashMap<NodeId, Instant>>,
 }
 
 impl Extension {
-    pub fn new(client: Arc<Client>) -> Arc<Self> {
+    pub fn new(client: Arc<BlockChainClient>, config: Config) -> Arc<Self> {
+        let best_block_number = client.chain_info().best_block_number;
         Arc::new(Self {
             requests: RwLock::new(HashMap::new()),
             header_downloaders: RwLock::new(HashMap::new()),
-            body_downloader: Mutex::new(BodyDownloader::new()),
+            segment_requests: Mutex::new(HashMap::new()),
+            body_downloader: Mutex::new(BodyDownloader::new(&config)),
             tokens: RwLock::new(HashMap::new()),
             tokens_info: RwLock::new(HashMap::new()),
             token_generator: Mutex::new(TokenGenerator::new(SYNC_EXPIRE_TOKEN_BEGIN, SYNC_EXPIRE_TOKEN_END)),
             client,
             api: RwLock::new(None),
             last_request: AtomicUsize::new(0),
+            traced_peer: RwLock::new(None),
+            reputations: RwLock::new(HashMap::new()),
+            block_filter: RwLock::new(Box::new(|_: &Header| true)),
+            archive: RwLock::new(None),
+            trace_hook: RwLock::new(None),
+            chunk_assembler: Mutex::new(ChunkAssembler::new(
+                config.chunk_timeout,
+                MAX_CHUNKED_MESSAGES_PER_PEER,
+                config.max_message_bytes,
+            )),
+            last_chunk: AtomicUsize::new(0),
+            download_meter: Mutex::new(BandwidthMeter::new(StdDuration::from_secs(BANDWIDTH_WINDOW_SECS))),
+            upload_meter: Mutex::new(BandwidthMeter::new(StdDuration::from_secs(BANDWIDTH_WINDOW_SECS))),
+            recorded_messages: Mutex::new(Vec::new()),
+            received_messages: Mutex::new(MessageCounter::new(config.message_histogram_window)),
+            sent_messages: Mutex::new(MessageCounter::new(config.message_histogram_window)),
+            import_meter: Mutex::new(BandwidthMeter::new(StdDuration::from_secs(BANDWIDTH_WINDOW_SECS))),
+            no_peers_since: Mutex::new(None),
+            last_progress: Mutex::new((best_block_number, Instant::now())),
+            sync_started_at: Instant::now(),
+            sync_phase: Mutex::new(SyncPhase::Idle),
+            is_watchdog_done: AtomicBool::new(false),
+            imported_tip: Mutex::new(None),
+            peer_features: RwLock::new(HashMap::new()),
+            header_cache: Mutex::new(LruCache::new(config.header_cache_size)),
+            announced_best: Mutex::new(HashMap::new()),
+            pivot_peer: RwLock::new(None),
+            peer_meters: Mutex::new(HashMap::new()),
+            peer_traffic: Mutex::new(HashMap::new()),
+            contributed_blocks: Mutex::new(HashMap::new()),
+            body_contributors: Mutex::new(HashMap::new()),
+            rejected_blocks: Mutex::new(HashSet::new()),
+            import_subscribers: Mutex::new(Vec::new()),
+            request_sent_at: Mutex::new(HashMap::new()),
+            peer_latencies: Mutex::new(HashMap::new()),
+            peer_versions: Mutex::new(HashMap::new()),
+            request_logs: Mutex::new(HashMap::new()),
+            is_timer_idle: AtomicBool::new(false),
+            announced_blocks: Mutex::new(HashMap::new()),
+            pending_blocks: Mutex::new(HashMap::new()),
+            stalled: Mutex::new(None),
+            unproven_claims: Mutex::new(HashMap::new()),
+            score_proofs: Mutex::new(HashMap::new()),
+            recently_removed: Mutex::new(HashMap::new()),
+            disconnected_peers: Mutex::new(HashMap::new()),
+            outbound_batches: Mutex::new(HashMap::new()),
+            is_flush_scheduled: AtomicBool::new(false),
+            last_status_broadcast: Mutex::new(None),
+            is_status_broadcast_scheduled: AtomicBool::new(false),
+            sync_target: Mutex::new(None),
+            deferred_statuses: Mutex::new(HashMap::new()),
+            fork_choice_log: Mutex::new(VecDeque::new()),
+            genesis_mismatches: Mutex::new(HashMap::new()),
+            unavailable_bodies: Mutex::new(HashMap::new()),
+            pruned_bodies: Mutex::new(HashMap::new()),
+            pending_handshakes: Mutex::new(HashMap::new()),
+            seal_verifier_pool: config
+                .seal_verifier
+                .as_ref()
+                .map(|verifier| SealVerifierPool::new(Arc::clone(verifier), config.seal_verification_parallelism)),
+            config,
         })
     }
 
-    fn send_message(&self, id: &NodeId, message: Message) {
-        let api = self.api.read();
-        api.as_ref().expect("Api must exist").send(id, &message.rlp_bytes().to_vec());
-    }
-
-    fn dismiss_request(&self, id: &NodeId, request_id: u64) {
-        if let Some(requests) = self.requests.write().get_mut(id) {
-            requests.retain(|(i, _)| *i != request_id);
-        }
+    /// Peers whose chain is better than ours, sorted by total score in descending order
+    pub fn peers_ahead(&self) -> Vec<(NodeId, U256)> {
+        let ours = self.chain_tip();
+        let mut peers: Vec<_> = self
+            .header_downloaders
+            .read()
+            .iter()
+            .filter(|(_, peer)| self.is_ahead(peer, &ours))
+            .map(|(id, peer)| (*id, peer.total_score()))
+            .collect();
+        peers.sort_unstable_by(|(_, a), (_, b)| b.cmp(a));
+        peers
     }
 
-    fn send_header_request(&self, id: &NodeId, request: RequestMessage) {
-        if let Some(requests) = self.requests.write().get_mut(id) {
-            let request_id = self.last_request.fetch_add(1, Ordering::Relaxed) as u64;
-            requests.push((request_id, request.clone()));
-            self.send_message(id, Message::Request(request_id, request));
+    /// Fraction of the peers whose status is known that advertise the soft fork feature
+    pub fn feature_adoption(&self, feature: u32) -> f64 {
+        let peer_features = self.peer_features.read();
+        if peer_features.is_empty() {
+            return 0.0
         }
+        let adopted = peer_features.values().filter(|features| features.contains(&feature)).count();
+        adopted as f64 / peer_features.len() as f64
     }
 
-    fn send_body_request(&self, id: &NodeId) {
-        if let Some(requests) = self.requests.write().get_mut(id) {
-            let have_body_request = {
-                requests.iter().any(|r| match r {
-                    (_, RequestMessage::Bodies(..)) => true,
-                    _ => false,
-                })
-            };
-            if have_body_request {
-                return
+    /// The peer trusted for the state snapshot: the pinned one if connected, or otherwise
+    /// the reputable peer ahead of us with the highest score, which is kept until it's removed
+    pub fn pivot_peer(&self) -> Option<NodeId> {
+        if let Some(pinned) = self.config.pivot_peer {
+            if self.header_downloaders.read().contains_key(&pinned) {
+                return Some(pinned)
             }
+        }
+        if let Some(id) = *self.pivot_peer.read() {
+            return Some(id)
+        }
 
-            if let Some(request) = self.body_downloader.lock().create_request() {
-                let request_id = self.last_request.fetch_add(1, Ordering::Relaxed) as u64;
-                requests.push((request_id, request.clone()));
-                self.send_message(id, Message::Request(request_id, request));
-
-                let tokens = self.tokens.read();
-                let mut tokens_info = self.tokens_info.write();
-
-                let token = tokens.get(id).unwrap();
-                let token_info = tokens_info.get_mut(token).unwrap();
-
-                let api = self.api.read();
-                api.as_ref()
-                    .expect("Api must exist")
-                    .set_timer_once(*token, Duration::milliseconds(SYNC_EXPIRE_REQUEST_INTERVAL))
-                    .expect("Timer set succeeds");
-                token_info.request_id = Some(request_id);
+        let peers_ahead = self.peers_ahead();
+        let selected = {
+            let reputations = self.reputations.read();
+            peers_ahead
+                .into_iter()
+                .map(|(id, _)| id)
+                .find(|id| reputations.get(id).map_or(false, |reputation| *reputation >= 0))
+        };
+        let mut pivot_peer = self.pivot_peer.write();
+        if pivot_peer.is_none() {
+            *pivot_peer = selected;
+            if let Some(id) = selected {
+                cinfo!(SYNC, "Peer #{} is selected as the pivot", id);
             }
         }
+        *pivot_peer
     }
 
-    fn send_response(&self, id: &NodeId, request_id: u64, response: ResponseMessage) {
-        self.send_message(id, Message::Response(request_id, response));
+    /// Receives the hash of every block imported by the sync, in import order.
+    /// Hashes are dropped for a subscriber which doesn't keep up, rather than blocking the import.
+    pub fn subscribe_imported(&self) -> Receiver<H256> {
+        let (sender, receiver) = sync_channel(IMPORTED_CHANNEL_CAPACITY);
+        self.import_subscribers.lock().push(sender);
+        receiver
     }
-}
 
-impl NetworkExtension for Extension {
-    fn name(&self) -> &'static str {
-        "block-propagation"
-    }
-    fn need_encryption(&self) -> bool {
-        false
+    fn notify_imported(&self, hashes: &[H256]) {
+        if hashes.is_empty() {
+            return
+        }
+        let mut subscribers = self.import_subscribers.lock();
+        subscribers.retain(|subscriber| {
+            for hash in hashes {
+                match subscriber.try_send(*hash) {
+                    Ok(()) => {}
+                    Err(TrySendError::Full(_)) => {
+                        cwarn!(SYNC, "Import subscriber is full, dropping the notification of {}", hash);
+                    }
+                    Err(TrySendError::Disconnected(_)) => return false,
+                }
+            }
+            true
+        });
     }
 
-    fn versions(&self) -> &[u64] {
-        const VERSIONS: &'static [u64] = &[0];
-        &VERSIONS
+    pub fn metrics(&self) -> SyncMetrics {
+        let (pending_headers, pending_bodies) = self.body_downloader.lock().pending_counts();
+        SyncMetrics {
+            pending_headers,
+            pending_bodies,
+        }
     }
 
-    fn on_initialize(&self, api: Arc<Api>) {
-        let mut api_lock = self.api.write();
-        api.set_timer(SYNC_TIMER_TOKEN, Duration::milliseconds(SYNC_TIMER_INTERVAL)).expect("Timer set succeeds");
-        *api_lock = Some(api);
+    /// Download and upload bytes per second across all peers
+    pub fn current_bandwidth(&self) -> (u64, u64) {
+        let now = Instant::now();
+        (self.download_meter.lock().rate(now), self.upload_meter.lock().rate(now))
+    }
 
-        let mut header = self.client.best_header();
-        let mut hollow_headers = vec![header.decode()];
-        while self.client.block_body(BlockId::Hash(header.hash())).is_none() {
-            header = self
-                .client
-                .block_header(BlockId::Hash(header.parent_hash()))
-                .expect("Every imported header must have parent");
-            hollow_headers.push(header.decode());
-        }
-        for neighbors in hollow_headers.windows(2).rev() {
-            let child = &neighbors[0];
-            let parent = &neighbors[1];
-            cdebug!(SYNC, "Adding block #{} (hash: {}) for initial body download target", child.number(), child.hash());
-            self.body_downloader.lock().add_target(child, parent);
+    pub fn message_histogram(&self) -> MessageHistogram {
+        let now = Instant::now();
+        MessageHistogram {
+            received: self.received_messages.lock().counts(now),
+            sent: self.sent_messages.lock().counts(now),
         }
-        cinfo!(SYNC, "Sync extension initialized");
     }
 
-    fn on_node_added(&self, id: &NodeId, _version: u64) {
-        let mut requests = self.requests.write();
-        let mut tokens = self.tokens.write();
-        let mut tokens_info = self.tokens_info.write();
-        let mut token_generator = self.token_generator.lock();
-
-        cinfo!(SYNC, "New peer detected #{}", id);
+    /// The chain info may lag behind the blocks we've just imported, so the best of them is used for a while
+    fn chain_tip(&self) -> ChainTip {
         let chain_info = self.client.chain_info();
-        self.send_message(
-            id,
-            Message::Status {
+        let mut imported_tip = self.imported_tip.lock();
+        let lifetime = StdDuration::from_secs(IMPORTED_TIP_LIFETIME_SECS);
+        let is_expired = imported_tip.as_ref().map_or(false, |(_, imported_at)| imported_at.elapsed() >= lifetime);
+        if is_expired {
+            *imported_tip = None;
+        }
+        match imported_tip.as_ref() {
+            Some((tip, _)) if tip.total_score > chain_info.total_score => tip.clone(),
+            _ => ChainTip {
                 total_score: chain_info.total_score,
                 best_hash: chain_info.best_block_hash,
-                genesis_hash: chain_info.genesis_hash,
             },
-        );
-
-        let token = token_generator.gen().expect("Token generator is full");
-        let token_info = TokenInfo {
-            node_id: *id,
-            request_id: None,
-        };
-
-        let t = requests.insert(*id, Vec::new());
-        debug_assert_eq!(None, t);
-        let t = tokens_info.insert(token, token_info);
-        debug_assert_eq!(None, t);
-        let t = tokens.insert(*id, token);
-        debug_assert_eq!(None, t);
-        debug_assert!(t.is_none());
+        }
     }
 
-    fn on_node_removed(&self, id: &NodeId) {
-        let mut requests = self.requests.write();
-        let mut header_downloaders = self.header_downloaders.write();
-        let mut tokens = self.tokens.write();
-        let mut tokens_info = self.tokens_info.write();
-        let mut token_generator = self.token_generator.lock();
+    fn update_imported_tip(&self, tip: ChainTip) {
+        let mut imported_tip = self.imported_tip.lock();
+        let is_better = imported_tip.as_ref().map_or(true, |(current, _)| tip.total_score > current.total_score);
+        if is_better {
+            *imported_tip = Some((tip, Instant::now()));
+        }
+    }
 
-        cinfo!(SYNC, "Peer removed #{}", id);
-        header_downloaders.remove(id);
+    /// The client may have chosen another best block than the one we've just imported.
+    /// Its best block is the authority once it's at least as good as ours.
+    fn reconcile_imported_tip(&self) {
+        let chain_info = self.client.chain_info();
+        let mut imported_tip = self.imported_tip.lock();
+        let is_diverged = imported_tip.as_ref().map_or(false, |(tip, _)| {
+            tip.best_hash != chain_info.best_block_hash && tip.total_score <= chain_info.total_score
+        });
+        if is_diverged {
+            cinfo!(
+                SYNC,
+                "Best block of the client is #{}({}) rather than the imported block",
+                chain_info.best_block_number,
+                chain_info.best_block_hash
+            );
+            *imported_tip = None;
+        }
+    }
 
-        let t = requests.remove(id);
-        debug_assert_ne!(None, t);
-        let token = tokens.remove(id);
-        debug_assert_ne!(None, token);
-        let token = token.unwrap();
-        let t = tokens_info.remove(&token);
-        debug_assert_ne!(None, t);
-        let t = token_generator.restore(token);
-        debug_assert!(t);
+    fn is_ahead(&self, peer: &HeaderDownloader, ours: &ChainTip) -> bool {
+        let tip = ChainTip {
+            total_score: peer.total_score(),
+            best_hash: peer.best_hash(),
+        };
+        self.config.fork_choice.is_better(&tip, ours)
     }
 
-    fn on_message(&self, id: &NodeId, data: &[u8]) {
-        if let Ok(received_message) = UntrustedRlp::new(data).as_val() {
-            match received_message {
-                Message::Status {
-                    total_score,
-                    best_hash,
-                    genesis_hash,
-                } => self.on_peer_status(id, total_score, best_hash, genesis_hash),
-                Message::Request(request_id, request) => self.on_peer_request(id, request_id, request),
-                Message::Response(request_id, response) => self.on_peer_response(id, request_id, response),
+    /// Decides whether blocks are requested from the peer, logging the decision if its chain is chosen
+    fn is_peer_chain_chosen(&self, id: &NodeId, ours: &ChainTip) -> bool {
+        let peer_tip = match self.header_downloaders.read().get(id) {
+            Some(peer) if self.is_ahead(peer, ours) => ChainTip {
+                total_score: peer.total_score(),
+                best_hash: peer.best_hash(),
+            },
+            _ => return false,
+        };
+
+        let mut log = self.fork_choice_log.lock();
+        // The same decision is made again on every tick until either chain changes
+        let is_logged = log
+            .iter()
+            .rev()
+            .find(|entry| entry.peer == *id)
+            .map_or(false, |entry| entry.peer_tip == peer_tip && entry.our_tip == *ours);
+        if !is_logged {
+            cdebug!(SYNC, "Chain of peer #{} {:?} is chosen over ours {:?}", id, peer_tip, ours);
+            if log.len() >= FORK_CHOICE_LOG_LENGTH {
+                log.pop_front();
             }
-        } else {
-            cinfo!(SYNC, "Invalid message from peer {}", id);
+            log.push_back(ForkChoiceEntry {
+                peer: *id,
+                peer_tip,
+                our_tip: ours.clone(),
+                decided_at: Instant::now(),
+            });
         }
+        true
     }
 
-    fn on_timeout(&self, token: TimerToken) {
-        match token {
-            SYNC_TIMER_TOKEN => {
-                let total_score = self.client.chain_info().total_score;
-                let mut peer_ids: Vec<_> = self.header_downloaders.read().keys().cloned().collect();
-                thread_rng().shuffle(&mut peer_ids);
-
-                for id in peer_ids {
-                    if let Some(peer) = self.header_downloaders.write().get_mut(&id) {
-                        if let Some(request) = peer.create_request() {
-                            self.send_header_request(&id, request);
-                        }
-                    }
+    /// Recent decisions to follow a peer's chain, from the oldest
+    pub fn fork_choice_log(&self) -> Vec<ForkChoiceEntry> {
+        self.fork_choice_log.lock().iter().cloned().collect()
+    }
 
-                    let peer_score = if let Some(peer) = self.header_downloaders.read().get(&id) {
-                        peer.total_score()
-                    } else {
-                        U256::zero()
-                    };
+    /// Estimated time to import the blocks whose headers are downloaded, at the recent import rate
+    pub fn eta_to_sync(&self) -> Option<StdDuration> {
+        if self.peers_ahead().is_empty() {
+            return None
+        }
+        let best_block_number = self.client.chain_info().best_block_number;
+        let remaining_blocks = self.client.best_header().number().saturating_sub(best_block_number);
+        let mut import_meter = self.import_meter.lock();
+        let imported_blocks = import_meter.total(Instant::now());
+        estimate_time_to_sync(remaining_blocks, imported_blocks, import_meter.window())
+    }
 
-                    if peer_score > total_score {
-                        self.send_body_request(&id);
-                    }
-                }
-            }
-            SYNC_EXPIRE_TOKEN_BEGIN...SYNC_EXPIRE_TOKEN_END => {
-                let (id, request_id) = {
-                    let mut tokens_info = self.tokens_info.write();
-                    let token_info = match tokens_info.get_mut(&token) {
-                        Some(info) => info,
-                        None => return,
-                    };
+    pub fn status(&self) -> SyncStatus {
+        if !self.peers_ahead().is_empty() {
+            return SyncStatus::Syncing
+        }
+        if let Some(reason) = self.stall_reason() {
+            return SyncStatus::Stalled(reason)
+        }
+        let is_isolated =
+            self.no_peers_since.lock().map_or(false, |since| since.elapsed() >= self.config.no_peers_window);
+        if is_isolated {
+            SyncStatus::NoPeers
+        } else {
+            SyncStatus::Synced
+        }
+    }
 
-                    match token_info.request_id {
-                        Some(request_id) => {
-                            token_info.request_id = None;
-                            (token_info.node_id, request_id)
-                        }
-                        None => return,
-                    }
-                };
+    pub fn sync_phase(&self) -> SyncPhase {
+        *self.sync_phase.lock()
+    }
 
-                if let Some(requests) = self.requests.write().get_mut(&id) {
-                    let expired_request = requests.iter().find(|(r, _)| *r == request_id);
-                    if let Some((_, request)) = expired_request {
-                        match request {
-                            RequestMessage::Bodies(hashes) => {
-                                self.body_downloader.lock().reset_downloading(&hashes);
-                            }
-                            _ => unreachable!(),
-                        }
-                    }
-                }
+    fn current_phase(&self) -> SyncPhase {
+        if !self.body_downloader.lock().is_empty() {
+            return SyncPhase::BodyFill
+        }
+        if !self.pending_blocks.lock().is_empty() {
+            return SyncPhase::Finalization
+        }
+        if self.header_downloaders.read().values().any(|peer| peer.is_following_skeleton()) {
+            return SyncPhase::HeaderSkeleton
+        }
+        if !self.peers_ahead().is_empty() {
+            return SyncPhase::AncestorDiscovery
+        }
+        SyncPhase::Idle
+    }
 
-                self.dismiss_request(&id, request_id);
+    fn update_sync_phase(&self) {
+        let phase = self.current_phase();
+        {
+            let mut sync_phase = self.sync_phase.lock();
+            if *sync_phase == phase {
+                return
             }
-            _ => unreachable!(),
+            cinfo!(SYNC, "Sync phase changes from {:?} to {:?}", *sync_phase, phase);
+            *sync_phase = phase;
+        }
+        if let Some(callback) = &self.config.on_phase_change {
+            callback(phase);
         }
     }
-}
 
-impl ChainNotify for Extension {
-    fn new_headers(
-        &self,
-        imported: Vec<H256>,
-        _invalid: Vec<H256>,
-        enacted: Vec<H256>,
-        retracted: Vec<H256>,
-        _sealed: Vec<H256>,
-        _duration: u64,
-    ) {
-        let peer_ids: Vec<_> = self.header_downloaders.read().keys().cloned().collect();
-        for id in peer_ids {
-            if let Some(peer) = self.header_downloaders.write().get_mut(&id) {
-                peer.mark_as_imported(imported.clone());
+    /// The stall is over once we reach the score of the peers we've lost
+    fn stall_reason(&self) -> Option<StallReason> {
+        let ours = self.chain_tip();
+        let mut stalled = self.stalled.lock();
+        match *stalled {
+            Some((reason, target_score)) if ours.total_score < target_score => Some(reason),
+            _ => {
+                *stalled = None;
+                None
             }
         }
-        let mut enacted_headers: Vec<_> = enacted
-            .into_iter()
-            .map(|hash| self.client.block_header(BlockId::Hash(hash)).expect("Enacted header must exist"))
-            .collect();
-        enacted_headers.sort_unstable_by_key(|header| header.number());
-
-        enacted_headers
-            .into_iter()
-            .filter(|header| self.client.block_body(BlockId::Hash(header.hash())).is_none())
-            .for_each(|header| {
-                let parent = self
-                    .client
-                    .block_header(BlockId::Hash(header.parent_hash()))
-                    .expect("Enacted header must have parent");
-                self.body_downloader.lock().add_target(&header.decode(), &parent.decode());
-            });
-        self.body_downloader.lock().remove_target(&retracted);
     }
 
-    fn new_blocks(
-        &self,
-        imported: Vec<H256>,
-        invalid: Vec<H256>,
-        _enacted: Vec<H256>,
-        _retracted: Vec<H256>,
-        _sealed: Vec<H256>,
-        _duration: u64,
-    ) {
-        self.body_downloader.lock().remove_target(&imported);
-        self.body_downloader.lock().remove_target(&invalid);
+    /// Total score which is being synced to while `lock_sync_target` is set
+    pub fn sync_target(&self) -> Option<U256> {
+        *self.sync_target.lock()
+    }
 
+    /// The target is locked to the highest peer again once it's reached, applying the statuses received meanwhile
+    fn update_sync_target(&self) {
+        if !self.config.lock_sync_target {
+            return
+        }
+        let mut sync_target = self.sync_target.lock();
+        if let Some(target) = *sync_target {
+            if self.chain_tip().total_score < target && !self.peers_ahead().is_empty() {
+                return
+            }
+            cinfo!(SYNC, "Sync target {} is reached", target);
+        }
 
-        let chain_info = self.client.chain_info();
-        let peer_ids = self.header_downloaders.read();
-        for id in peer_ids.keys() {
-            self.send_message(
-                id,
-                Message::Status {
-                    total_score: chain_info.total_score,
-                    best_hash: chain_info.best_block_hash,
-                    genesis_hash: chain_info.genesis_hash,
-                },
-            );
+        let deferred: Vec<_> = self.deferred_statuses.lock().drain().collect();
+        for (id, (total_score, best_hash)) in deferred {
+            self.apply_status(&id, total_score, best_hash);
+        }
+        *sync_target = self.peers_ahead().first().map(|(_, total_score)| *total_score);
+        if let Some(target) = *sync_target {
+            cinfo!(SYNC, "Sync target is locked to {}", target);
         }
     }
-}
 
-impl Extension {
-    fn on_peer_status(&self, from: &NodeId, total_score: U256, best_hash: H256, genesis_hash: H256) {
-        // Validity check
-        if genesis_hash != self.client.chain_info().genesis_hash {
-            cinfo!(SYNC, "Genesis hash mismatch with peer {}", from);
-            return
+    /// Statuses are applied once the locked target is reached
+    fn defer_status(&self, id: &NodeId, total_score: U256, best_hash: H256) -> bool {
+        if self.sync_target.lock().is_none() {
+            return false
         }
+        ctrace!(SYNC, "Status of peer #{} is deferred until the sync target is reached", id);
+        self.deferred_statuses.lock().insert(*id, (total_score, best_hash));
+        true
+    }
 
-        cinfo!(SYNC, "Peer #{} status update: total_score: {}, best_hash: {}", from, total_score, best_hash);
-
-        let mut peers = self.header_downloaders.write();
-        if peers.contains_key(from) {
-            peers.get_mut(from).unwrap().update(total_score, best_hash);
-        } else {
-            peers.insert(*from, HeaderDownloader::new(self.client.clone(), total_score, best_hash));
+    fn check_isolation(&self) {
+        let mut no_peers_since = self.no_peers_since.lock();
+        if !self.header_downloaders.read().is_empty() {
+            *no_peers_since = None;
+            return
+        }
+        match *no_peers_since {
+            None => *no_peers_since = Some(Instant::now()),
+            Some(since) if since.elapsed() >= self.config.no_peers_window => {
+                cwarn!(SYNC, "There has been no peer to sync with for {} seconds", since.elapsed().as_secs());
+            }
+            _ => {}
         }
     }
 
-    fn on_peer_request(&self, from: &NodeId, id: u64, request: RequestMessage) {
-        if !self.header_downloaders.read().contains_key(from) {
-            cinfo!(SYNC, "Request from invalid peer #{} received", from);
+    /// Sync isn't completed until we've caught up with at least one peer
+    fn check_sync_deadline(&self, is_done: bool) {
+        let deadline = match self.config.sync_deadline {
+            Some(deadline) => deadline,
+            None => return,
+        };
+        if self.is_watchdog_done.load(Ordering::SeqCst) {
             return
         }
+        if is_done && !self.header_downloaders.read().is_empty() {
+            self.is_watchdog_done.store(true, Ordering::SeqCst);
+            return
+        }
+        if self.sync_started_at.elapsed() < deadline {
+            return
+        }
+        self.is_watchdog_done.store(true, Ordering::SeqCst);
+        cwarn!(SYNC, "Sync hasn't completed in {} seconds", deadline.as_secs());
+        if let Some(callback) = &self.config.on_sync_deadline {
+            callback();
+        }
+    }
 
-        if !self.is_valid_request(&request) {
-            cinfo!(SYNC, "Invalid request received from peer #{}", from);
+    /// We may be eclipsed if there are too few peers or all of them advertise the same tip while we're behind
+    fn check_peer_diversity(&self) {
+        if self.peers_ahead().is_empty() {
             return
         }
+        let (peer_count, tip_count) = {
+            let header_downloaders = self.header_downloaders.read();
+            let tips: HashSet<_> = header_downloaders.values().map(|peer| peer.best_hash()).collect();
+            (header_downloaders.len(), tips.len())
+        };
+        if peer_count >= self.config.min_diverse_peers && tip_count > 1 {
+            return
+        }
+        cwarn!(SYNC, "Peer diversity is low: {} peers advertise {} tips", peer_count, tip_count);
+        if let Some(callback) = &self.config.on_low_peer_diversity {
+            callback();
+        }
+    }
 
-        let response = match request {
-            RequestMessage::Headers {
-                start_number,
-                max_count,
-            } => self.create_headers_response(start_number, max_count),
-            RequestMessage::Bodies(hashes) => self.create_bodies_response(hashes),
-            RequestMessage::StateHead(hash) => self.create_state_head_response(hash),
-            RequestMessage::StateChunk {
-                block_hash,
-                tree_root,
-            } => self.create_state_chunk_response(block_hash, tree_root),
+    /// Drops the peers ahead of us if the best block hasn't advanced for the stall window,
+    /// which makes room for other peers
+    fn check_stall(&self) {
+        let stall_window = match self.config.stall_window {
+            Some(window) => window,
+            None => return,
+        };
+        let best_block_number = self.client.chain_info().best_block_number;
+        let stalled_peers = {
+            let mut last_progress = self.last_progress.lock();
+            if best_block_number != last_progress.0 {
+                *last_progress = (best_block_number, Instant::now());
+                return
+            }
+            if last_progress.1.elapsed() < stall_window {
+                return
+            }
+            // Not advancing isn't a stall if there is nothing to download
+            last_progress.1 = Instant::now();
+            self.peers_ahead()
         };
+        if stalled_peers.is_empty() {
+            return
+        }
 
-        self.send_response(from, id, response);
+        cwarn!(SYNC, "Best block #{} hasn't advanced, dropping {} peers", best_block_number, stalled_peers.len());
+        for (id, _) in stalled_peers {
+            self.remove_peer(&id, StallReason::NoProgress);
+        }
     }
 
-    fn is_valid_request(&self, request: &RequestMessage) -> bool {
-        match request {
-            RequestMessage::Headers {
-                ..
-            } => true,
-            RequestMessage::Bodies(hashes) => !hashes.is_empty(),
-            RequestMessage::StateHead(hash) => match self.client.block_number(BlockId::Hash(*hash)) {
-                Some(number) if number % SNAPSHOT_PERIOD == 0 => true,
-                _ => false,
-            },
-            RequestMessage::StateChunk {
-                block_hash,
-                ..
-            } => {
-                let _is_checkpoint = match self.client.block_number(BlockId::Hash(*block_hash)) {
-                    Some(number) if number % SNAPSHOT_PERIOD == 0 => true,
-                    _ => false,
-                };
-                // FIXME:  check tree_root
-                unimplemented!()
+    /// Messages received so far, recorded only if `record_messages` is set
+    pub fn recorded_messages(&self) -> Vec<(NodeId, Vec<u8>)> {
+        self.recorded_messages.lock().clone()
+    }
+
+    /// Feeds recorded messages in order, adding their senders as peers first
+    pub fn replay(&self, messages: &[(NodeId, Vec<u8>)]) {
+        for (id, data) in messages {
+            if !self.requests.read().contains_key(id) {
+                self.on_node_added(id, 0);
             }
+            self.on_message(id, data);
         }
     }
 
-    fn create_headers_response(&self, start_number: BlockNumber, max_count: u64) -> ResponseMessage {
-        let headers = (0..max_count)
-            .map(|number| self.client.block(BlockId::Number(start_number + number)))
-            .take_while(|block| block.is_some())
-            .map(|block| block.expect("take_while guarantees existance of item").header().decode())
-            .collect();
-        ResponseMessage::Headers(headers)
+    fn penalize(&self, id: &NodeId, amount: i64) {
+        if let Some(reputation) = self.reputations.write().get_mut(id) {
+            *reputation -= amount;
+            cdebug!(SYNC, "Peer #{} is penalized by {}, reputation: {}", id, amount, reputation);
+        }
     }
 
-    fn create_bodies_response(&self, hashes: Vec<H256>) -> ResponseMessage {
-        let bodies = hashes
-            .into_iter()
-            .map(|hash| self.client.block_body(BlockId::Hash(hash)).map(|body| body.parcels()).unwrap_or_default())
-            .collect();
-        ResponseMessage::Bodies(bodies)
+    fn reward(&self, id: &NodeId, amount: i64) {
+        if let Some(reputation) = self.reputations.write().get_mut(id) {
+            *reputation = ::std::cmp::min(*reputation + amount, MAX_REPUTATION);
+        }
     }
 
-    fn create_state_head_response(&self, _hash: H256) -> ResponseMessage {
-        unimplemented!()
+    fn decay_reputations(&self) {
+        for reputation in self.reputations.write().values_mut() {
+            *reputation = decay_reputation(*reputation, self.config.reputation_decay);
+        }
     }
 
-    fn create_state_chunk_response(&self, _hash: H256, _tree_root: H256) -> ResponseMessage {
-        unimplemented!()
+    /// Logs every message exchanged with the given peer, including its decoded contents
+    pub fn trace_peer(&self, id: NodeId) {
+        cinfo!(SYNC, "Start tracing messages of peer #{}", id);
+        *self.traced_peer.write() = Some(id);
     }
 
-    fn on_peer_response(&self, from: &NodeId, id: u64, mut response: ResponseMessage) {
-        let last_request = self.requests.read()[from].iter().find(|(i, _)| *i == id).cloned();
-        if let Some((_, request)) = last_request {
-            match &mut response {
-                ResponseMessage::Headers(headers) => {
-                    headers.sort_unstable_by_key(|h| h.number());
-                }
-                _ => {}
-            }
+    /// The lines logged for the traced peer are also passed to the hook
+    pub fn set_trace_hook(&self, hook: Box<Fn(&str) + Send + Sync>) {
+        *self.trace_hook.write() = Some(hook);
+    }
 
-            if !self.is_valid_response(&request, &response) {
-                return
-            }
+    fn trace(&self, line: String) {
+        cinfo!(SYNC, "{}", line);
+        if let Some(hook) = self.trace_hook.read().as_ref() {
+            hook(&line);
+        }
+    }
 
-            match response {
-                ResponseMessage::Headers(headers) => {
-                    self.dismiss_request(from, id);
-                    self.on_header_response(from, headers)
-                }
-                ResponseMessage::Bodies(bodies) => {
-                    let hashes = match request {
-                        RequestMessage::Bodies(hashes) => hashes,
-                        _ => unreachable!(),
-                    };
-                    assert_eq!(bodies.len(), hashes.len());
-                    if let Some(token) = self.tokens.read().get(from) {
-                        if let Some(token_info) = self.tokens_info.write().get_mut(token) {
-                            if token_info.request_id.is_none() {
-                                ctrace!(SYNC, "Expired before handling response");
-                                return
-                            }
-                            let api = self.api.read();
-                            api.as_ref().expect("Api must exist").clear_timer(*token).expect("Timer clear succeed");
-                            token_info.request_id = None;
-                        }
-                    }
-                    self.dismiss_request(from, id);
-                    self.on_body_response(hashes, bodies);
-                }
-                _ => unimplemented!(),
-            }
+    /// Downloaded blocks rejected by the filter are dropped instead of being imported
+    pub fn set_block_filter(&self, filter: Box<Fn(&Header) -> bool + Send + Sync>) {
+        *self.block_filter.write() = filter;
+    }
+
+    /// Bodies pruned from the client are served from the archive
+    pub fn set_archive(&self, archive: Box<Fn(&H256) -> Option<EncodedBody> + Send + Sync>) {
+        *self.archive.write() = Some(archive);
+    }
+
+    fn block_body(&self, hash: &H256) -> Option<EncodedBody> {
+        if let Some(body) = self.announced_body(hash) {
+            return Some(body)
+        }
+        if let Some(body) = self.pending_blocks.lock().get(hash) {
+            return Some(body.clone())
         }
+        self.client.block_body(BlockId::Hash(*hash)).or_else(|| {
+            let archive = self.archive.read();
+            archive.as_ref().and_then(|archive| archive(hash))
+        })
     }
 
-    fn is_valid_response(&self, request: &RequestMessage, response: &ResponseMessage) -> bool {
-        match (request, response) {
-            (
-                RequestMessage::Headers {
-                    start_number,
-                    ..
-                },
-                ResponseMessage::Headers(headers),
-            ) => {
-                // Continuity check
-                for neighbors in headers.windows(2) {
-                    let parent = &neighbors[0];
-                    let child = &neighbors[1];
-                    if child.number() != parent.number() + 1 || *child.parent_hash() != parent.hash() {
-                        return false
-                    }
-                }
+    /// Peers are likely to request the block we've just announced, so it's kept in memory for a while
+    fn cache_announced_block(&self, hash: H256) {
+        let header = self.client.block_header(BlockId::Hash(hash));
+        let body = self.client.block_body(BlockId::Hash(hash));
+        if let (Some(header), Some(body)) = (header, body) {
+            self.header_cache.lock().insert(header.number(), header);
+            let mut announced_blocks = self.announced_blocks.lock();
+            let lifetime = StdDuration::from_secs(ANNOUNCED_BLOCK_LIFETIME_SECS);
+            announced_blocks.retain(|_, (_, announced_at)| announced_at.elapsed() < lifetime);
+            announced_blocks.insert(hash, (body, Instant::now()));
+        }
+    }
 
-                headers.first().map(|header| header.number()) == Some(*start_number)
-            }
-            (RequestMessage::Bodies(hashes), ResponseMessage::Bodies(bodies)) => {
-                if hashes.len() != bodies.len() {
-                    return false
-                }
-                for body in bodies {
-                    for parcel in body {
-                        let is_valid = match &parcel.as_unsigned().action {
-                            Action::Custom(bytes) => self.client.custom_handlers().iter().any(|c| c.is_target(bytes)),
-                            _ => true,
-                        };
-                        if !is_valid {
-                            return false
-                        }
-                    }
-                }
-                true
-            }
-            (RequestMessage::StateHead(..), ResponseMessage::StateHead(..)) => unimplemented!(),
-            (
-                RequestMessage::StateChunk {
-                    ..
-                },
-                ResponseMessage::StateChunk(..),
-            ) => unimplemented!(),
-            _ => false,
+    fn announced_body(&self, hash: &H256) -> Option<EncodedBody> {
+        let lifetime = StdDuration::from_secs(ANNOUNCED_BLOCK_LIFETIME_SECS);
+        match self.announced_blocks.lock().get(hash) {
+            Some((body, announced_at)) if announced_at.elapsed() < lifetime => Some(body.clone()),
+            _ => None,
         }
     }
 
-    fn on_header_response(&self, from: &NodeId, headers: Vec<Header>) {
-        let mut completed = if let Some(peer) = self.header_downloaders.write().get_mut(from) {
-            let encoded = headers.iter().map(|h| EncodedHeader::new(h.rlp_bytes().to_vec())).collect();
-            peer.import_headers(encoded);
-            peer.downloaded()
-        } else {
-            Vec::new()
+    fn is_acceptable_block(&self, header: &Header) -> bool {
+        let filter = self.block_filter.read();
+        filter(header)
+    }
+
+    /// The block isn't imported, so its descendants can't be either
+    fn reject_branch(&self, body_downloader: &mut BodyDownloader, hash: H256) {
+        let mut rejected_blocks = self.rejected_blocks.lock();
+        rejected_blocks.insert(hash);
+        rejected_blocks.extend(body_downloader.remove_descendants(&hash));
+    }
+
+    /// A peer whose best block is already imported can't provide anything new:
+    /// the block is either an ancestor of our best block or on a branch we've out-scored
+    fn is_on_losing_fork(&self, id: &NodeId) -> bool {
+        let best_hash = match self.header_downloaders.read().get(id) {
+            Some(peer) => peer.best_hash(),
+            None => return true,
         };
-        completed.sort_unstable_by_key(|header| header.number());
+        self.client.block_body(BlockId::Hash(best_hash)).is_some()
+    }
 
-        let mut exists = Vec::new();
-        for header in completed {
-            match self.client.import_header(header.clone().into_inner()) {
-                Err(BlockImportError::Import(ImportError::AlreadyInChain)) => exists.push(header.hash()),
-                // FIXME: handle import errors
-                Err(err) => {
-                    cwarn!(SYNC, "Cannot import header({}): {:?}", header.hash(), err);
-                    break
+    pub fn peer_states(&self) -> HashMap<NodeId, PeerState> {
+        let reputations = self.reputations.read();
+        let contributed_blocks = self.contributed_blocks.lock();
+        self.header_downloaders
+            .read()
+            .iter()
+            .map(|(id, peer)| {
+                let state = PeerState {
+                    total_score: peer.total_score(),
+                    best_hash: peer.best_hash(),
+                    reputation: reputations.get(id).cloned().unwrap_or(0),
+                    contributed_blocks: contributed_blocks.get(id).cloned().unwrap_or(0),
+                };
+                (*id, state)
+            })
+            .collect()
+    }
+
+    fn credit_contributors(&self, imported: &[H256]) {
+        let mut body_contributors = self.body_contributors.lock();
+        let mut contributed_blocks = self.contributed_blocks.lock();
+        for hash in imported {
+            if let Some(id) = body_contributors.remove(hash) {
+                if let Some(count) = contributed_blocks.get_mut(&id) {
+                    *count += 1;
                 }
-                _ => {}
             }
         }
+    }
 
-        if let Some(peer) = self.header_downloaders.write().get_mut(from) {
-            peer.mark_as_imported(exists);
-            if let Some(request) = peer.create_request() {
-                self.send_header_request(from, request);
-            }
+    fn has_contributed(&self, id: &NodeId) -> bool {
+        self.contributed_blocks.lock().get(id).map_or(false, |count| *count > 0)
+    }
+
+    /// Peers with higher reputation and throughput are more likely to come first,
+    /// but preferred peers always come before the others and slow peers after them.
+    /// Among the rest, peers which have contributed valid blocks come first
+    fn weighted_shuffle(&self, peer_ids: Vec<NodeId>) -> Vec<NodeId> {
+        let now = Instant::now();
+        let weights = {
+            let reputations = self.reputations.read();
+            let mut peer_meters = self.peer_meters.lock();
+            peer_ids
+                .iter()
+                .map(|id| {
+                    let reputation = reputations.get(id).cloned().unwrap_or(0);
+                    let throughput = peer_meters.get_mut(id).map_or(0, |meter| meter.rate(now));
+                    peer_weight(reputation, throughput)
+                })
+                .collect()
+        };
+        let shuffled = weighted_shuffle(&mut thread_rng(), peer_ids, weights);
+        let (mut preferred, others): (Vec<_>, Vec<_>) =
+            shuffled.into_iter().partition(|id| self.config.preferred_peers.contains(id));
+        let (fast, slow): (Vec<_>, Vec<_>) = others.into_iter().partition(|id| !self.is_slow(id));
+        let (proven, unproven): (Vec<_>, Vec<_>) = fast.into_iter().partition(|id| self.has_contributed(id));
+        preferred.extend(proven);
+        preferred.extend(unproven);
+        preferred.extend(slow);
+        if self.config.deprioritize_leeches {
+            let (others, leeches): (Vec<_>, Vec<_>) = preferred.into_iter().partition(|id| !self.is_leech(id));
+            preferred = others;
+            preferred.extend(leeches);
         }
+        preferred
     }
 
-    fn on_body_response(&self, hashes: Vec<H256>, bodies: Vec<Vec<UnverifiedParcel>>) {
-        {
-            let mut body_downloader = self.body_downloader.lock();
-            body_downloader.import_bodies(hashes, bodies);
-            let completed = body_downloader.drain();
-            for (hash, parcels) in completed {
-                let header = self
-                    .client
-                    .block_header(BlockId::Hash(hash))
-                    .expect("Downloaded body's header must exist")
-                    .decode();
-                let block = Block {
-                    header,
-                    parcels,
-                };
-                cdebug!(SYNC, "Body download completed for #{}({})", block.header.number(), hash);
-                match self.client.import_block(block.rlp_bytes(Seal::With)) {
-                    Err(BlockImportError::Import(ImportError::AlreadyInChain)) => {
-                        cwarn!(SYNC, "Downloaded already existing block({})", hash)
-                    }
-                    Err(err) => {
-                        // FIXME: handle import errors
-                        cwarn!(SYNC, "Cannot import block({}): {:?}", hash, err);
-                        break
-                    }
-                    _ => {}
-                }
+    /// Bytes of the responses exchanged with the peer
+    pub fn peer_traffic(&self, id: &NodeId) -> Option<PeerTraffic> {
+        self.peer_traffic.lock().get(id).cloned()
+    }
+
+    /// A leech requests from us but hardly serves, though it's still served honestly
+    pub fn is_leech(&self, id: &NodeId) -> bool {
+        let traffic = match self.peer_traffic(id) {
+            Some(traffic) => traffic,
+            None => return false,
+        };
+        traffic.requested >= self.config.min_leech_bytes
+            && traffic.fairness_ratio().map_or(false, |ratio| ratio < self.config.leech_fairness_ratio)
+    }
+
+    fn is_slow(&self, id: &NodeId) -> bool {
+        self.peer_latencies.lock().get(id).map_or(false, |latency| *latency > self.config.slow_peer_latency)
+    }
+
+    /// Requests recently sent to the peer, from the oldest
+    pub fn peer_request_log(&self, id: &NodeId) -> Vec<RequestLogEntry> {
+        self.request_logs.lock().get(id).map_or_else(Vec::new, |log| log.iter().cloned().collect())
+    }
+
+    fn record_request(&self, id: &NodeId, request_id: u64) {
+        let now = Instant::now();
+        self.request_sent_at.lock().insert(request_id, now);
+        let mut request_logs = self.request_logs.lock();
+        let log = request_logs.entry(*id).or_insert_with(VecDeque::new);
+        if log.len() >= REQUEST_LOG_LENGTH {
+            log.pop_front();
+        }
+        log.push_back(RequestLogEntry {
+            request_id,
+            sent_at: now,
+            answered_at: None,
+        });
+    }
+
+    fn record_response(&self, id: &NodeId, request_id: u64) {
+        let now = Instant::now();
+        if let Some(sent_at) = self.request_sent_at.lock().remove(&request_id) {
+            self.peer_latencies.lock().insert(*id, now.duration_since(sent_at));
+        }
+        if let Some(log) = self.request_logs.lock().get_mut(id) {
+            if let Some(entry) = log.iter_mut().find(|entry| entry.request_id == request_id) {
+                entry.answered_at = Some(now);
             }
         }
+    }
+
+    /// The sync timer ticks less often while there is nothing to download
+    fn set_timer_idle(&self, is_idle: bool) {
+        if self.is_timer_idle.swap(is_idle, Ordering::SeqCst) == is_idle {
+            return
+        }
+        let interval = if is_idle {
+            cdebug!(SYNC, "Nothing to download, backing off the sync timer");
+            Duration::from_std(self.config.idle_timer_interval).expect("Interval is in range")
+        } else {
+            cdebug!(SYNC, "Resuming the sync timer");
+            Duration::milliseconds(SYNC_TIMER_INTERVAL)
+        };
+        let api = self.api.read();
+        let api = api.as_ref().expect("Api must exist");
+        api.clear_timer(SYNC_TIMER_TOKEN).expect("Timer clear succeed");
+        api.set_timer(SYNC_TIMER_TOKEN, interval).expect("Timer set succeeds");
+    }
 
-        let total_score = self.client.chain_info().total_score;
-        let mut peer_ids: Vec<_> = self.header_downloaders.read().keys().cloned().collect();
-        thread_rng().shuffle(&mut peer_ids);
+    fn throttled_peer_count(&self, count: usize) -> usize {
+        let load = (self.config.load_probe)().max(0.0).min(1.0);
+        ((1.0 - load) * count as f64).ceil() as usize
+    }
 
-        for id in peer_ids {
-            let peer_score = if let Some(peer) = self.header_downloaders.read().get(&id) {
-                peer.total_score()
-            } else {
-                U256::zero()
-            };
+    /// Messages which were in flight when the peer was removed may still arrive for a while
+    fn is_recently_removed(&self, id: &NodeId) -> bool {
+        let mut recently_removed = self.recently_removed.lock();
+        let lifetime = StdDuration::from_secs(REMOVED_PEER_LIFETIME_SECS);
+        recently_removed.retain(|_, removed_at| removed_at.elapsed() < lifetime);
+        recently_removed.contains_key(id)
+    }
 
-            if peer_score > total_score {
-                self.send_body_request(&id);
+    /// A peer reconnecting soon doesn't lose its reputation and latency
+    fn take_disconnected_stats(&self, id: &NodeId) -> Option<PeerStats> {
+        let mut disconnected_peers = self.disconnected_peers.lock();
+        let lifetime = StdDuration::from_secs(DISCONNECTED_PEER_LIFETIME_SECS);
+        disconnected_peers.retain(|_, stats| stats.disconnected_at.elapsed() < lifetime);
+        disconnected_peers.remove(id)
+    }
+
+    fn is_traced(&self, id: &NodeId) -> bool {
+        *self.traced_peer.read() == Some(*id)
+    }
+
+    /// Adds headers from the best header down to the best block as body download targets
+    fn add_hollow_targets(&self, body_downloader: &mut BodyDownloader) {
+        let mut header = self.client.best_header();
+        let mut hollow_headers = vec![header.decode()];
+        while self.client.block_body(BlockId::Hash(header.hash())).is_none() {
+            header = self
+                .client
+                .block_header(BlockId::Hash(header.parent_hash()))
+                .expect("Every imported header must have parent");
+            hollow_headers.push(header.decode());
+        }
+        let rejected_blocks = self.rejected_blocks.lock();
+        for neighbors in hollow_headers.windows(2).rev() {
+            let child = &neighbors[0];
+            let parent = &neighbors[1];
+            // The rest descend from a rejected block
+            if rejected_blocks.contains(&child.hash()) {
+                break
             }
+            cdebug!(SYNC, "Adding block #{} (hash: {}) for initial body download target", child.number(), child.hash());
+            body_downloader.add_target(child, parent);
         }
     }
+
+    /// Discards the state of the body downloader, which is rebuilt from the client
+    fn reset_body_downloader(&self, body_downloader: &mut BodyDownloader) {
+        *body_downloader = BodyDownloader::new(&self.config);
+        self.add_hollow_targets(body_downloader);
+    }
+
+    /// Returns the size of the encoded message
+    fn send_message(&self, id: &NodeId, message: Message) -> usize {
+        if self.is_traced(id) {
+            self.trace(format!("Send message to traced peer #{}: {:?}", id, message));
+        }
+        self.sent_messages.lock().record(message.type_name(), Instant::now());
+        let bytes = message.rlp_bytes().to_vec();
+        if let Some(window) = self.config.batch_window {
+            let batch = {
+                let mut batches = self.outbound_batches.lock();
+                if bytes.len() <= MAX_BATCHED_MESSAGE_BYTES {
+                    batches.entry(*id).or_insert_with(Vec::new).push(message);
+                    if !self.is_flush_scheduled.swap(true, Ordering::SeqCst) {
+                        let window = Duration::from_std(window).expect("Window is in range");
+                        let api = self.api.read();
+                        api.as_ref()
+                            .expect("Api must exist")
+                            .set_timer_once(BATCH_FLUSH_TOKEN, window)
+                            .expect("Timer set succeeds");
+                    }
+                    return bytes.len()
+                }
+                batches.remove(id)
+            };
+            // Messages queued earlier must be sent first
+            if let Some(batch) = batch {
+                self.send_batch(id, batch);
+            }
+        }
+        let size = bytes.len();
+        self.send_bytes(id, bytes);
+        size
+    }
+
+    /// Broadcasts in the window are deferred to its end, which announces the latest tip at that time
+    fn broadcast_status(&self) {
+        if let Some(window) = self.config.status_broadcast_window {
+            let mut last_status_broadcast = self.last_status_broadcast.lock();
+            if let Some(elapsed) = last_status_broadcast.map(|at| at.elapsed()) {
+                if elapsed < window {
+                    if !self.is_status_broadcast_scheduled.swap(true, Ordering::SeqCst) {
+                        let remaining = Duration::from_std(window - elapsed).expect("Window is in range");
+                        let api = self.api.read();
+                        api.as_ref()
+                            .expect("Api must exist")
+                            .set_timer_once(STATUS_BROADCAST_TOKEN, remaining)
+                            .expect("Timer set succeeds");
+                    }
+                    return
+                }
+            }
+            *last_status_broadcast = Some(Instant::now());
+        }
+        self.announce_best_block();
+    }
+
+    fn on_status_broadcast_timeout(&self) {
+        self.is_status_broadcast_scheduled.store(false, Ordering::SeqCst);
+        *self.last_status_broadcast.lock() = Some(Instant::now());
+        self.announce_best_block();
+    }
+
+    fn announce_best_block(&self) {
+        let best_hash = self.client.chain_info().best_block_hash;
+        let peer_ids: Vec<_> = self.header_downloaders.read().keys().cloned().collect();
+        if !peer_ids.is_empty() {
+            self.cache_announced_block(best_hash);
+        }
+        let recent_hashes = self.recent_block_hashes();
+        for id in peer_ids {
+            self.send_status(&id);
+            self.announced_best.lock().insert(id, best_hash);
+            if recent_hashes.len() > 1 {
+                self.send_message(&id, Message::NewBlockHashes(recent_hashes.clone()));
+            }
+        }
+    }
+
+    /// Peers which missed the previous announcements can catch up with these
+    fn recent_block_hashes(&self) -> Vec<H256> {
+        let best_block_number = self.client.chain_info().best_block_number;
+        let mut hashes: Vec<_> = (0..self.config.announcement_depth as u64)
+            .take_while(|depth| *depth <= best_block_number)
+            .filter_map(|depth| self.client.block_hash(BlockId::Number(best_block_number - depth)))
+            .collect();
+        hashes.reverse();
+        hashes
+    }
+
+    fn flush_batches(&self) {
+        self.is_flush_scheduled.store(false, Ordering::SeqCst);
+        let batches: Vec<_> = self.outbound_batches.lock().drain().collect();
+        for (id, batch) in batches {
+            self.send_batch(&id, batch);
+        }
+    }
+
+    fn send_batch(&self, id: &NodeId, mut batch: Vec<Message>) {
+        let message = if batch.len() == 1 {
+            batch.pop().expect("Batch has a message")
+        } else {
+            Message::Batch(batch)
+        };
+        self.send_bytes(id, message.rlp_bytes().to_vec());
+    }
+
+    fn send_bytes(&self, id: &NodeId, bytes: Vec<u8>) {
+        let api = self.api.read();
+        let api = api.as_ref().expect("Api must exist");
+        if bytes.len() <= self.config.chunk_bytes {
+            self.upload_meter.lock().record(bytes.len(), Instant::now());
+            api.send(id, &bytes);
+            return
+        }
+
+        let chunk_id = self.last_chunk.fetch_add(1, Ordering::Relaxed) as u64;
+        let chunks: Vec<_> = bytes.chunks(self.config.chunk_bytes).collect();
+        let total = chunks.len() as u64;
+        for (seq, data) in chunks.into_iter().enumerate() {
+            let chunk = Message::Chunked {
+                id: chunk_id,
+                seq: seq as u64,
+                total,
+                data: data.to_vec(),
+            };
+            let bytes = chunk.rlp_bytes().to_vec();
+            self.upload_meter.lock().record(bytes.len(), Instant::now());
+            api.send(id, &bytes);
+        }
+    }
+
+    fn dismiss_request(&self, id: &NodeId, request_id: u64) {
+        if let Some(requests) = self.requests.write().get_mut(id) {
+            requests.retain(|(i, _)| *i != request_id);
+        }
+        self.request_sent_at.lock().remove(&request_id);
+    }
+
+    fn send_header_request(&self, id: &NodeId, request: RequestMessage) -> Option<u64> {
+        if let Some(requests) = self.requests.write().get_mut(id) {
+            // A headers request is sent again only after the previous one has expired
+            requests.retain(|(_, request)| match request {
+                RequestMessage::Headers {
+                    ..
+                } => false,
+                _ => true,
+            });
+            let request_id = self.last_request.fetch_add(1, Ordering::Relaxed) as u64;
+            requests.push((request_id, request.clone()));
+            self.record_request(id, request_id);
+            self.send_message(id, Message::Request(request_id, request));
+            return Some(request_id)
+        }
+        None
+    }
+
+    /// A peer serves a segment of a skeleton followed by a peer which isn't ahead of it,
+    /// and requests its own headers only if there's no such skeleton
+    fn request_headers(&self, id: &NodeId) {
+        let (request, is_segment) = {
+            let mut header_downloaders = self.header_downloaders.write();
+            let (total_score, is_requesting) = match header_downloaders.get(id) {
+                Some(peer) => (peer.total_score(), peer.is_requesting()),
+                None => return,
+            };
+            if is_requesting || header_downloaders.values().any(|owner| owner.is_segment_requested_from(id)) {
+                return
+            }
+            let segment = header_downloaders
+                .values_mut()
+                .filter(|owner| owner.is_following_skeleton() && owner.total_score() <= total_score)
+                .filter_map(|owner| owner.create_segment_request(id))
+                .next();
+            let is_waiting = header_downloaders
+                .values()
+                .any(|owner| owner.is_following_skeleton() && owner.total_score() <= total_score);
+            let is_backfilling = header_downloaders.get(id).map_or(false, |peer| peer.is_backfilling());
+            match segment {
+                Some(request) => (request, true),
+                None if is_waiting && !is_backfilling => return,
+                None => match header_downloaders.get_mut(id).and_then(|peer| peer.create_request()) {
+                    Some(request) => (request, false),
+                    None => return,
+                },
+            }
+        };
+        if let Some(request_id) = self.send_header_request(id, request) {
+            let mut segment_requests = self.segment_requests.lock();
+            if is_segment {
+                segment_requests.insert(*id, request_id);
+            } else {
+                segment_requests.remove(id);
+            }
+        }
+    }
+
+    fn send_score_proof_request(&self, id: &NodeId, best_hash: H256) {
+        if let Some(requests) = self.requests.write().get_mut(id) {
+            // The proof of a previous status isn't needed anymore
+            requests.retain(|(_, request)| match request {
+                RequestMessage::ScoreProof(..) => false,
+                _ => true,
+            });
+            let request_id = self.last_request.fetch_add(1, Ordering::Relaxed) as u64;
+            let request = RequestMessage::ScoreProof(best_hash);
+            requests.push((request_id, request.clone()));
+            self.record_request(id, request_id);
+            self.send_message(id, Message::Request(request_id, request));
+        }
+    }
+
+    fn send_body_request(&self, id: &NodeId) {
+        if self.has_body_request(id) || self.is_inflight_window_full(id) {
+            return
+        }
+
+        let request = self.body_downloader.lock().create_request();
+        if let Some(request) = request {
+            self.dispatch_body_request(id, request);
+        }
+    }
+
+    /// Pending bodies are split across all idle peers at once in aggressive mode
+    fn send_body_requests(&self, peer_ids: Vec<NodeId>) {
+        if !self.config.aggressive {
+            for id in peer_ids {
+                self.send_body_request(&id);
+            }
+            return
+        }
+
+        let idle_peers: Vec<_> =
+            peer_ids.into_iter().filter(|id| !self.has_body_request(id) && !self.is_inflight_window_full(id)).collect();
+        let requests = self.body_downloader.lock().create_requests(idle_peers.len());
+        for (id, request) in idle_peers.iter().zip(requests) {
+            self.dispatch_body_request(id, request);
+        }
+    }
+
+    /// Peers are not requested more bytes than they can deliver promptly
+    fn is_inflight_window_full(&self, id: &NodeId) -> bool {
+        let inflight_bytes =
+            self.requests.read().get(id).map_or(0, |requests| {
+                requests.iter().map(|(_, request)| estimated_response_bytes(request)).sum::<usize>()
+            });
+        inflight_bytes >= self.config.max_inflight_bytes
+    }
+
+    fn has_body_request(&self, id: &NodeId) -> bool {
+        self.requests.read().get(id).map_or(true, |requests| {
+            requests.iter().any(|r| match r {
+                (_, RequestMessage::Bodies(..)) => true,
+                _ => false,
+            })
+        })
+    }
+
+    fn dispatch_body_request(&self, id: &NodeId, request: RequestMessage) {
+        let request = match request {
+            RequestMessage::Bodies(hashes) => {
+                let (pruned, hashes): (Vec<_>, Vec<_>) = {
+                    let pruned_bodies = self.pruned_bodies.lock();
+                    let pruned = pruned_bodies.get(id);
+                    hashes.into_iter().partition(|hash| pruned.map_or(false, |pruned| pruned.contains(hash)))
+                };
+                if !pruned.is_empty() {
+                    ctrace!(SYNC, "Skip {} bodies pruned by peer #{}", pruned.len(), id);
+                    self.body_downloader.lock().reset_downloading(&pruned);
+                }
+                if hashes.is_empty() {
+                    return
+                }
+                RequestMessage::Bodies(hashes)
+            }
+            request => request,
+        };
+        if let Some(requests) = self.requests.write().get_mut(id) {
+            let request_id = self.last_request.fetch_add(1, Ordering::Relaxed) as u64;
+            requests.push((request_id, request.clone()));
+            self.record_request(id, request_id);
+            self.send_message(id, Message::Request(request_id, request));
+
+            let tokens = self.tokens.read();
+            let mut tokens_info = self.tokens_info.write();
+
+            let token = tokens.get(id).unwrap();
+            let token_info = tokens_info.get_mut(token).unwrap();
+
+            let timeout = Duration::from_std(self.config.body_request_timeout).expect("Timeout is in range");
+            let api = self.api.read();
+            api.as_ref().expect("Api must exist").set_timer_once(*token, timeout).expect("Timer set succeeds");
+            token_info.request_id = Some(request_id);
+        }
+    }
+
+    /// Headers being downloaded may descend from blocks retracted from our chain
+    fn reanchor_header_downloaders(&self) {
+        cinfo!(SYNC, "Best block is reorganized, downloading headers again from the new best block");
+        for peer in self.header_downloaders.write().values_mut() {
+            peer.reanchor();
+        }
+        self.segment_requests.lock().clear();
+        for requests in self.requests.write().values_mut() {
+            requests.retain(|(_, request)| match request {
+                RequestMessage::Headers {
+                    ..
+                } => false,
+                _ => true,
+            });
+        }
+    }
+
+    /// Downloads the chain again from the genesis block, discarding the sync state and the outstanding requests.
+    /// It's only for recovering from a corrupt sync state, and the extension never calls it by itself
+    pub fn force_resync_from_genesis(&self) {
+        cwarn!(SYNC, "Resync from the genesis block is forced, discarding the sync state");
+        for peer in self.header_downloaders.write().values_mut() {
+            peer.reanchor_to_genesis();
+        }
+        self.segment_requests.lock().clear();
+        for requests in self.requests.write().values_mut() {
+            let mut request_sent_at = self.request_sent_at.lock();
+            for (request_id, _) in requests.drain(..) {
+                request_sent_at.remove(&request_id);
+            }
+        }
+        self.reset_body_downloader(&mut self.body_downloader.lock());
+
+        let peer_ids: Vec<_> = self.header_downloaders.read().keys().cloned().collect();
+        for id in peer_ids {
+            self.request_headers(&id);
+        }
+    }
+
+    /// Only imported blocks are claimed, not the downloaded headers or the queued blocks,
+    /// since the peer would request the bodies which can't be served yet
+    fn send_status(&self, id: &NodeId) {
+        let chain_info = self.client.chain_info();
+        // Peers of the first version can't decode the network id, the features and the fork id
+        let is_legacy = self.peer_versions.lock().get(id) == Some(&LEGACY_VERSION);
+        let status = if is_legacy {
+            Message::Status {
+                total_score: chain_info.total_score,
+                best_hash: chain_info.best_block_hash,
+                genesis_hash: chain_info.genesis_hash,
+                network_id: None,
+                features: Vec::new(),
+                fork_id: None,
+            }
+        } else {
+            Message::Status {
+                total_score: chain_info.total_score,
+                best_hash: chain_info.best_block_hash,
+                genesis_hash: chain_info.genesis_hash,
+                network_id: Some(self.config.network_id),
+                features: self.config.features.clone(),
+                fork_id: Some(self.fork_id()),
+            }
+        };
+        self.send_message(id, status);
+    }
+
+    fn send_response(&self, id: &NodeId, request_id: u64, response: ResponseMessage) {
+        let size = self.send_message(id, Message::Response(request_id, response));
+        if let Some(traffic) = self.peer_traffic.lock().get_mut(id) {
+            traffic.requested += size;
+        }
+    }
+}
+
+impl NetworkExtension for Extension {
+    fn name(&self) -> &'static str {
+        "block-propagation"
+    }
+    fn need_encryption(&self) -> bool {
+        false
+    }
+
+    fn versions(&self) -> &[u64] {
+        const VERSIONS: &'static [u64] = &[LEGACY_VERSION, 1];
+        &VERSIONS
+    }
+
+    fn on_initialize(&self, api: Arc<Api>) {
+        // The network service may initialize the extension again after it restarts
+        let is_initialized = self.api.read().is_some();
+        if is_initialized {
+            cwarn!(SYNC, "Sync extension is initialized again");
+            let peer_ids: Vec<_> = self.requests.read().keys().cloned().collect();
+            for id in peer_ids {
+                self.on_node_removed(&id);
+            }
+            let api = self.api.read();
+            api.as_ref().expect("Api must exist").clear_timer(SYNC_TIMER_TOKEN).expect("Timer clear succeed");
+        }
+
+        let mut api_lock = self.api.write();
+        api.set_timer(SYNC_TIMER_TOKEN, Duration::milliseconds(SYNC_TIMER_INTERVAL)).expect("Timer set succeeds");
+        self.is_timer_idle.store(false, Ordering::SeqCst);
+        *api_lock = Some(api);
+        if is_initialized {
+            // Download targets are kept from the previous initialization
+            return
+        }
+
+        self.add_hollow_targets(&mut self.body_downloader.lock());
+        cinfo!(SYNC, "Sync extension initialized");
+    }
+
+    fn on_node_added(&self, id: &NodeId, version: u64) {
+        let mut requests = self.requests.write();
+        let mut tokens = self.tokens.write();
+        let mut tokens_info = self.tokens_info.write();
+        let mut token_generator = self.token_generator.lock();
+
+        // Status must be sent only once per session
+        if requests.contains_key(id) {
+            cwarn!(SYNC, "Peer #{} is already added", id);
+            return
+        }
+        if let Some(target_peers) = self.config.target_peers {
+            if requests.len() >= target_peers {
+                cinfo!(SYNC, "Peer #{} is ignored since there are already {} peers", id, requests.len());
+                return
+            }
+        }
+
+        cinfo!(SYNC, "New peer detected #{}", id);
+        self.recently_removed.lock().remove(id);
+        self.pending_handshakes.lock().insert(*id, Instant::now());
+        self.peer_versions.lock().insert(*id, version);
+        self.send_status(id);
+
+        let token = token_generator.gen().expect("Token generator is full");
+        let token_info = TokenInfo {
+            node_id: *id,
+            request_id: None,
+        };
+
+        let t = requests.insert(*id, Vec::new());
+        debug_assert_eq!(None, t);
+        let t = tokens_info.insert(token, token_info);
+        debug_assert_eq!(None, t);
+        let t = tokens.insert(*id, token);
+        debug_assert_eq!(None, t);
+        debug_assert!(t.is_none());
+        let stats = self.take_disconnected_stats(id);
+        if let Some(stats) = &stats {
+            cdebug!(SYNC, "Peer #{} reconnected with reputation {}", id, stats.reputation);
+        }
+        self.reputations.write().insert(*id, stats.as_ref().map_or(0, |stats| stats.reputation));
+        self.contributed_blocks.lock().insert(*id, stats.as_ref().map_or(0, |stats| stats.contributed_blocks));
+        if let Some(latency) = stats.and_then(|stats| stats.latency) {
+            self.peer_latencies.lock().insert(*id, latency);
+        }
+        self.peer_meters.lock().insert(*id, BandwidthMeter::new(StdDuration::from_secs(BANDWIDTH_WINDOW_SECS)));
+        self.peer_traffic.lock().insert(*id, PeerTraffic::default());
+    }
+
+    fn on_node_removed(&self, id: &NodeId) {
+        self.remove_peer(id, StallReason::PeersRemoved);
+    }
+
+    fn on_message(&self, id: &NodeId, data: &[u8]) {
+        if self.is_recently_removed(id) {
+            ctrace!(SYNC, "Message from removed peer #{} is ignored", id);
+            return
+        }
+        if data.len() > self.config.max_message_bytes {
+            cinfo!(SYNC, "Message of {} bytes from peer #{} is too large", data.len(), id);
+            self.penalize(id, INVALID_MESSAGE_PENALTY);
+            return
+        }
+        if self.config.record_messages {
+            self.recorded_messages.lock().push((*id, data.to_vec()));
+        }
+        self.download_meter.lock().record(data.len(), Instant::now());
+        if let Some(meter) = self.peer_meters.lock().get_mut(id) {
+            meter.record(data.len(), Instant::now());
+        }
+        self.on_peer_message(id, data);
+    }
+
+    fn on_timeout(&self, token: TimerToken) {
+        match token {
+            SYNC_TIMER_TOKEN => {
+                self.decay_reputations();
+                self.check_isolation();
+                self.check_stall();
+                self.check_peer_diversity();
+                self.chunk_assembler.lock().remove_expired(Instant::now());
+                self.probe_genesis_mismatches();
+                self.drop_silent_peers();
+                if self.config.observer {
+                    return
+                }
+                self.update_sync_target();
+                self.update_sync_phase();
+
+                let is_done = self.body_downloader.lock().is_empty()
+                    && self.peers_ahead().is_empty()
+                    && !self.header_downloaders.read().values().any(|peer| peer.is_idle());
+                self.check_sync_deadline(is_done);
+                if is_done {
+                    self.set_timer_idle(true);
+                    return
+                }
+                self.set_timer_idle(false);
+
+                // Bodies are caught up first if headers are too far ahead of them
+                let is_header_leading = self.metrics().header_body_ratio() > MAX_HEADER_BODY_RATIO;
+                if is_header_leading {
+                    ctrace!(SYNC, "Headers are downloaded far ahead of bodies, requesting bodies only");
+                }
+
+                let ours = self.chain_tip();
+                let peer_ids: Vec<_> = self.header_downloaders.read().keys().cloned().collect();
+                let mut peer_ids = self.weighted_shuffle(peer_ids);
+                let count = self.throttled_peer_count(peer_ids.len());
+                peer_ids.truncate(count);
+
+                let mut body_peer_ids = Vec::new();
+                for id in peer_ids {
+                    if self.is_on_losing_fork(&id) {
+                        ctrace!(SYNC, "Skip requests to peer #{} on a losing fork", id);
+                        continue
+                    }
+
+                    if self.is_inflight_window_full(&id) {
+                        ctrace!(SYNC, "Skip requests to peer #{} with full in-flight window", id);
+                        continue
+                    }
+
+                    if !is_header_leading {
+                        self.request_headers(&id);
+                    }
+
+                    if self.is_peer_chain_chosen(&id, &ours) {
+                        body_peer_ids.push(id);
+                    }
+                }
+                self.send_body_requests(body_peer_ids);
+            }
+            BATCH_FLUSH_TOKEN => self.flush_batches(),
+            STATUS_BROADCAST_TOKEN => self.on_status_broadcast_timeout(),
+            SYNC_EXPIRE_TOKEN_BEGIN...SYNC_EXPIRE_TOKEN_END => {
+                let (id, request_id) = {
+                    let mut tokens_info = self.tokens_info.write();
+                    let token_info = match tokens_info.get_mut(&token) {
+                        Some(info) => info,
+                        None => return,
+                    };
+
+                    match token_info.request_id {
+                        Some(request_id) => {
+                            token_info.request_id = None;
+                            (token_info.node_id, request_id)
+                        }
+                        None => return,
+                    }
+                };
+
+                if let Some(requests) = self.requests.write().get_mut(&id) {
+                    let expired_request = requests.iter().find(|(r, _)| *r == request_id);
+                    if let Some((_, request)) = expired_request {
+                        match request {
+                            RequestMessage::Bodies(hashes) => {
+                                self.body_downloader.lock().reset_downloading(&hashes);
+                            }
+                            _ => unreachable!(),
+                        }
+                    }
+                }
+
+                self.dismiss_request(&id, request_id);
+            }
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl ChainNotify for Extension {
+    fn new_headers(
+        &self,
+        imported: Vec<H256>,
+        _invalid: Vec<H256>,
+        enacted: Vec<H256>,
+        retracted: Vec<H256>,
+        _sealed: Vec<H256>,
+        _duration: u64,
+    ) {
+        let peer_ids: Vec<_> = self.header_downloaders.read().keys().cloned().collect();
+        for id in peer_ids {
+            if let Some(peer) = self.header_downloaders.write().get_mut(&id) {
+                peer.mark_as_imported(imported.clone());
+            }
+        }
+        let mut enacted_headers: Vec<_> = enacted
+            .into_iter()
+            .map(|hash| self.client.block_header(BlockId::Hash(hash)).expect("Enacted header must exist"))
+            .collect();
+        enacted_headers.sort_unstable_by_key(|header| header.number());
+
+        enacted_headers
+            .into_iter()
+            .filter(|header| self.client.block_body(BlockId::Hash(header.hash())).is_none())
+            .filter(|header| {
+                let mut rejected_blocks = self.rejected_blocks.lock();
+                if rejected_blocks.contains(&header.parent_hash()) {
+                    rejected_blocks.insert(header.hash());
+                    return false
+                }
+                true
+            })
+            .for_each(|header| {
+                let parent = self
+                    .client
+                    .block_header(BlockId::Hash(header.parent_hash()))
+                    .expect("Enacted header must have parent");
+                self.body_downloader.lock().add_target(&header.decode(), &parent.decode());
+            });
+        self.body_downloader.lock().remove_target(&retracted);
+    }
+
+    fn new_blocks(
+        &self,
+        imported: Vec<H256>,
+        invalid: Vec<H256>,
+        _enacted: Vec<H256>,
+        retracted: Vec<H256>,
+        _sealed: Vec<H256>,
+        _duration: u64,
+    ) {
+        if !retracted.is_empty() {
+            self.reanchor_header_downloaders();
+        }
+        self.body_downloader.lock().remove_target(&imported);
+        self.body_downloader.lock().remove_target(&invalid);
+        {
+            let mut pending_blocks = self.pending_blocks.lock();
+            for hash in imported.iter().chain(invalid.iter()) {
+                pending_blocks.remove(hash);
+            }
+        }
+        self.credit_contributors(&imported);
+        {
+            let mut body_contributors = self.body_contributors.lock();
+            for hash in &invalid {
+                body_contributors.remove(hash);
+            }
+        }
+        // The parent of deferred blocks may have been imported
+        if !imported.is_empty() {
+            self.import_downloaded_bodies();
+        }
+        // Canonical blocks may have changed
+        self.header_cache.lock().clear();
+
+        if self.config.observer {
+            return
+        }
+        self.broadcast_status();
+    }
+}
+
+impl Extension {
+    fn remove_peer(&self, id: &NodeId, reason: StallReason) {
+        let (outstanding, removed_peer) = {
+            let mut requests = self.requests.write();
+            let mut header_downloaders = self.header_downloaders.write();
+            let mut tokens = self.tokens.write();
+            let mut tokens_info = self.tokens_info.write();
+            let mut token_generator = self.token_generator.lock();
+
+            // Ignored peers don't have any state
+            if !requests.contains_key(id) {
+                return
+            }
+
+            cinfo!(SYNC, "Peer removed #{}", id);
+            let removed_peer = header_downloaders.remove(id);
+            self.segment_requests.lock().remove(id);
+            self.peer_versions.lock().remove(id);
+
+            let removed = requests.remove(id);
+            debug_assert_ne!(None, removed);
+            let token = tokens.remove(id);
+            debug_assert_ne!(None, token);
+            let token = token.unwrap();
+            let token_info = tokens_info.remove(&token);
+            debug_assert_ne!(None, token_info);
+            if token_info.map_or(false, |info| info.request_id.is_some()) {
+                let api = self.api.read();
+                api.as_ref().expect("Api must exist").clear_timer(token).expect("Timer clear succeed");
+            }
+            let t = token_generator.restore(token);
+            debug_assert!(t);
+            let reputation = self.reputations.write().remove(id).unwrap_or(0);
+            self.peer_features.write().remove(id);
+            self.peer_meters.lock().remove(id);
+            self.peer_traffic.lock().remove(id);
+            let latency = self.peer_latencies.lock().remove(id);
+            let contributed_blocks = self.contributed_blocks.lock().remove(id).unwrap_or(0);
+            self.disconnected_peers.lock().insert(
+                *id,
+                PeerStats {
+                    reputation,
+                    latency,
+                    contributed_blocks,
+                    disconnected_at: Instant::now(),
+                },
+            );
+            self.unproven_claims.lock().remove(id);
+            self.score_proofs.lock().remove(id);
+            self.recently_removed.lock().insert(*id, Instant::now());
+            self.request_logs.lock().remove(id);
+            self.announced_best.lock().remove(id);
+            let mut pivot_peer = self.pivot_peer.write();
+            if *pivot_peer == Some(*id) {
+                cinfo!(SYNC, "Pivot peer #{} is removed", id);
+                *pivot_peer = None;
+            }
+            self.chunk_assembler.lock().remove_peer(id);
+            self.outbound_batches.lock().remove(id);
+            self.deferred_statuses.lock().remove(id);
+            self.genesis_mismatches.lock().remove(id);
+            self.pending_handshakes.lock().remove(id);
+            self.unavailable_bodies.lock().remove(id);
+            self.pruned_bodies.lock().remove(id);
+
+            (removed.unwrap_or_default(), removed_peer)
+        };
+
+        // Bodies requested from the removed peer must be available to other peers
+        for (request_id, request) in outstanding {
+            self.request_sent_at.lock().remove(&request_id);
+            if let RequestMessage::Bodies(hashes) = request {
+                self.body_downloader.lock().reset_downloading(&hashes);
+            }
+        }
+
+        if let Some(peer) = removed_peer {
+            if self.is_ahead(&peer, &self.chain_tip()) && self.peers_ahead().is_empty() {
+                cwarn!(SYNC, "No peer is ahead of us anymore while we're behind: {:?}", reason);
+                let mut stalled = self.stalled.lock();
+                let target_score =
+                    (*stalled).map_or(peer.total_score(), |(_, score)| ::std::cmp::max(score, peer.total_score()));
+                *stalled = Some((reason, target_score));
+            }
+        }
+    }
+
+    fn on_peer_status(
+        &self,
+        from: &NodeId,
+        total_score: U256,
+        mut best_hash: H256,
+        genesis_hash: H256,
+        network_id: Option<NetworkId>,
+        features: Vec<u32>,
+    ) {
+        if !self.requests.read().contains_key(from) {
+            cinfo!(SYNC, "Status from invalid peer #{} received", from);
+            return
+        }
+
+        // Validity check
+        if genesis_hash != self.client.chain_info().genesis_hash {
+            cinfo!(SYNC, "Genesis hash mismatch with peer {}", from);
+            self.on_genesis_mismatch(from);
+            return
+        }
+        self.genesis_mismatches.lock().remove(from);
+        // Older versions don't send the network id
+        if network_id.map_or(false, |network_id| network_id != self.config.network_id) {
+            cinfo!(SYNC, "Network id mismatch with peer {}", from);
+            return
+        }
+        if best_hash.is_zero() {
+            // A peer which hasn't initialized its best block can only have the genesis
+            let genesis_score = self.client.block_total_score(BlockId::Hash(genesis_hash));
+            if genesis_score != Some(total_score) {
+                cinfo!(SYNC, "Peer #{} sent a null best hash", from);
+                self.penalize(from, INVALID_MESSAGE_PENALTY);
+                return
+            }
+            best_hash = genesis_hash;
+        }
+        // An older status may arrive after a newer one
+        let known_score = self.header_downloaders.read().get(from).map(|peer| peer.total_score());
+        if let Some(known_score) = known_score {
+            if total_score < known_score {
+                cdebug!(SYNC, "Outdated status of peer #{} is ignored: {} < {}", from, total_score, known_score);
+                return
+            }
+        }
+
+        self.pending_handshakes.lock().remove(from);
+        cinfo!(SYNC, "Peer #{} status update: total_score: {}, best_hash: {}", from, total_score, best_hash);
+        // A peer having only the genesis block is still served, but never requested since it is on a losing fork
+        if best_hash == genesis_hash {
+            cdebug!(SYNC, "Peer #{} has only the genesis block", from);
+        }
+        self.peer_features.write().insert(*from, features);
+
+        // The announcement may have been dropped, so it's sent again but only once
+        let announced = self.announced_best.lock().remove(from);
+        if let Some(announced) = announced {
+            let chain_info = self.client.chain_info();
+            if announced == chain_info.best_block_hash && total_score < chain_info.total_score {
+                cdebug!(SYNC, "Peer #{} hasn't imported our best block {}, announcing it again", from, announced);
+                self.send_status(from);
+            }
+        }
+
+        // Until the claimed score is proven, the peer is treated as if it had the same chain as ours
+        let (total_score, best_hash) = {
+            let ours = self.chain_tip();
+            let claimed = ChainTip {
+                total_score,
+                best_hash,
+            };
+            let needs_proof = self.config.verify_score_proof && !self.config.observer;
+            self.score_proofs.lock().remove(from);
+            if needs_proof && self.config.fork_choice.is_better(&claimed, &ours) {
+                self.unproven_claims.lock().insert(*from, claimed);
+                self.send_score_proof_request(from, best_hash);
+                (ours.total_score, ours.best_hash)
+            } else {
+                self.unproven_claims.lock().remove(from);
+                (total_score, best_hash)
+            }
+        };
+
+        if self.defer_status(from, total_score, best_hash) {
+            return
+        }
+        self.apply_status(from, total_score, best_hash);
+    }
+
+    fn apply_status(&self, from: &NodeId, total_score: U256, best_hash: H256) {
+        let has_work = {
+            let mut peers = self.header_downloaders.write();
+            if peers.contains_key(from) {
+                peers.get_mut(from).unwrap().update(total_score, best_hash);
+            } else {
+                peers.insert(*from, HeaderDownloader::new(self.client.clone(), &self.config, total_score, best_hash));
+            }
+            self.is_ahead(&peers[from], &self.chain_tip()) || peers[from].is_idle()
+        };
+        if has_work {
+            self.set_timer_idle(false);
+        }
+    }
+
+    fn on_get_status(&self, from: &NodeId) {
+        if !self.requests.read().contains_key(from) {
+            cinfo!(SYNC, "Status request from invalid peer #{} received", from);
+            return
+        }
+        ctrace!(SYNC, "Peer #{} asked for our status", from);
+        self.send_status(from);
+    }
+
+    /// Headers of the unknown blocks are requested, whose bodies are downloaded once imported
+    fn on_new_block_hashes(&self, from: &NodeId, mut hashes: Vec<H256>) {
+        if !self.header_downloaders.read().contains_key(from) {
+            cinfo!(SYNC, "Block hashes from invalid peer #{} received", from);
+            return
+        }
+        if self.config.observer {
+            return
+        }
+        hashes.retain(|hash| self.client.block_header(BlockId::Hash(*hash)).is_none());
+        hashes.truncate(MAX_HEADERS_BY_HASHES_LENGTH);
+        if hashes.is_empty() {
+            return
+        }
+        ctrace!(SYNC, "Requesting {} announced headers from peer #{}", hashes.len(), from);
+        if let Some(requests) = self.requests.write().get_mut(from) {
+            let request_id = self.last_request.fetch_add(1, Ordering::Relaxed) as u64;
+            let request = RequestMessage::HeadersByHashes(hashes);
+            requests.push((request_id, request.clone()));
+            self.record_request(from, request_id);
+            self.send_message(from, Message::Request(request_id, request));
+        }
+    }
+
+    fn fork_id(&self) -> ForkId {
+        let chain_info = self.client.chain_info();
+        ForkId::new(chain_info.genesis_hash, &self.config.fork_blocks, chain_info.best_block_number)
+    }
+
+    /// Peers with a different genesis are left to the status handling
+    fn is_compatible_fork(&self, id: &NodeId, genesis_hash: H256, fork_id: Option<ForkId>) -> bool {
+        let chain_info = self.client.chain_info();
+        if genesis_hash != chain_info.genesis_hash {
+            return true
+        }
+        let is_compatible = match fork_id {
+            Some(remote) => self.fork_id().is_compatible(
+                &remote,
+                chain_info.genesis_hash,
+                &self.config.fork_blocks,
+                chain_info.best_block_number,
+            ),
+            None => !self.config.require_fork_id,
+        };
+        if !is_compatible {
+            cinfo!(SYNC, "Fork id mismatch with peer {}: {:?}", id, fork_id);
+        }
+        is_compatible
+    }
+
+    /// The peer may be reconfiguring, so it can be given one more chance if configured
+    fn on_genesis_mismatch(&self, id: &NodeId) {
+        let is_retried = {
+            let mut genesis_mismatches = self.genesis_mismatches.lock();
+            match (genesis_mismatches.get(id).cloned(), self.config.genesis_mismatch_retry) {
+                (Some(Some(_)), _) => return,
+                (None, Some(delay)) => {
+                    genesis_mismatches.insert(*id, Some(Instant::now() + delay));
+                    false
+                }
+                _ => {
+                    genesis_mismatches.remove(id);
+                    true
+                }
+            }
+        };
+        if is_retried {
+            cinfo!(SYNC, "Peer #{} with a different genesis is dropped", id);
+            self.remove_peer(id, StallReason::PeersRemoved);
+        } else {
+            cdebug!(SYNC, "Peer #{} with a different genesis will be probed again", id);
+        }
+    }
+
+    /// Peers which never send a valid status would hold their slots forever
+    fn drop_silent_peers(&self) {
+        let timeout = match self.config.handshake_timeout {
+            Some(timeout) => timeout,
+            None => return,
+        };
+        let silent_peers: Vec<_> = self
+            .pending_handshakes
+            .lock()
+            .iter()
+            .filter(|(_, connected_at)| connected_at.elapsed() >= timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in silent_peers {
+            cinfo!(SYNC, "Peer #{} hasn't sent a valid status in {} seconds", id, timeout.as_secs());
+            self.remove_peer(&id, StallReason::PeersRemoved);
+        }
+    }
+
+    fn probe_genesis_mismatches(&self) {
+        let now = Instant::now();
+        let peer_ids: Vec<_> = {
+            let mut genesis_mismatches = self.genesis_mismatches.lock();
+            let due: Vec<_> = genesis_mismatches
+                .iter()
+                .filter(|(_, probe_at)| probe_at.map_or(false, |probe_at| probe_at <= now))
+                .map(|(id, _)| *id)
+                .collect();
+            for id in &due {
+                genesis_mismatches.insert(*id, None);
+            }
+            due
+        };
+        for id in peer_ids {
+            cdebug!(SYNC, "Probing peer #{} with a different genesis again", id);
+            self.send_status(&id);
+        }
+    }
+
+    fn on_peer_message(&self, id: &NodeId, data: &[u8]) {
+        if let Ok(received_message) = UntrustedRlp::new(data).as_val::<Message>() {
+            if self.is_traced(id) {
+                self.trace(format!("Receive message from traced peer #{}: {:?}", id, received_message));
+            }
+            if let Message::Response(..) = received_message {
+                if let Some(traffic) = self.peer_traffic.lock().get_mut(id) {
+                    traffic.served += data.len();
+                }
+            }
+            self.dispatch_message(id, received_message);
+        } else {
+            cinfo!(SYNC, "Invalid message from peer {}", id);
+            self.penalize(id, INVALID_MESSAGE_PENALTY);
+        }
+    }
+
+    fn dispatch_message(&self, id: &NodeId, message: Message) {
+        match message {
+            // The framed messages are counted once they're dispatched
+            Message::Batch(..)
+            | Message::Chunked {
+                ..
+            } => {}
+            _ => self.received_messages.lock().record(message.type_name(), Instant::now()),
+        }
+        match message {
+            Message::Status {
+                total_score,
+                best_hash,
+                genesis_hash,
+                network_id,
+                features,
+                fork_id,
+            } => {
+                if self.is_compatible_fork(id, genesis_hash, fork_id) {
+                    self.on_peer_status(id, total_score, best_hash, genesis_hash, network_id, features)
+                }
+            }
+            Message::GetStatus => self.on_get_status(id),
+            Message::NewBlockHashes(hashes) => self.on_new_block_hashes(id, hashes),
+            Message::UnavailableBodies {
+                transient,
+                permanent,
+            } => self.on_unavailable_bodies(id, transient, permanent),
+            Message::Request(request_id, request) => self.on_peer_request(id, request_id, request),
+            Message::Response(request_id, response) => self.on_peer_response(id, request_id, response),
+            Message::Chunked {
+                id: chunk_id,
+                seq,
+                total,
+                data,
+            } => self.on_chunk(id, chunk_id, seq, total, data),
+            // Batches are never nested, which is checked while decoding
+            Message::Batch(messages) => {
+                for message in messages {
+                    self.dispatch_message(id, message);
+                }
+            }
+        }
+    }
+
+    fn on_chunk(&self, from: &NodeId, id: u64, seq: u64, total: u64, data: Vec<u8>) {
+        // Chunks except the last one are full, so the message would be larger than allowed
+        let is_too_large = total.saturating_sub(1).saturating_mul(self.config.chunk_bytes as u64)
+            >= self.config.max_message_bytes as u64;
+        if seq >= total || total > MAX_CHUNKS || is_too_large {
+            cinfo!(SYNC, "Invalid chunk received from peer #{}", from);
+            self.penalize(from, INVALID_MESSAGE_PENALTY);
+            return
+        }
+
+        let assembled = self.chunk_assembler.lock().insert(*from, id, seq, total, data, Instant::now());
+        match assembled {
+            Ok(Some(bytes)) => {
+                // Reassembled messages are held to the same limit as the others
+                if bytes.len() > self.config.max_message_bytes {
+                    cinfo!(SYNC, "Chunked message of {} bytes from peer #{} is too large", bytes.len(), from);
+                    self.penalize(from, INVALID_MESSAGE_PENALTY);
+                    return
+                }
+                self.on_peer_message(from, &bytes);
+            }
+            Ok(None) => {}
+            Err(err) => {
+                cinfo!(SYNC, "Chunk from peer #{} is rejected: {:?}", from, err);
+                self.penalize(from, INVALID_MESSAGE_PENALTY);
+            }
+        }
+    }
+
+    fn on_peer_request(&self, from: &NodeId, id: u64, request: RequestMessage) {
+        if !self.header_downloaders.read().contains_key(from) {
+            cinfo!(SYNC, "Request from invalid peer #{} received", from);
+            return
+        }
+        if self.config.observer && !self.config.observer_serves_requests {
+            ctrace!(SYNC, "Request from peer #{} is ignored in observer mode", from);
+            return
+        }
+
+        if !self.is_valid_request(&request) {
+            cinfo!(SYNC, "Invalid request received from peer #{}", from);
+            self.penalize(from, INVALID_MESSAGE_PENALTY);
+            return
+        }
+
+        let response = match request {
+            RequestMessage::Headers {
+                start_number,
+                max_count,
+                skip,
+                reverse,
+            } => self.create_headers_response(
+                start_number,
+                max_count,
+                skip,
+                reverse,
+                self.config.max_header_response_bytes,
+            ),
+            RequestMessage::Bodies(hashes) => {
+                let response = self.create_bodies_response(hashes.clone(), self.body_response_bytes(from));
+                // The requester must know the missing bodies before it checks the response
+                if let ResponseMessage::Bodies(bodies) = &response {
+                    self.send_unavailable_bodies(from, &hashes[..bodies.len()], bodies);
+                }
+                response
+            }
+            RequestMessage::StateHead(hash) => self.create_state_head_response(hash),
+            RequestMessage::StateChunk {
+                block_hash,
+                tree_root,
+            } => self.create_state_chunk_response(block_hash, tree_root),
+            RequestMessage::HeadersByHashes(hashes) => {
+                self.create_headers_by_hashes_response(hashes, self.config.max_header_response_bytes)
+            }
+            RequestMessage::ScoreProof(best_hash) => {
+                self.create_score_proof_response(best_hash, self.config.max_header_response_bytes)
+            }
+        };
+
+        self.send_response(from, id, response);
+    }
+
+    fn is_valid_request(&self, request: &RequestMessage) -> bool {
+        match request {
+            RequestMessage::Headers {
+                ..
+            } => true,
+            RequestMessage::Bodies(hashes) => !hashes.is_empty(),
+            RequestMessage::StateHead(hash) => match self.client.block_number(BlockId::Hash(*hash)) {
+                Some(number) if number % SNAPSHOT_PERIOD == 0 => true,
+                _ => false,
+            },
+            RequestMessage::StateChunk {
+                block_hash,
+                ..
+            } => {
+                let _is_checkpoint = match self.client.block_number(BlockId::Hash(*block_hash)) {
+                    Some(number) if number % SNAPSHOT_PERIOD == 0 => true,
+                    _ => false,
+                };
+                // FIXME:  check tree_root
+                unimplemented!()
+            }
+            RequestMessage::HeadersByHashes(hashes) => !hashes.is_empty(),
+            RequestMessage::ScoreProof(..) => true,
+        }
+    }
+
+    fn create_headers_response(
+        &self,
+        start_number: BlockNumber,
+        max_count: u64,
+        skip: u64,
+        reverse: bool,
+        max_bytes: usize,
+    ) -> ResponseMessage {
+        let step = skip.saturating_add(1);
+        let mut headers = Vec::new();
+        let mut total_bytes = 0;
+        let mut next_number = Some(start_number);
+        while let Some(number) = next_number {
+            if headers.len() as u64 >= max_count {
+                break
+            }
+            // Numbers out of range are clamped by stopping there
+            next_number = if reverse {
+                number.checked_sub(step)
+            } else {
+                number.checked_add(step)
+            };
+
+            let header = match self.canonical_header(number) {
+                Some(header) => header,
+                None => break,
+            };
+            let size = header.rlp().as_raw().len();
+            // At least one header is served to make progress
+            if !headers.is_empty() && total_bytes + size > max_bytes {
+                break
+            }
+            total_bytes += size;
+            headers.push(header.decode());
+        }
+        ResponseMessage::Headers(headers)
+    }
+
+    /// Hashes beyond the limit are ignored, and unknown ones are skipped
+    fn create_headers_by_hashes_response(&self, mut hashes: Vec<H256>, max_bytes: usize) -> ResponseMessage {
+        hashes.truncate(MAX_HEADERS_BY_HASHES_LENGTH);
+        let mut headers = Vec::new();
+        let mut total_bytes = 0;
+        for hash in hashes {
+            let header = match self.client.block_header(BlockId::Hash(hash)) {
+                Some(header) => header,
+                None => continue,
+            };
+            let size = header.rlp().as_raw().len();
+            // At least one header is served to make progress
+            if !headers.is_empty() && total_bytes + size > max_bytes {
+                break
+            }
+            total_bytes += size;
+            headers.push(header.decode());
+        }
+        ResponseMessage::HeadersByHashes(headers)
+    }
+
+    /// Headers up to the best block, from the genesis if the chain is short enough
+    fn create_score_proof_response(&self, best_hash: H256, max_bytes: usize) -> ResponseMessage {
+        let mut headers = Vec::new();
+        let mut total_bytes = 0;
+        let mut next_hash = Some(best_hash);
+        while let Some(hash) = next_hash {
+            if headers.len() >= MAX_SCORE_PROOF_LENGTH {
+                break
+            }
+            let header = match self.client.block_header(BlockId::Hash(hash)) {
+                Some(header) => header,
+                None => break,
+            };
+            let size = header.rlp().as_raw().len();
+            // At least the best header is served, which the requester may know the parent of
+            if !headers.is_empty() && total_bytes + size > max_bytes {
+                break
+            }
+            total_bytes += size;
+            let header = header.decode();
+            next_hash = if header.number() == 0 {
+                None
+            } else {
+                Some(*header.parent_hash())
+            };
+            headers.push(header);
+        }
+        headers.reverse();
+        ResponseMessage::ScoreProof(headers)
+    }
+
+    /// Header of our chain at the number, which is cached for serving recent headers requests
+    fn canonical_header(&self, number: BlockNumber) -> Option<EncodedHeader> {
+        let mut header_cache = self.header_cache.lock();
+        if let Some(header) = header_cache.get_mut(&number) {
+            return Some(header.clone())
+        }
+        let header = self.client.block_header(BlockId::Number(number))?;
+        header_cache.insert(number, header.clone());
+        Some(header)
+    }
+
+    /// Peers with higher reputation are served with larger bodies responses
+    fn body_response_bytes(&self, id: &NodeId) -> usize {
+        let reputation = self.reputations.read().get(id).cloned().unwrap_or(0);
+        let min_bytes = self.config.min_body_response_bytes;
+        let max_bytes = ::std::cmp::max(self.config.max_body_response_bytes, min_bytes);
+        if reputation <= 0 {
+            return min_bytes
+        }
+        let reputation = ::std::cmp::min(reputation, MAX_REPUTATION) as usize;
+        min_bytes + (max_bytes - min_bytes) / MAX_REPUTATION as usize * reputation
+    }
+
+    fn create_bodies_response(&self, hashes: Vec<H256>, max_bytes: usize) -> ResponseMessage {
+        let mut bodies = Vec::new();
+        let mut total_bytes = 0;
+        // The rest of the hashes will be requested again
+        for hash in hashes.into_iter().take(self.config.max_body_request_length) {
+            let (size, parcels) = match self.block_body(&hash) {
+                Some(body) => (body.rlp().as_raw().len(), body.parcels()),
+                None => (0, Vec::new()),
+            };
+            // At least one body is served to make progress
+            if !bodies.is_empty() && total_bytes + size > max_bytes {
+                break
+            }
+            total_bytes += size;
+            bodies.push(parcels);
+        }
+        ResponseMessage::Bodies(bodies)
+    }
+
+    fn send_unavailable_bodies(&self, id: &NodeId, hashes: &[H256], bodies: &[Vec<UnverifiedParcel>]) {
+        let pruning_floor = self.client.pruning_floor();
+        let mut transient = Vec::new();
+        let mut permanent = Vec::new();
+        for (hash, body) in hashes.iter().zip(bodies) {
+            if !body.is_empty() || self.block_body(hash).is_some() {
+                continue
+            }
+            let number = self.client.block_header(BlockId::Hash(*hash)).map(|header| header.number());
+            let is_pruned = match (number, pruning_floor) {
+                (Some(number), Some(floor)) => number < floor,
+                _ => false,
+            };
+            if is_pruned {
+                permanent.push(*hash);
+            } else {
+                transient.push(*hash);
+            }
+        }
+        if transient.is_empty() && permanent.is_empty() {
+            return
+        }
+        cdebug!(SYNC, "Bodies unavailable for peer #{}: {:?} later, {:?} never", id, transient, permanent);
+        self.send_message(
+            id,
+            Message::UnavailableBodies {
+                transient,
+                permanent,
+            },
+        );
+    }
+
+    fn on_unavailable_bodies(&self, from: &NodeId, transient: Vec<H256>, permanent: Vec<H256>) {
+        if !self.header_downloaders.read().contains_key(from) {
+            return
+        }
+        self.pruned_bodies.lock().entry(*from).or_insert_with(HashSet::new).extend(&permanent);
+        let mut unavailable_bodies = self.unavailable_bodies.lock();
+        let unavailable = unavailable_bodies.entry(*from).or_insert_with(HashSet::new);
+        unavailable.extend(transient);
+        unavailable.extend(permanent);
+    }
+
+    fn create_state_head_response(&self, _hash: H256) -> ResponseMessage {
+        unimplemented!()
+    }
+
+    fn create_state_chunk_response(&self, _hash: H256, _tree_root: H256) -> ResponseMessage {
+        unimplemented!()
+    }
+
+    fn on_peer_response(&self, from: &NodeId, id: u64, mut response: ResponseMessage) {
+        let last_request = match self.requests.read().get(from) {
+            Some(requests) => requests.iter().find(|(i, _)| *i == id).cloned(),
+            None => return,
+        };
+        if let Some((_, request)) = last_request {
+            self.record_response(from, id);
+            let mut is_penalized = false;
+            match &mut response {
+                ResponseMessage::Headers(headers) => {
+                    headers.sort_unstable_by_key(|h| h.number());
+                    // Headers of a skeleton don't follow one another
+                    let is_skeleton = match &request {
+                        RequestMessage::Headers {
+                            skip,
+                            ..
+                        } => *skip > 0,
+                        _ => false,
+                    };
+                    if !is_skeleton {
+                        if let Err((index, err)) = verify_headers_batch(headers, self.config.verification_level) {
+                            cinfo!(SYNC, "Invalid header at {} received from peer #{}: {}", index, from, err);
+                            self.penalize(from, INVALID_MESSAGE_PENALTY);
+                            if index == 0 {
+                                return
+                            }
+                            // Headers before the invalid one can still be imported
+                            headers.truncate(index);
+                            is_penalized = true;
+                        }
+                    }
+                }
+                _ => {}
+            }
+
+            if !self.is_valid_response(&request, &response) {
+                cinfo!(SYNC, "Invalid response received from peer #{}", from);
+                self.penalize(from, INVALID_MESSAGE_PENALTY);
+                return
+            }
+            if !is_penalized {
+                self.reward(from, VALID_RESPONSE_REWARD);
+            }
+
+            match response {
+                ResponseMessage::Headers(headers) => {
+                    let is_segment = {
+                        let mut segment_requests = self.segment_requests.lock();
+                        let is_segment = segment_requests.get(from) == Some(&id);
+                        if is_segment {
+                            segment_requests.remove(from);
+                        }
+                        is_segment
+                    };
+                    self.dismiss_request(from, id);
+                    if is_segment {
+                        self.on_segment_response(from, headers)
+                    } else {
+                        self.on_header_response(from, headers)
+                    }
+                }
+                ResponseMessage::Bodies(bodies) => {
+                    let hashes = match request {
+                        RequestMessage::Bodies(hashes) => hashes,
+                        _ => unreachable!(),
+                    };
+                    debug_assert!(bodies.len() <= hashes.len());
+                    if let Some(token) = self.tokens.read().get(from) {
+                        if let Some(token_info) = self.tokens_info.write().get_mut(token) {
+                            if token_info.request_id.is_none() {
+                                ctrace!(SYNC, "Expired before handling response");
+                                return
+                            }
+                            let api = self.api.read();
+                            api.as_ref().expect("Api must exist").clear_timer(*token).expect("Timer clear succeed");
+                            token_info.request_id = None;
+                        }
+                    }
+                    self.dismiss_request(from, id);
+                    self.on_body_response(from, hashes, bodies);
+                }
+                ResponseMessage::HeadersByHashes(headers) => {
+                    let hashes = match request {
+                        RequestMessage::HeadersByHashes(hashes) => hashes,
+                        _ => unreachable!(),
+                    };
+                    self.dismiss_request(from, id);
+                    self.on_headers_by_hashes_response(from, hashes, headers);
+                }
+                ResponseMessage::ScoreProof(headers) => {
+                    self.dismiss_request(from, id);
+                    self.on_score_proof_response(from, headers);
+                }
+                _ => unimplemented!(),
+            }
+        } else if id < self.last_request.load(Ordering::Relaxed) as u64 {
+            // The request has expired or been cancelled, so the late response is just ignored
+            ctrace!(SYNC, "Response to the cancelled request {} received from peer #{}", id, from);
+        } else {
+            cinfo!(SYNC, "Response to the unknown request {} received from peer #{}", id, from);
+            self.penalize(from, UNKNOWN_RESPONSE_PENALTY);
+        }
+    }
+
+    fn is_valid_response(&self, request: &RequestMessage, response: &ResponseMessage) -> bool {
+        match (request, response) {
+            (
+                RequestMessage::Headers {
+                    start_number,
+                    ..
+                },
+                ResponseMessage::Headers(headers),
+            ) => {
+                // A peer sends no headers if it has nothing from the start
+                headers.is_empty() || headers.first().map(|header| header.number()) == Some(*start_number)
+            }
+            (RequestMessage::Bodies(hashes), ResponseMessage::Bodies(bodies)) => {
+                if hashes.len() < bodies.len() {
+                    return false
+                }
+                for body in bodies {
+                    for parcel in body {
+                        let is_valid = match &parcel.as_unsigned().action {
+                            Action::Custom(bytes) => self.client.custom_handlers().iter().any(|c| c.is_target(bytes)),
+                            _ => true,
+                        };
+                        if !is_valid {
+                            return false
+                        }
+                    }
+                }
+                true
+            }
+            (RequestMessage::HeadersByHashes(hashes), ResponseMessage::HeadersByHashes(headers)) => {
+                if hashes.len() < headers.len() {
+                    return false
+                }
+                let requested: HashSet<_> = hashes.iter().collect();
+                headers.iter().all(|header| requested.contains(&header.hash()))
+            }
+            (RequestMessage::ScoreProof(best_hash), ResponseMessage::ScoreProof(headers)) => {
+                headers.len() <= MAX_SCORE_PROOF_LENGTH
+                    && headers.last().map(|header| header.hash()) == Some(*best_hash)
+            }
+            (RequestMessage::StateHead(..), ResponseMessage::StateHead(..)) => unimplemented!(),
+            (
+                RequestMessage::StateChunk {
+                    ..
+                },
+                ResponseMessage::StateChunk(..),
+            ) => unimplemented!(),
+            _ => false,
+        }
+    }
+
+    fn on_header_response(&self, from: &NodeId, headers: Vec<Header>) {
+        if headers.is_empty() {
+            ctrace!(SYNC, "Peer #{} has no headers to send", from);
+            return
+        }
+        let (completed, is_overclaimed, is_disjoint) = if let Some(peer) = self.header_downloaders.write().get_mut(from)
+        {
+            let encoded = headers.iter().map(|h| EncodedHeader::new(h.rlp_bytes().to_vec())).collect();
+            peer.import_headers(encoded);
+            let is_overclaimed = peer.downloaded_best_score().map_or(false, |score| peer.total_score() > score);
+            (peer.downloaded(), is_overclaimed, peer.is_disjoint())
+        } else {
+            (Vec::new(), false, false)
+        };
+        if is_disjoint {
+            cinfo!(SYNC, "Peer #{} serving a chain without a common ancestor is dropped", from);
+            self.penalize(from, INVALID_MESSAGE_PENALTY);
+            self.remove_peer(from, StallReason::PeersRemoved);
+            return
+        }
+        if is_overclaimed {
+            cinfo!(SYNC, "Peer #{} claimed a total score higher than the score of its best block", from);
+            self.penalize(from, INVALID_MESSAGE_PENALTY);
+        }
+        self.import_downloaded_headers(from, from, completed);
+        self.request_headers(from);
+    }
+
+    /// The headers of a segment are imported by the downloader whose skeleton they fill
+    fn on_segment_response(&self, from: &NodeId, headers: Vec<Header>) {
+        let encoded: Vec<_> = headers.iter().map(|h| EncodedHeader::new(h.rlp_bytes().to_vec())).collect();
+        let filled = {
+            let mut filled = None;
+            for (owner, peer) in self.header_downloaders.write().iter_mut() {
+                match peer.import_segment(from, &encoded) {
+                    Some(true) => {
+                        filled = Some((*owner, peer.downloaded()));
+                        break
+                    }
+                    Some(false) => {
+                        cdebug!(SYNC, "Segment from peer #{} doesn't match the skeleton of peer #{}", from, owner);
+                        break
+                    }
+                    None => {}
+                }
+            }
+            filled
+        };
+        if let Some((owner, completed)) = filled {
+            self.import_downloaded_headers(from, &owner, completed);
+        }
+        self.request_headers(from);
+    }
+
+    /// Headers are verified as served by `from`, and marked as imported in the downloader of `owner`
+    fn import_downloaded_headers(&self, from: &NodeId, owner: &NodeId, mut completed: Vec<EncodedHeader>) {
+        completed.sort_unstable_by_key(|header| header.number());
+        if let Some(pool) = &self.seal_verifier_pool {
+            let decoded: Vec<_> = completed.iter().map(|header| header.decode()).collect();
+            if let Err((index, err)) = pool.verify(&decoded) {
+                cinfo!(SYNC, "Invalid seal of header({}) received from peer #{}: {}", decoded[index].hash(), from, err);
+                self.penalize(from, INVALID_MESSAGE_PENALTY);
+                // Headers before the invalid one are still imported in order
+                completed.truncate(index);
+            }
+        }
+
+        let mut exists = Vec::new();
+        let mut is_rejected = false;
+        for header in completed {
+            {
+                let mut rejected_blocks = self.rejected_blocks.lock();
+                if rejected_blocks.contains(&header.parent_hash()) {
+                    rejected_blocks.insert(header.hash());
+                    is_rejected = true;
+                    break
+                }
+            }
+            match self.client.import_header(header.clone().into_inner()) {
+                Err(BlockImportError::Import(ImportError::AlreadyInChain)) => exists.push(header.hash()),
+                // FIXME: handle import errors
+                Err(err) => {
+                    cwarn!(SYNC, "Cannot import header({}): {:?}", header.hash(), err);
+                    break
+                }
+                _ => {}
+            }
+        }
+
+        if let Some(peer) = self.header_downloaders.write().get_mut(owner) {
+            peer.mark_as_imported(exists);
+            if is_rejected {
+                cinfo!(SYNC, "Chain of peer #{} descends from a rejected block", owner);
+                peer.reject();
+            }
+        }
+    }
+
+    fn on_headers_by_hashes_response(&self, from: &NodeId, hashes: Vec<H256>, headers: Vec<Header>) {
+        // The best header requested by the downloader of the peer
+        let is_tip_response = match self.header_downloaders.write().get_mut(from) {
+            Some(peer) => {
+                let is_tip_response = peer.is_tip_requested() && hashes == vec![peer.best_hash()];
+                if is_tip_response {
+                    let tip = headers.iter().find(|header| header.hash() == hashes[0]);
+                    peer.import_tip(tip.map(|header| EncodedHeader::new(header.rlp_bytes().to_vec())));
+                }
+                is_tip_response
+            }
+            None => false,
+        };
+        if is_tip_response {
+            self.request_headers(from);
+            return
+        }
+        for header in headers {
+            match self.client.import_header(header.rlp_bytes().to_vec()) {
+                Err(BlockImportError::Import(ImportError::AlreadyInChain)) => {}
+                Err(err) => cdebug!(SYNC, "Cannot import header({}): {:?}", header.hash(), err),
+                _ => {}
+            }
+        }
+    }
+
+    fn on_score_proof_response(&self, from: &NodeId, mut headers: Vec<Header>) {
+        let claimed = match self.unproven_claims.lock().get(from) {
+            Some(claimed) => claimed.clone(),
+            None => return,
+        };
+        // A page ends at the parent of the pages received before
+        let (pages, received) = self.score_proofs.lock().remove(from).unwrap_or((0, Vec::new()));
+        let expected = received.first().map_or(claimed.best_hash, |header| *header.parent_hash());
+        if headers.last().map(|h| h.hash()) != Some(expected) {
+            if pages > 0 {
+                self.score_proofs.lock().insert(*from, (pages, received));
+            }
+            return
+        }
+        headers.extend(received);
+        let verifier = self.config.seal_verifier.as_ref();
+        match verify_score_proof(&*self.client, verifier, &headers, claimed.total_score) {
+            ScoreProof::Forged => {
+                cinfo!(SYNC, "Peer #{} sent a forged proof of its total score", from);
+                self.unproven_claims.lock().remove(from);
+                self.penalize(from, INVALID_MESSAGE_PENALTY);
+                return
+            }
+            ScoreProof::Unreachable if pages + 1 < MAX_SCORE_PROOF_PAGES => {
+                let parent_hash = *headers[0].parent_hash();
+                cdebug!(SYNC, "Proof of peer #{} doesn't reach our chain yet, requesting the headers before it", from);
+                self.score_proofs.lock().insert(*from, (pages + 1, headers));
+                self.send_score_proof_request(from, parent_hash);
+                return
+            }
+            // The claim is applied unproven, the headers are still verified when they are imported
+            ScoreProof::Unreachable | ScoreProof::Inconclusive => {
+                cdebug!(SYNC, "Proof of peer #{} can't be verified, downloading its headers unproven", from)
+            }
+            ScoreProof::Valid => {}
+        }
+        self.unproven_claims.lock().remove(from);
+        if let Some(peer) = self.header_downloaders.write().get_mut(from) {
+            peer.update(claimed.total_score, claimed.best_hash);
+        }
+        self.set_timer_idle(false);
+    }
+
+    fn on_body_response(&self, from: &NodeId, hashes: Vec<H256>, bodies: Vec<Vec<UnverifiedParcel>>) {
+        // Bodies announced as unavailable are requested again instead of being taken as mismatched
+        let (hashes, bodies) = match self.unavailable_bodies.lock().remove(from) {
+            Some(unavailable) => {
+                let unanswered = hashes[bodies.len()..].to_vec();
+                let (missing, served): (Vec<_>, Vec<_>) =
+                    hashes.into_iter().zip(bodies).partition(|(hash, _)| unavailable.contains(hash));
+                let missing: Vec<_> = missing.into_iter().map(|(hash, _)| hash).collect();
+                self.body_downloader.lock().reset_downloading(&missing);
+                let (mut hashes, bodies): (Vec<_>, Vec<_>) = served.into_iter().unzip();
+                // Hashes without bodies are left for the downloader to request again
+                hashes.extend(unanswered);
+                (hashes, bodies)
+            }
+            None => (hashes, bodies),
+        };
+        let served = hashes[..bodies.len()].to_vec();
+        let invalid = self.body_downloader.lock().import_bodies(hashes, bodies);
+        {
+            let mut body_contributors = self.body_contributors.lock();
+            for hash in served.into_iter().filter(|hash| !invalid.contains(hash)) {
+                body_contributors.entry(hash).or_insert(*from);
+            }
+        }
+        if !invalid.is_empty() {
+            cinfo!(SYNC, "Duplicated or mismatched bodies received from peer #{}: {:?}", from, invalid);
+            self.penalize(from, INVALID_MESSAGE_PENALTY);
+        }
+        self.import_downloaded_bodies();
+
+        let ours = self.chain_tip();
+        let peer_ids: Vec<_> = self.header_downloaders.read().keys().cloned().collect();
+        let peer_ids = self.weighted_shuffle(peer_ids);
+
+        let mut body_peer_ids = Vec::new();
+        for id in peer_ids {
+            if self.is_on_losing_fork(&id) {
+                continue
+            }
+
+            if self.is_peer_chain_chosen(&id, &ours) {
+                body_peer_ids.push(id);
+            }
+        }
+        self.send_body_requests(body_peer_ids);
+    }
+
+    /// Blocks are deferred while the parent of the first one is neither imported nor queued,
+    /// since the client would reject them as orphans
+    fn import_downloaded_bodies(&self) {
+        let mut imported = Vec::new();
+        {
+            let mut body_downloader = self.body_downloader.lock();
+            let is_parent_available = body_downloader.first_parent().map_or(true, |parent| {
+                match self.client.block_status(BlockId::Hash(parent)) {
+                    BlockStatus::InChain | BlockStatus::Queued => true,
+                    _ => false,
+                }
+            });
+            if !is_parent_available {
+                cdebug!(SYNC, "Downloaded bodies are deferred until their parent is imported");
+                return
+            }
+            let pruning_floor = self.client.pruning_floor();
+            let mut last_block: Option<Header> = None;
+            let mut is_consistent = true;
+            // Bodies are taken one by one, so the ones after a block which isn't imported stay as targets
+            while let Some((hash, parcels)) = body_downloader.pop_downloaded() {
+                let header = match self.client.block_header(BlockId::Hash(hash)) {
+                    Some(header) => header.decode(),
+                    None => {
+                        is_consistent = false;
+                        cerror!(SYNC, "Header of downloaded body({}) doesn't exist", hash);
+                        break
+                    }
+                };
+                let block = Block {
+                    header,
+                    parcels,
+                };
+                cdebug!(SYNC, "Body download completed for #{}({})", block.header.number(), hash);
+                let is_in_order = last_block.as_ref().map_or(true, |parent| is_child_block(parent, &block.header));
+                debug_assert!(is_in_order, "Block #{}({}) is imported out of order", block.header.number(), hash);
+                if !is_in_order {
+                    is_consistent = false;
+                    cerror!(SYNC, "Block #{}({}) is imported out of order", block.header.number(), hash);
+                    break
+                }
+                last_block = Some(block.header.clone());
+
+                if !self.is_acceptable_block(&block.header) {
+                    cinfo!(SYNC, "Block #{}({}) is rejected by the block filter", block.header.number(), hash);
+                    self.reject_branch(&mut body_downloader, hash);
+                    break
+                }
+                // The state of its ancestors may have been pruned, so the import would fail
+                if pruning_floor.map_or(false, |floor| block.header.number() < floor) {
+                    cinfo!(SYNC, "Block #{}({}) is below the pruning floor, skipping", block.header.number(), hash);
+                    self.reject_branch(&mut body_downloader, hash);
+                    break
+                }
+                match self.client.import_block(block.rlp_bytes(Seal::With)) {
+                    Err(BlockImportError::Import(ImportError::AlreadyInChain)) => {
+                        cwarn!(SYNC, "Downloaded already existing block({})", hash)
+                    }
+                    Err(err) => {
+                        cwarn!(SYNC, "Cannot import block({}): {:?}, downloading it again", hash, err);
+                        let parent = self.client.block_header(BlockId::Hash(*block.header.parent_hash()));
+                        if let Some(parent) = parent {
+                            body_downloader.retry_first(&block.header, &parent.decode());
+                        }
+                        break
+                    }
+                    _ => {
+                        // Peers may request the body before the queue imports it
+                        let mut body = RlpStream::new_list(1);
+                        body.append_list(&block.parcels);
+                        self.pending_blocks.lock().insert(hash, EncodedBody::new(body.out()));
+                        imported.push(hash);
+                        self.import_meter.lock().record(1, Instant::now());
+                        let parent_score = self.client.block_total_score(BlockId::Hash(*block.header.parent_hash()));
+                        if let Some(parent_score) = parent_score {
+                            self.update_imported_tip(ChainTip {
+                                total_score: parent_score + *block.header.score(),
+                                best_hash: hash,
+                            });
+                        }
+                    }
+                }
+            }
+            if !is_consistent {
+                cerror!(SYNC, "Body downloader is inconsistent with the chain, resetting it to the best block");
+                self.reset_body_downloader(&mut body_downloader);
+            }
+        }
+        if !imported.is_empty() {
+            self.reconcile_imported_tip();
+        }
+        self.notify_imported(&imported);
+    }
+}
+
+fn is_child_block(parent: &Header, child: &Header) -> bool {
+    child.number() == parent.number() + 1 && *child.parent_hash() == parent.hash()
+}
+
+fn estimated_response_bytes(request: &RequestMessage) -> usize {
+    match request {
+        RequestMessage::Headers {
+            max_count,
+            ..
+        } => *max_count as usize * ESTIMATED_HEADER_BYTES,
+        RequestMessage::Bodies(hashes) => hashes.len() * ESTIMATED_BODY_BYTES,
+        RequestMessage::HeadersByHashes(hashes) => hashes.len() * ESTIMATED_HEADER_BYTES,
+        RequestMessage::ScoreProof(..) => MAX_SCORE_PROOF_LENGTH * ESTIMATED_HEADER_BYTES,
+        // State sync is not implemented yet
+        RequestMessage::StateHead(..)
+        | RequestMessage::StateChunk {
+            ..
+        } => 0,
+    }
+}
+
+fn estimate_time_to_sync(remaining_blocks: u64, imported_blocks: usize, window: StdDuration) -> Option<StdDuration> {
+    if imported_blocks == 0 {
+        return None
+    }
+    Some(StdDuration::from_secs(remaining_blocks * window.as_secs() / imported_blocks as u64))
+}
+
+/// Checks that every header follows the previous one, and returns the index of the first invalid header.
+/// Timestamps are only checked on the fully verified range. Seals are verified later by the client, which has the engine.
+fn verify_headers_batch(headers: &[Header], level: VerificationLevel) -> Result<(), (usize, BlockError)> {
+    for (index, neighbors) in headers.windows(2).enumerate() {
+        let parent = &neighbors[0];
+        let child = &neighbors[1];
+        if child.number() != parent.number() + 1 {
+            return Err((
+                index + 1,
+                BlockError::InvalidNumber(Mismatch {
+                    expected: parent.number() + 1,
+                    found: child.number(),
+                }),
+            ))
+        }
+        if *child.parent_hash() != parent.hash() {
+            return Err((
+                index + 1,
+                BlockError::InvalidParentHash(Mismatch {
+                    expected: parent.hash(),
+                    found: *child.parent_hash(),
+                }),
+            ))
+        }
+        if level.is_full(child.number()) && child.timestamp() <= parent.timestamp() {
+            return Err((
+                index + 1,
+                BlockError::InvalidTimestamp(OutOfBounds {
+                    max: None,
+                    min: Some(parent.timestamp() + 1),
+                    found: child.timestamp(),
+                }),
+            ))
+        }
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum ScoreProof {
+    Valid,
+    /// There's no verifier to check the proof
+    Inconclusive,
+    /// The proof doesn't reach a block we know
+    Unreachable,
+    Forged,
+}
+
+/// A proof is a chain of headers which starts from the genesis or a child of a block we know.
+/// The seal and the score of every header except our genesis are verified.
+fn verify_score_proof(
+    client: &BlockChainClient,
+    verifier: Option<&Arc<SealVerifier>>,
+    headers: &[Header],
+    claimed_score: U256,
+) -> ScoreProof {
+    let verifier = match verifier {
+        Some(verifier) => verifier,
+        None => return ScoreProof::Inconclusive,
+    };
+    let first = match headers.first() {
+        Some(header) => header,
+        None => return ScoreProof::Forged,
+    };
+    if headers.windows(2).any(|neighbors| !is_child_block(&neighbors[0], &neighbors[1])) {
+        return ScoreProof::Forged
+    }
+    let (mut total_score, mut parent) = if first.number() == 0 {
+        if first.hash() != client.chain_info().genesis_hash {
+            return ScoreProof::Forged
+        }
+        (U256::zero(), None)
+    } else {
+        let parent_hash = *first.parent_hash();
+        match (client.block_total_score(BlockId::Hash(parent_hash)), client.block_header(BlockId::Hash(parent_hash))) {
+            (Some(score), Some(parent)) => (score, Some(parent.decode())),
+            _ => return ScoreProof::Unreachable,
+        }
+    };
+    for header in headers {
+        if let Some(parent) = parent {
+            let result = verifier.verify_seal(header).and_then(|_| verifier.verify_score(header, &parent));
+            if let Err(err) = result {
+                cdebug!(SYNC, "Invalid header({}) in a score proof: {}", header.hash(), err);
+                return ScoreProof::Forged
+            }
+        }
+        let (sum, is_overflowed) = total_score.overflowing_add(*header.score());
+        if is_overflowed {
+            return ScoreProof::Forged
+        }
+        total_score = sum;
+        parent = Some(header.clone());
+    }
+    if total_score == claimed_score {
+        ScoreProof::Valid
+    } else {
+        ScoreProof::Forged
+    }
+}
+
+fn peer_weight(reputation: i64, throughput: u64) -> u64 {
+    let reputation = ::std::cmp::max(reputation + MAX_REPUTATION, 0) as u64 + 1;
+    reputation.saturating_mul(throughput + 1)
+}
+
+/// Picks items one by one with probabilities proportional to their weights, which must be positive
+fn weighted_shuffle<R: Rng, T>(rng: &mut R, mut items: Vec<T>, mut weights: Vec<u64>) -> Vec<T> {
+    debug_assert_eq!(items.len(), weights.len());
+    let mut result = Vec::with_capacity(items.len());
+    while !items.is_empty() {
+        let total = weights.iter().fold(0u64, |acc, weight| acc.saturating_add(*weight));
+        let mut target = rng.gen_range(0, total);
+        let index = weights
+            .iter()
+            .position(|weight| {
+                if target < *weight {
+                    return true
+                }
+                target -= *weight;
+                false
+            })
+            .expect("Target is less than the total weight");
+        result.push(items.swap_remove(index));
+        weights.swap_remove(index);
+    }
+    result
+}
+
+/// Moves the reputation toward zero by `rate`
+fn decay_reputation(reputation: i64, rate: i64) -> i64 {
+    if reputation < 0 {
+        ::std::cmp::min(reputation + rate, 0)
+    } else {
+        ::std::cmp::max(reputation - rate, 0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::{Duration as StdDuration, Instant};
+
+    use ccore::{
+        BlockChainClient, BlockError, BlockId, BlockInfo, ChainInfo, ChainNotify, Header, ImportBlock,
+        TestBlockChainClient,
+    };
+    use ckey::NetworkId;
+    use cnetwork::{NetworkExtension, NodeId, SocketAddr, TestNetworkCall, TestNetworkClient};
+    use ctypes::util::unexpected::Mismatch;
+    use parking_lot::Mutex;
+    use primitives::{H256, U256};
+    use rand::thread_rng;
+    use rlp::{Encodable, RlpStream, UntrustedRlp};
+    use time::Duration;
+
+    use super::super::config::{Config, VerificationLevel};
+    use super::super::fork_choice::{ChainTip, ForkChoice};
+    use super::super::fork_id::ForkId;
+    use super::super::message::{Message, RequestMessage, ResponseMessage};
+    use super::super::seal::SealVerifier;
+    use super::{
+        estimate_time_to_sync, is_child_block, peer_weight, verify_headers_batch, verify_score_proof, weighted_shuffle,
+        Extension, ScoreProof, StallReason, SyncPhase, SyncStatus, ANNOUNCED_BLOCK_LIFETIME_SECS, BATCH_FLUSH_TOKEN,
+        IMPORTED_TIP_LIFETIME_SECS, INVALID_MESSAGE_PENALTY, MAX_HEADERS_BY_HASHES_LENGTH, MAX_HEADER_BODY_RATIO,
+        MAX_REPUTATION, MAX_SCORE_PROOF_LENGTH, STATUS_BROADCAST_TOKEN, SYNC_TIMER_INTERVAL, SYNC_TIMER_TOKEN,
+    };
+
+    fn node_id(port: u16) -> NodeId {
+        SocketAddr::v4(127, 0, 0, 1, port).into()
+    }
+
+    fn chain(length: u64) -> Vec<Header> {
+        let mut headers: Vec<Header> = Vec::new();
+        for number in 0..length {
+            let mut header = Header::default();
+            header.set_number(number);
+            header.set_timestamp(number);
+            if let Some(parent) = headers.last() {
+                header.set_parent_hash(parent.hash());
+            }
+            headers.push(header);
+        }
+        headers
+    }
+
+    fn sent_messages(network: &TestNetworkClient, to: &NodeId) -> usize {
+        let mut count = 0;
+        while let Some(call) = network.pop_call("block-propagation") {
+            if let TestNetworkCall::Send(id, _) = call {
+                if id == *to {
+                    count += 1;
+                }
+            }
+        }
+        count
+    }
+
+    #[test]
+    fn trace_only_targeted_peer() {
+        let extension = Extension::new(Arc::new(TestBlockChainClient::new()), Config::default());
+        let traced = node_id(3485);
+        let other = node_id(3486);
+        assert!(!extension.is_traced(&traced));
+
+        extension.trace_peer(traced);
+        assert!(extension.is_traced(&traced));
+        assert!(!extension.is_traced(&other));
+
+        let lines = Arc::new(Mutex::new(Vec::new()));
+        let traced_lines = lines.clone();
+        extension.set_trace_hook(Box::new(move |line: &str| traced_lines.lock().push(line.to_string())));
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        for peer in &[traced, other] {
+            extension.on_node_added(peer, 0);
+            extension.on_message(peer, &Message::GetStatus.rlp_bytes());
+        }
+        let lines = lines.lock();
+        // The status sent on connection, the received request, and the status sent in response
+        assert_eq!(3, lines.len());
+        assert!(lines[0].starts_with("Send message to traced peer"));
+        assert!(lines[1].starts_with("Receive message from traced peer"));
+        assert!(lines.iter().all(|line| line.contains(&format!("#{}", traced))));
+        assert!(!lines.iter().any(|line| line.contains(&format!("#{}", other))));
+    }
+
+    #[test]
+    fn status_is_sent_once_per_session() {
+        let extension = Extension::new(Arc::new(TestBlockChainClient::new()), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.on_node_added(&peer, 0);
+        assert_eq!(1, sent_messages(&network, &peer));
+
+        extension.on_node_removed(&peer);
+        extension.on_node_added(&peer, 0);
+        assert_eq!(1, sent_messages(&network, &peer));
+    }
+
+    #[test]
+    fn initialize_twice_resets_timer_and_peers() {
+        let extension = Extension::new(Arc::new(TestBlockChainClient::new()), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        extension.on_node_added(&node_id(3485), 0);
+
+        let mut restarted = TestNetworkClient::new();
+        restarted.register_extension(extension.clone());
+
+        let mut calls = Vec::new();
+        while let Some(call) = network.pop_call("block-propagation") {
+            calls.push(call);
+        }
+        assert_eq!(Some(&TestNetworkCall::ClearTimer(SYNC_TIMER_TOKEN)), calls.last());
+        assert_eq!(
+            Some(TestNetworkCall::SetTimer {
+                token: SYNC_TIMER_TOKEN,
+                duration: Duration::milliseconds(SYNC_TIMER_INTERVAL),
+            }),
+            restarted.pop_call("block-propagation")
+        );
+        assert_eq!(None, restarted.pop_call("block-propagation"));
+        assert!(extension.requests.read().is_empty());
+        assert!(extension.tokens.read().is_empty());
+        assert!(extension.tokens_info.read().is_empty());
+    }
+
+    #[test]
+    fn nodes_beyond_target_peers_are_ignored() {
+        let nodes: Vec<_> = (3485..3489).map(node_id).collect();
+        let config = Config {
+            target_peers: Some(3),
+            ..Config::default()
+        };
+        for (config, expected) in vec![(Config::default(), 4), (config, 3)] {
+            let extension = Extension::new(Arc::new(TestBlockChainClient::new()), config);
+            let mut network = TestNetworkClient::new();
+            network.register_extension(extension.clone());
+            for node in &nodes {
+                extension.on_node_added(node, 0);
+            }
+            assert_eq!(expected, extension.requests.read().len());
+            assert_eq!(expected == 4, extension.requests.read().contains_key(&nodes[3]));
+
+            extension.on_node_removed(&nodes[3]);
+            assert_eq!(3, extension.requests.read().len());
+        }
+    }
+
+    #[test]
+    fn replaying_recorded_messages_reaches_same_state() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let config = Config {
+            record_messages: true,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (peer, misbehaving_peer) = (node_id(3485), node_id(3486));
+        extension.on_node_added(&peer, 0);
+        extension.on_node_added(&misbehaving_peer, 0);
+        let chain_info = client.chain_info();
+        let status = Message::Status {
+            total_score: chain_info.total_score + U256::from(10),
+            best_hash: H256::random(),
+            genesis_hash: chain_info.genesis_hash,
+            network_id: Some(NetworkId::default()),
+            features: Vec::new(),
+            fork_id: None,
+        };
+        extension.on_message(&peer, &status.rlp_bytes());
+        extension.on_message(&misbehaving_peer, &[0xff]);
+        let recorded = extension.recorded_messages();
+        assert_eq!(2, recorded.len());
+
+        let replayed = Extension::new(client.clone(), Config::default());
+        let mut replayed_network = TestNetworkClient::new();
+        replayed_network.register_extension(replayed.clone());
+        replayed.replay(&recorded);
+
+        assert_eq!(extension.peers_ahead(), replayed.peers_ahead());
+        assert_eq!(*extension.reputations.read(), *replayed.reputations.read());
+        assert!(replayed.recorded_messages().is_empty());
+    }
+
+    #[test]
+    fn no_peers_status_is_reported_after_window() {
+        let config = Config {
+            no_peers_window: StdDuration::from_secs(0),
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        assert_eq!(SyncStatus::Synced, extension.status());
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(SyncStatus::NoPeers, extension.status());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(10),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(SyncStatus::Syncing, extension.status());
+    }
+
+    #[test]
+    fn peers_ahead_are_dropped_after_stall_window() {
+        let config = Config {
+            stall_window: Some(StdDuration::from_secs(0)),
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (ahead, behind) = (node_id(3485), node_id(3486));
+        let chain_info = client.chain_info();
+        for (peer, total_score) in
+            vec![(ahead, chain_info.total_score + U256::from(10)), (behind, chain_info.total_score)]
+        {
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                total_score,
+                H256::random(),
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        assert_eq!(1, extension.peers_ahead().len());
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert!(extension.peers_ahead().is_empty());
+        assert_eq!(SyncStatus::Stalled(StallReason::NoProgress), extension.status());
+        assert!(!extension.requests.read().contains_key(&ahead));
+        assert!(extension.requests.read().contains_key(&behind));
+    }
+
+    #[test]
+    fn genesis_only_peer_is_served_but_not_requested() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        let genesis_score = client.block_total_score(BlockId::Number(0)).unwrap();
+        extension.on_peer_status(
+            &peer,
+            genesis_score,
+            chain_info.genesis_hash,
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        while network.pop_call("block-propagation").is_some() {}
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(0, sent_messages(&network, &peer));
+
+        let request = Message::Request(
+            1,
+            RequestMessage::Headers {
+                start_number: 0,
+                max_count: 10,
+                skip: 0,
+                reverse: false,
+            },
+        );
+        extension.on_message(&peer, &request.rlp_bytes());
+        assert_eq!(1, sent_messages(&network, &peer));
+    }
+
+    fn announced_status(network: &TestNetworkClient, to: &NodeId) -> Option<(U256, H256)> {
+        let mut announced = None;
+        while let Some(call) = network.pop_call("block-propagation") {
+            if let TestNetworkCall::Send(id, bytes) = call {
+                if let Ok(Message::Status {
+                    total_score,
+                    best_hash,
+                    ..
+                }) = UntrustedRlp::new(&bytes).as_val()
+                {
+                    if id == *to {
+                        announced = Some((total_score, best_hash));
+                    }
+                }
+            }
+        }
+        announced
+    }
+
+    #[test]
+    fn best_block_is_announced_to_peer_behind() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        assert_eq!(Some((chain_info.total_score, chain_info.best_block_hash)), announced_status(&network, &peer));
+
+        let genesis_score = client.block_total_score(BlockId::Number(0)).unwrap();
+        extension.on_peer_status(
+            &peer,
+            genesis_score,
+            chain_info.genesis_hash,
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        client.add_blocks(1, 0);
+        let imported = client.chain_info();
+        extension.new_blocks(vec![imported.best_block_hash], vec![], vec![], vec![], vec![], 0);
+        assert_eq!(Some((imported.total_score, imported.best_block_hash)), announced_status(&network, &peer));
+    }
+
+    struct LowerHashAtEqualScore;
+
+    impl ForkChoice for LowerHashAtEqualScore {
+        fn is_better(&self, peer: &ChainTip, ours: &ChainTip) -> bool {
+            peer.total_score > ours.total_score
+                || (peer.total_score == ours.total_score && peer.best_hash < ours.best_hash)
+        }
+    }
+
+    #[test]
+    fn custom_fork_choice_decides_peers_ahead() {
+        let config = Config {
+            fork_choice: Arc::new(LowerHashAtEqualScore),
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (lower, higher) = (node_id(3485), node_id(3486));
+        let chain_info = client.chain_info();
+        for (peer, best_hash) in vec![(lower, H256::zero()), (higher, H256::from([0xff; 32]))] {
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                chain_info.total_score,
+                best_hash,
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        while network.pop_call("block-propagation").is_some() {}
+
+        assert_eq!(vec![(lower, chain_info.total_score)], extension.peers_ahead());
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(1, extension.requests.read()[&lower].len());
+        assert!(extension.requests.read()[&higher].is_empty());
+    }
+
+    #[test]
+    fn recent_import_is_used_while_chain_info_lags() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        let peer_score = chain_info.total_score + U256::from(10);
+        extension.on_peer_status(
+            &peer,
+            peer_score,
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        assert_eq!(1, extension.peers_ahead().len());
+
+        // The chain info of the client still reports the genesis
+        let imported = ChainTip {
+            total_score: peer_score,
+            best_hash: H256::random(),
+        };
+        extension.update_imported_tip(imported.clone());
+        assert_eq!(imported, extension.chain_tip());
+        assert!(extension.peers_ahead().is_empty());
+
+        *extension.imported_tip.lock() =
+            Some((imported, Instant::now() - StdDuration::from_secs(IMPORTED_TIP_LIFETIME_SECS)));
+        assert_eq!(chain_info.best_block_hash, extension.chain_tip().best_hash);
+        assert_eq!(1, extension.peers_ahead().len());
+    }
+
+    #[test]
+    fn oversized_message_is_dropped() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let chain_info = client.chain_info();
+        let status = Message::Status {
+            total_score: chain_info.total_score + U256::from(10),
+            best_hash: H256::random(),
+            genesis_hash: chain_info.genesis_hash,
+            network_id: Some(NetworkId::default()),
+            features: Vec::new(),
+            fork_id: None,
+        }
+        .rlp_bytes();
+        let config = Config {
+            max_message_bytes: status.len() - 1,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.on_message(&peer, &status);
+        assert!(extension.peers_ahead().is_empty());
+        assert!(extension.reputations.read()[&peer] < 0);
+    }
+
+    #[test]
+    fn headers_and_bodies_are_requested_from_same_peer_at_once() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+        let headers: Vec<_> =
+            (0..11).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        for header in &headers[1..6] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        for neighbors in headers[..6].windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let remote_info = remote.chain_info();
+        extension.on_peer_status(
+            &peer,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        let requests = extension.requests.read()[&peer].clone();
+        assert_eq!(2, requests.len());
+        for (request_id, request) in requests {
+            let response = match request {
+                RequestMessage::Headers {
+                    start_number,
+                    ..
+                } => ResponseMessage::Headers(headers[start_number as usize..].to_vec()),
+                RequestMessage::Bodies(hashes) => ResponseMessage::Bodies(vec![Vec::new(); hashes.len()]),
+                _ => panic!("Unexpected request: {:?}", request),
+            };
+            extension.on_message(&peer, &Message::Response(request_id, response).rlp_bytes());
+        }
+        assert!(extension.requests.read()[&peer].is_empty());
+        assert_eq!(2, extension.reputations.read()[&peer]);
+        assert!(client.block_body(BlockId::Hash(headers[5].hash())).is_some());
+    }
+
+    #[test]
+    fn requests_expire_after_timeout_of_their_type() {
+        let config = Config {
+            header_request_timeout: StdDuration::from_secs(0),
+            ..Config::default()
+        };
+        let body_request_timeout = Duration::from_std(config.body_request_timeout).unwrap();
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let headers = chain(3);
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let genesis_hash = client.chain_info().genesis_hash;
+        extension.on_peer_status(
+            &peer,
+            U256::max_value(),
+            H256::random(),
+            genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        let mut body_timer = None;
+        while let Some(call) = network.pop_call("block-propagation") {
+            if let TestNetworkCall::SetTimerOnce {
+                token,
+                duration,
+            } = call
+            {
+                assert_eq!(body_request_timeout, duration);
+                body_timer = Some(token);
+            }
+        }
+        let body_timer = body_timer.expect("Bodies must be requested");
+
+        // The headers request expires immediately while the bodies request is still waiting
+        let last_request_id = extension.last_request.load(Ordering::Relaxed) as u64;
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert!(extension.requests.read()[&peer].iter().any(|(id, _)| *id == last_request_id));
+        let count_requests = |is_headers: bool| {
+            extension.requests.read()[&peer]
+                .iter()
+                .filter(|(_, request)| match request {
+                    RequestMessage::Headers {
+                        ..
+                    } => is_headers,
+                    _ => !is_headers,
+                })
+                .count()
+        };
+        assert_eq!(1, count_requests(true));
+        assert_eq!(1, count_requests(false));
+        assert_eq!(None, extension.body_downloader.lock().create_request());
+
+        extension.on_timeout(body_timer);
+        assert_eq!(0, count_requests(false));
+        assert!(extension.body_downloader.lock().create_request().is_some());
+    }
+
+    #[test]
+    fn feature_adoption_is_computed_over_peers() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        let adoption = |feature: u32| (extension.feature_adoption(feature) * 100.0).round() as u32;
+        assert_eq!(0, adoption(1));
+
+        let chain_info = client.chain_info();
+        let features = vec![vec![1, 2], vec![1], vec![2], Vec::new()];
+        for (port, features) in (3485..).zip(features) {
+            let peer = node_id(port);
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                chain_info.total_score,
+                chain_info.best_block_hash,
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                features,
+            );
+        }
+        assert_eq!(50, adoption(1));
+        assert_eq!(0, adoption(3));
+
+        extension.on_node_removed(&node_id(3488));
+        assert_eq!(67, adoption(2));
+    }
+
+    #[test]
+    fn interrupted_body_download_is_resumed_by_another_peer() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+        let headers: Vec<_> =
+            (0..11).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        for header in &headers[1..] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+
+        let (interrupted, resuming) = (node_id(3485), node_id(3486));
+        extension.on_node_added(&interrupted, 0);
+        extension.on_node_added(&resuming, 0);
+        let remote_info = remote.chain_info();
+        extension.on_peer_status(
+            &interrupted,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+
+        extension.send_body_request(&interrupted);
+        let (request_id, _) = extension.requests.read()[&interrupted][0].clone();
+        let response = ResponseMessage::Bodies(vec![Vec::new(); 4]);
+        extension.on_message(&interrupted, &Message::Response(request_id, response).rlp_bytes());
+        // The remaining bodies are requested again from the same peer, but it drops before responding
+        assert_eq!(1, extension.requests.read()[&interrupted].len());
+        extension.on_node_removed(&interrupted);
+
+        extension.send_body_request(&resuming);
+        let remaining = headers[5..].iter().map(|header| header.hash()).collect();
+        assert_eq!(RequestMessage::Bodies(remaining), extension.requests.read()[&resuming][0].1);
+    }
+
+    #[test]
+    fn fewer_peers_are_requested_under_high_load() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let config = Config {
+            load_probe: Box::new(|| 0.75),
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let headers = chain(11);
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let genesis_hash = client.chain_info().genesis_hash;
+        let peers: Vec<_> = (3485..3489).map(node_id).collect();
+        for peer in &peers {
+            extension.on_node_added(peer, 0);
+            extension.on_peer_status(
+                peer,
+                U256::max_value(),
+                H256::random(),
+                genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let requested = peers.iter().filter(|peer| !extension.requests.read()[*peer].is_empty()).count();
+        assert_eq!(1, requested);
+    }
+
+    #[test]
+    fn null_best_hash_is_rejected_unless_genesis_score() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (peer, genesis_peer) = (node_id(3485), node_id(3486));
+        let chain_info = client.chain_info();
+        extension.on_node_added(&peer, 0);
+        extension.on_node_added(&genesis_peer, 0);
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(10),
+            H256::zero(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        assert!(!extension.header_downloaders.read().contains_key(&peer));
+        assert!(extension.reputations.read()[&peer] < 0);
+
+        let genesis_score = client.block_total_score(BlockId::Number(0)).unwrap();
+        extension.on_peer_status(
+            &genesis_peer,
+            genesis_score,
+            H256::zero(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        assert_eq!(chain_info.genesis_hash, extension.header_downloaders.read()[&genesis_peer].best_hash());
+    }
+
+    #[test]
+    fn served_headers_are_cached_until_new_blocks() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let served = || match extension.create_headers_response(0, 5, 0, false, usize::max_value()) {
+            ResponseMessage::Headers(headers) => headers.len(),
+            _ => unreachable!(),
+        };
+        assert_eq!(5, served());
+
+        // Headers by number can't be found in the client anymore
+        client.numbers.write().clear();
+        assert_eq!(5, served());
+
+        extension.new_blocks(vec![], vec![], vec![], vec![], vec![], 0);
+        assert_eq!(0, served());
+    }
+
+    #[test]
+    fn best_block_is_announced_again_once_if_peer_does_not_advance() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        let genesis_score = client.block_total_score(BlockId::Number(0)).unwrap();
+        let status = Message::Status {
+            total_score: genesis_score,
+            best_hash: chain_info.genesis_hash,
+            genesis_hash: chain_info.genesis_hash,
+            network_id: Some(NetworkId::default()),
+            features: Vec::new(),
+            fork_id: None,
+        }
+        .rlp_bytes();
+        extension.on_message(&peer, &status);
+        extension.new_blocks(vec![chain_info.best_block_hash], vec![], vec![], vec![], vec![], 0);
+        assert_eq!(Some((chain_info.total_score, chain_info.best_block_hash)), announced_status(&network, &peer));
+
+        extension.on_message(&peer, &status);
+        assert_eq!(Some((chain_info.total_score, chain_info.best_block_hash)), announced_status(&network, &peer));
+        extension.on_message(&peer, &status);
+        assert_eq!(None, announced_status(&network, &peer));
+    }
+
+    #[test]
+    fn reputable_peer_with_highest_score_is_selected_as_pivot() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        assert_eq!(None, extension.pivot_peer());
+
+        let (ahead, penalized, far_ahead) = (node_id(3485), node_id(3486), node_id(3487));
+        let chain_info = client.chain_info();
+        for (peer, score) in vec![(ahead, 10), (penalized, 30), (far_ahead, 20)] {
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                chain_info.total_score + U256::from(score),
+                H256::random(),
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        extension.penalize(&penalized, 1);
+        assert_eq!(Some(far_ahead), extension.pivot_peer());
+
+        extension.on_node_removed(&far_ahead);
+        assert_eq!(Some(ahead), extension.pivot_peer());
+    }
+
+    #[test]
+    fn pinned_pivot_peer_is_selected_while_connected() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let (pinned, other) = (node_id(3485), node_id(3486));
+        let config = Config {
+            pivot_peer: Some(pinned),
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let chain_info = client.chain_info();
+        for (peer, score) in vec![(pinned, 10), (other, 20)] {
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                chain_info.total_score + U256::from(score),
+                H256::random(),
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        assert_eq!(Some(pinned), extension.pivot_peer());
+
+        extension.on_node_removed(&pinned);
+        assert_eq!(Some(other), extension.pivot_peer());
+    }
+
+    #[test]
+    fn headers_are_served_with_skip_and_direction() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+
+        let numbers = |start_number: u64, max_count: u64, skip: u64, reverse: bool| match extension
+            .create_headers_response(start_number, max_count, skip, reverse, usize::max_value())
+        {
+            ResponseMessage::Headers(headers) => headers.iter().map(|header| header.number()).collect::<Vec<_>>(),
+            _ => unreachable!(),
+        };
+        assert_eq!(vec![1, 4, 7, 10], numbers(1, 10, 2, false));
+        assert_eq!(vec![5, 4, 3, 2, 1, 0], numbers(5, 10, 0, true));
+        assert_eq!(vec![9, 6, 3, 0], numbers(9, 10, 2, true));
+        assert_eq!(vec![8, 5], numbers(8, 2, 2, true));
+        assert_eq!(vec![10], numbers(10, 10, u64::max_value(), false));
+    }
+
+    #[test]
+    fn headers_are_served_by_hashes() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+
+        let hash = |number: u64| client.block_hash(BlockId::Number(number)).unwrap();
+        let numbers = |hashes: Vec<H256>| match extension.create_headers_by_hashes_response(hashes, usize::max_value())
+        {
+            ResponseMessage::HeadersByHashes(headers) => {
+                headers.iter().map(|header| header.number()).collect::<Vec<_>>()
+            }
+            _ => unreachable!(),
+        };
+        assert_eq!(vec![7, 2, 5], numbers(vec![hash(7), hash(2), H256::random(), hash(5)]));
+        assert_eq!(Vec::<u64>::new(), numbers(vec![H256::random()]));
+        assert_eq!(MAX_HEADERS_BY_HASHES_LENGTH, numbers(vec![hash(3); MAX_HEADERS_BY_HASHES_LENGTH + 1]).len());
+
+        let request = RequestMessage::HeadersByHashes(vec![hash(7), hash(2)]);
+        assert!(extension.is_valid_request(&request));
+        assert!(!extension.is_valid_request(&RequestMessage::HeadersByHashes(Vec::new())));
+        let served = |numbers: &[u64]| {
+            let headers = numbers.iter().map(|number| client.block_header(BlockId::Number(*number)).unwrap().decode());
+            ResponseMessage::HeadersByHashes(headers.collect())
+        };
+        assert!(extension.is_valid_response(&request, &served(&[2])));
+        assert!(!extension.is_valid_response(&request, &served(&[2, 3])));
+        assert!(!extension.is_valid_response(&request, &served(&[7, 2, 7])));
+    }
+
+    #[test]
+    fn peer_with_higher_quality_is_chosen_more_often() {
+        assert!(peer_weight(10, 1000) > peer_weight(0, 1000));
+        assert!(peer_weight(0, 2000) > peer_weight(0, 1000));
+        assert!(peer_weight(-1000, 0) > 0);
+
+        let (poor, good) = (node_id(3485), node_id(3486));
+        let weights = vec![peer_weight(-90, 100), peer_weight(50, 500)];
+        let mut rng = thread_rng();
+        let chosen_first =
+            (0..1000).filter(|_| weighted_shuffle(&mut rng, vec![poor, good], weights.clone())[0] == good).count();
+        assert!(chosen_first > 900, "Good peer is chosen first {} times", chosen_first);
+    }
+
+    #[test]
+    fn headers_are_downloaded_again_from_new_best_after_reorg() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let genesis_hash = client.chain_info().genesis_hash;
+        extension.on_peer_status(
+            &peer,
+            U256::max_value(),
+            H256::random(),
+            genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let header_requests = || -> Vec<_> {
+            extension.requests.read()[&peer]
+                .iter()
+                .filter_map(|(_, request)| match request {
+                    RequestMessage::Headers {
+                        start_number,
+                        ..
+                    } => Some(*start_number),
+                    _ => None,
+                })
+                .collect()
+        };
+        assert_eq!(vec![5], header_requests());
+
+        let retracted = client.block_hash(BlockId::Number(5)).unwrap();
+        client.add_blocks(3, 0);
+        let best_hash = client.chain_info().best_block_hash;
+        extension.new_blocks(vec![best_hash], vec![], vec![best_hash], vec![retracted], vec![], 0);
+        assert!(header_requests().is_empty());
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(vec![8], header_requests());
+    }
+
+    #[test]
+    fn subscriber_receives_imported_blocks_in_order() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(3, 0);
+        let headers: Vec<_> =
+            (0..4).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        for header in &headers[1..] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        let receiver = extension.subscribe_imported();
+        drop(extension.subscribe_imported());
+
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let hashes: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+        extension.body_downloader.lock().create_request();
+        extension.on_body_response(&node_id(3485), hashes.clone(), vec![Vec::new(); 3]);
+
+        assert_eq!(hashes, receiver.try_iter().collect::<Vec<_>>());
+        assert_eq!(1, extension.import_subscribers.lock().len());
+    }
+
+    #[test]
+    fn slow_peer_is_deprioritized_but_still_requested() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (slow, fast) = (node_id(3485), node_id(3486));
+        extension.on_node_added(&slow, 0);
+        extension.on_node_added(&fast, 0);
+        extension.reputations.write().insert(slow, MAX_REPUTATION);
+        extension.peer_latencies.lock().insert(slow, Config::default().slow_peer_latency * 2);
+        for _ in 0..10 {
+            assert_eq!(vec![fast, slow], extension.weighted_shuffle(vec![slow, fast]));
+        }
+        assert_eq!(MAX_REPUTATION, extension.reputations.read()[&slow]);
+
+        extension.on_node_removed(&fast);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &slow,
+            chain_info.total_score + U256::from(10),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        while network.pop_call("block-propagation").is_some() {}
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(1, sent_messages(&network, &slow));
+    }
+
+    #[test]
+    fn imported_tip_is_reconciled_with_client_best() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        let remote = TestBlockChainClient::new_with_extra_data(vec![1]);
+        remote.add_blocks(3, 0);
+        let headers: Vec<_> =
+            (0..4).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        for header in &headers[1..] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let hashes: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+        extension.body_downloader.lock().create_request();
+        extension.on_body_response(&node_id(3485), hashes, vec![Vec::new(); 3]);
+
+        // The imported blocks are on a fork with less score than the best block of the client
+        let chain_info = client.chain_info();
+        assert!(client.block_body(BlockId::Hash(headers[3].hash())).is_some());
+        assert!(extension.imported_tip.lock().is_none());
+        assert_eq!(chain_info.best_block_hash, extension.chain_tip().best_hash);
+        assert_eq!(chain_info.total_score, extension.chain_tip().total_score);
+    }
+
+    #[test]
+    fn requests_to_peer_are_logged_with_response_status() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(10),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        assert!(extension.peer_request_log(&peer).is_empty());
+
+        let headers = chain(2);
+        extension.body_downloader.lock().add_target(&headers[1], &headers[0]);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let requests = extension.requests.read()[&peer].clone();
+        assert_eq!(2, requests.len());
+
+        let answered = requests
+            .iter()
+            .find(|(_, request)| match request {
+                RequestMessage::Bodies(..) => true,
+                _ => false,
+            })
+            .map(|(request_id, _)| *request_id)
+            .unwrap();
+        let response = ResponseMessage::Bodies(Vec::new());
+        extension.on_message(&peer, &Message::Response(answered, response).rlp_bytes());
+
+        let log = extension.peer_request_log(&peer);
+        let logged: Vec<_> = log.iter().map(|entry| (entry.request_id, entry.answered_at.is_some())).collect();
+        let expected: Vec<_> = requests.iter().map(|(request_id, _)| (*request_id, *request_id == answered)).collect();
+        assert_eq!(expected, logged);
+    }
+
+    #[test]
+    fn timer_backs_off_at_tip_until_peer_ahead_appears() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        let status = |total_score: U256| {
+            extension.on_peer_status(
+                &peer,
+                total_score,
+                H256::random(),
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            )
+        };
+        status(chain_info.total_score);
+        while network.pop_call("block-propagation").is_some() {}
+
+        let timer_calls = |network: &TestNetworkClient| {
+            let mut calls = Vec::new();
+            while let Some(call) = network.pop_call("block-propagation") {
+                calls.push(call);
+            }
+            calls
+        };
+        let idle_interval = Duration::from_std(Config::default().idle_timer_interval).unwrap();
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(
+            vec![
+                TestNetworkCall::ClearTimer(SYNC_TIMER_TOKEN),
+                TestNetworkCall::SetTimer {
+                    token: SYNC_TIMER_TOKEN,
+                    duration: idle_interval,
+                },
+            ],
+            timer_calls(&network)
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert!(timer_calls(&network).is_empty());
+
+        status(chain_info.total_score + U256::from(10));
+        assert_eq!(
+            vec![
+                TestNetworkCall::ClearTimer(SYNC_TIMER_TOKEN),
+                TestNetworkCall::SetTimer {
+                    token: SYNC_TIMER_TOKEN,
+                    duration: Duration::milliseconds(SYNC_TIMER_INTERVAL),
+                },
+            ],
+            timer_calls(&network)
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(1, sent_messages(&network, &peer));
+    }
+
+    #[test]
+    fn response_to_cancelled_request_is_ignored() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let headers = chain(2);
+        extension.body_downloader.lock().add_target(&headers[1], &headers[0]);
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(10),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let (request_id, _) = extension.requests.read()[&peer]
+            .iter()
+            .find(|(_, request)| *request == RequestMessage::Bodies(vec![headers[1].hash()]))
+            .cloned()
+            .unwrap();
+        let token = extension.tokens.read()[&peer];
+        extension.on_timeout(token);
+        let remaining = extension.requests.read()[&peer].clone();
+
+        let response = Message::Response(request_id, ResponseMessage::Bodies(vec![Vec::new()]));
+        extension.on_message(&peer, &response.rlp_bytes());
+        assert_eq!(0, extension.reputations.read()[&peer]);
+        assert_eq!(remaining, extension.requests.read()[&peer]);
+        assert!(extension.body_downloader.lock().create_request().is_some());
+
+        let unknown_id = extension.last_request.load(Ordering::Relaxed) as u64;
+        let response = Message::Response(unknown_id, ResponseMessage::Bodies(vec![Vec::new()]));
+        extension.on_message(&peer, &response.rlp_bytes());
+        assert!(extension.reputations.read()[&peer] < 0);
+    }
+
+    #[test]
+    fn announced_block_is_served_from_cache() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 1);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score,
+            chain_info.best_block_hash,
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.new_blocks(vec![chain_info.best_block_hash], vec![], vec![], vec![], vec![], 0);
+
+        // The announced block can't be found in the client anymore
+        client.numbers.write().clear();
+        client.blocks.write().remove(&chain_info.best_block_hash);
+        let headers = match extension.create_headers_response(10, 1, 0, false, usize::max_value()) {
+            ResponseMessage::Headers(headers) => headers,
+            _ => unreachable!(),
+        };
+        assert_eq!(vec![chain_info.best_block_hash], headers.iter().map(|header| header.hash()).collect::<Vec<_>>());
+        let parcels = || match extension.create_bodies_response(vec![chain_info.best_block_hash], 1024) {
+            ResponseMessage::Bodies(bodies) => bodies[0].len(),
+            _ => unreachable!(),
+        };
+        assert_eq!(1, parcels());
+
+        let lifetime = StdDuration::from_secs(ANNOUNCED_BLOCK_LIFETIME_SECS);
+        for (_, announced_at) in extension.announced_blocks.lock().values_mut() {
+            *announced_at = Instant::now() - lifetime;
+        }
+        assert_eq!(0, parcels());
+    }
+
+    #[test]
+    fn removing_all_peers_ahead_is_reported_as_stalled() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let chain_info = client.chain_info();
+        let peer_score = chain_info.total_score + U256::from(10);
+        let add_peer = |id: &NodeId| {
+            extension.on_node_added(id, 0);
+            extension.on_peer_status(
+                id,
+                peer_score,
+                H256::random(),
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        };
+        let (first, second) = (node_id(3485), node_id(3486));
+        add_peer(&first);
+        add_peer(&second);
+        extension.on_node_removed(&first);
+        assert_eq!(SyncStatus::Syncing, extension.status());
+        extension.on_node_removed(&second);
+        assert_eq!(SyncStatus::Stalled(StallReason::PeersRemoved), extension.status());
+
+        add_peer(&first);
+        assert_eq!(SyncStatus::Syncing, extension.status());
+        extension.on_node_removed(&first);
+        assert_eq!(SyncStatus::Stalled(StallReason::PeersRemoved), extension.status());
+
+        extension.update_imported_tip(ChainTip {
+            total_score: peer_score,
+            best_hash: H256::random(),
+        });
+        assert_eq!(SyncStatus::Synced, extension.status());
+    }
+
+    /// Accepts every seal, and expects the score of each block to be its number as in the test client
+    struct IncreasingScoreVerifier;
+
+    impl SealVerifier for IncreasingScoreVerifier {
+        fn verify_seal(&self, _header: &Header) -> Result<(), BlockError> {
+            Ok(())
+        }
+
+        fn verify_score(&self, header: &Header, parent: &Header) -> Result<(), BlockError> {
+            let expected = U256::from(parent.number() + 1);
+            if *header.score() != expected {
+                return Err(BlockError::InvalidScore(Mismatch {
+                    expected,
+                    found: *header.score(),
+                }))
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn score_proof_is_verified_against_our_chain() {
+        let local = TestBlockChainClient::new();
+        local.add_blocks(5, 0);
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+        let claimed_score = remote.chain_info().total_score;
+        let headers: Vec<_> =
+            (6..11).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        let verifier: Arc<SealVerifier> = Arc::new(IncreasingScoreVerifier);
+        let verifier = Some(&verifier);
+
+        assert_eq!(ScoreProof::Valid, verify_score_proof(&local, verifier, &headers, claimed_score));
+        assert_eq!(ScoreProof::Forged, verify_score_proof(&local, verifier, &headers, claimed_score + U256::from(1)));
+        let mut broken = headers.clone();
+        broken.remove(2);
+        assert_eq!(ScoreProof::Forged, verify_score_proof(&local, verifier, &broken, claimed_score));
+        assert_eq!(ScoreProof::Forged, verify_score_proof(&local, verifier, &[], claimed_score));
+        assert_eq!(
+            ScoreProof::Unreachable,
+            verify_score_proof(&TestBlockChainClient::new(), verifier, &headers, claimed_score)
+        );
+        assert_eq!(ScoreProof::Inconclusive, verify_score_proof(&local, None, &headers, claimed_score));
+
+        // A child of our best block can't claim any score it wants
+        let best = local.best_block_header().decode();
+        let mut fabricated = Header::new();
+        fabricated.set_parent_hash(best.hash());
+        fabricated.set_number(best.number() + 1);
+        fabricated.set_score(U256::from(1_000_000));
+        let claimed_score = local.chain_info().total_score + U256::from(1_000_000);
+        assert_eq!(ScoreProof::Forged, verify_score_proof(&local, verifier, &[fabricated], claimed_score));
+    }
+
+    #[test]
+    fn peer_is_ahead_only_after_proving_its_score() {
+        let remote = Arc::new(TestBlockChainClient::new());
+        remote.add_blocks(10, 0);
+        let remote_extension = Extension::new(remote.clone(), Config::default());
+        let remote_info = remote.chain_info();
+        let proof = match remote_extension.create_score_proof_response(remote_info.best_block_hash, usize::max_value())
+        {
+            ResponseMessage::ScoreProof(headers) => headers,
+            _ => unreachable!(),
+        };
+        assert_eq!(11, proof.len());
+        match remote_extension.create_score_proof_response(remote_info.best_block_hash, 0) {
+            ResponseMessage::ScoreProof(headers) => assert_eq!(1, headers.len()),
+            _ => unreachable!(),
+        }
+
+        let config = Config {
+            verify_score_proof: true,
+            seal_verifier: Some(Arc::new(IncreasingScoreVerifier) as Arc<SealVerifier>),
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (honest, forger) = (node_id(3485), node_id(3486));
+        for (peer, total_score) in
+            vec![(honest, remote_info.total_score), (forger, remote_info.total_score + U256::from(1))]
+        {
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                total_score,
+                remote_info.best_block_hash,
+                remote_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        assert!(extension.peers_ahead().is_empty());
+
+        for peer in &[honest, forger] {
+            let (request_id, request) = extension.requests.read()[peer][0].clone();
+            assert_eq!(RequestMessage::ScoreProof(remote_info.best_block_hash), request);
+            let response = Message::Response(request_id, ResponseMessage::ScoreProof(proof.clone()));
+            extension.on_message(peer, &response.rlp_bytes());
+        }
+        assert_eq!(vec![(honest, remote_info.total_score)], extension.peers_ahead());
+        assert!(extension.reputations.read()[&forger] < 0);
+    }
+
+    #[test]
+    fn score_proof_of_peer_far_ahead_is_requested_in_pages() {
+        let remote = Arc::new(TestBlockChainClient::new());
+        remote.add_blocks(MAX_SCORE_PROOF_LENGTH + 100, 0);
+        let remote_extension = Extension::new(remote.clone(), Config::default());
+        let remote_info = remote.chain_info();
+
+        // Without a verifier, the claim is applied after the first page
+        for (seal_verifier, expected_pages) in
+            vec![(Some(Arc::new(IncreasingScoreVerifier) as Arc<SealVerifier>), 2), (None, 1)]
+        {
+            let config = Config {
+                verify_score_proof: true,
+                seal_verifier,
+                ..Config::default()
+            };
+            let client = Arc::new(TestBlockChainClient::new());
+            let extension = Extension::new(client.clone(), config);
+            let mut network = TestNetworkClient::new();
+            network.register_extension(extension.clone());
+
+            let peer = node_id(3485);
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                remote_info.total_score,
+                remote_info.best_block_hash,
+                remote_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+            let mut pages = 0;
+            while extension.peers_ahead().is_empty() {
+                assert!(pages < expected_pages);
+                let (request_id, hash) = extension.requests.read()[&peer]
+                    .iter()
+                    .filter_map(|&(id, ref request)| match *request {
+                        RequestMessage::ScoreProof(hash) => Some((id, hash)),
+                        _ => None,
+                    })
+                    .last()
+                    .unwrap();
+                let response = remote_extension.create_score_proof_response(hash, usize::max_value());
+                extension.on_message(&peer, &Message::Response(request_id, response).rlp_bytes());
+                pages += 1;
+            }
+            assert_eq!(expected_pages, pages);
+            assert_eq!(vec![(peer, remote_info.total_score)], extension.peers_ahead());
+        }
+    }
+
+    #[test]
+    fn preferred_peer_is_requested_before_others() {
+        let preferred = node_id(3485);
+        let config = Config {
+            preferred_peers: vec![preferred],
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let other = node_id(3486);
+        let chain_info = client.chain_info();
+        for (peer, score) in vec![(preferred, 10), (other, 100)] {
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                chain_info.total_score + U256::from(score),
+                H256::random(),
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        extension.reputations.write().insert(other, MAX_REPUTATION);
+        let headers = chain(2);
+        extension.body_downloader.lock().add_target(&headers[1], &headers[0]);
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let has_body_request = |peer: &NodeId| {
+            extension.requests.read()[peer].iter().any(|(_, request)| match request {
+                RequestMessage::Bodies(..) => true,
+                _ => false,
+            })
+        };
+        assert!(has_body_request(&preferred));
+        assert!(!has_body_request(&other));
+    }
+
+    #[test]
+    fn late_status_from_removed_peer_is_ignored() {
+        let config = Config {
+            record_messages: true,
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        let chain_info = client.chain_info();
+        let status = Message::Status {
+            total_score: chain_info.total_score + U256::from(10),
+            best_hash: H256::random(),
+            genesis_hash: chain_info.genesis_hash,
+            network_id: Some(NetworkId::default()),
+            features: Vec::new(),
+            fork_id: None,
+        };
+        extension.on_node_added(&peer, 0);
+        extension.on_message(&peer, &status.rlp_bytes());
+        assert_eq!(1, extension.peers_ahead().len());
+
+        extension.on_node_removed(&peer);
+        extension.on_message(&peer, &status.rlp_bytes());
+        assert!(extension.peers_ahead().is_empty());
+        assert!(!extension.header_downloaders.read().contains_key(&peer));
+        assert!(!extension.peer_features.read().contains_key(&peer));
+        assert_eq!(1, extension.recorded_messages().len());
+
+        // The peer is accepted again once it reconnects
+        extension.on_node_added(&peer, 0);
+        extension.on_message(&peer, &status.rlp_bytes());
+        assert_eq!(1, extension.peers_ahead().len());
+    }
+
+    #[test]
+    fn bodies_are_favored_while_headers_lead_heavily() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let headers = chain(1001);
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let metrics = extension.metrics();
+        assert_eq!((1000, 0), (metrics.pending_headers, metrics.pending_bodies));
+        assert!(metrics.header_body_ratio() > MAX_HEADER_BODY_RATIO);
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(10),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let requests = extension.requests.read()[&peer].clone();
+        assert_eq!(1, requests.len());
+        match requests[0].1 {
+            RequestMessage::Bodies(ref hashes) => assert_eq!(extension.metrics().pending_bodies, hashes.len()),
+            ref request => panic!("Unexpected request: {:?}", request),
+        }
+    }
+
+    struct CountingVerifier(AtomicUsize);
+
+    impl SealVerifier for CountingVerifier {
+        fn verify_seal(&self, _header: &Header) -> Result<(), BlockError> {
+            self.0.fetch_add(1, Ordering::SeqCst);
+            Ok(())
+        }
+
+        fn verify_score(&self, _header: &Header, _parent: &Header) -> Result<(), BlockError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn seals_are_verified_before_headers_are_imported_in_order() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+        let headers: Vec<_> =
+            (0..11).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+
+        let verifier = Arc::new(CountingVerifier(AtomicUsize::new(0)));
+        let config = Config {
+            seal_verifier: Some(verifier.clone() as Arc<SealVerifier>),
+            seal_verification_parallelism: 3,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        client.add_notify(extension.clone());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let remote_info = remote.chain_info();
+        extension.on_peer_status(
+            &peer,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let (request_id, _) = extension.requests.read()[&peer][0].clone();
+        extension
+            .on_message(&peer, &Message::Response(request_id, ResponseMessage::Headers(headers.clone())).rlp_bytes());
+
+        assert_eq!(10, verifier.0.load(Ordering::SeqCst));
+        let expected: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+        assert_eq!(Some(RequestMessage::Bodies(expected)), extension.body_downloader.lock().create_request());
+    }
+
+    #[test]
+    fn reconnected_peer_keeps_reputation_and_latency() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.penalize(&peer, 30);
+        extension.peer_latencies.lock().insert(peer, StdDuration::from_secs(3));
+        extension.on_node_removed(&peer);
+        assert_eq!(None, extension.reputations.read().get(&peer));
+
+        extension.on_node_added(&peer, 0);
+        assert_eq!(Some(&-30), extension.reputations.read().get(&peer));
+        assert_eq!(Some(&StdDuration::from_secs(3)), extension.peer_latencies.lock().get(&peer));
+
+        let other = node_id(3486);
+        extension.on_node_added(&other, 0);
+        assert_eq!(Some(&0), extension.reputations.read().get(&other));
+    }
+
+    #[test]
+    fn observer_never_sends_requests() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let config = Config {
+            observer: true,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+        let remote_info = remote.chain_info();
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.on_peer_status(
+            &peer,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        for _ in 0..3 {
+            extension.on_timeout(SYNC_TIMER_TOKEN);
+        }
+        assert_eq!(vec![(peer, remote_info.total_score)], extension.peers_ahead());
+
+        let request = Message::Request(0, RequestMessage::Bodies(vec![remote_info.best_block_hash]));
+        extension.on_message(&peer, &request.rlp_bytes());
+
+        while let Some(call) = network.pop_call("block-propagation") {
+            if let TestNetworkCall::Send(_, data) = call {
+                match UntrustedRlp::new(&data).as_val::<Message>().unwrap() {
+                    Message::Status {
+                        ..
+                    } => {}
+                    message => panic!("Unexpected message in observer mode: {:?}", message),
+                }
+            }
+        }
+        assert!(extension.requests.read()[&peer].is_empty());
+    }
+
+    #[test]
+    fn small_messages_are_flushed_as_one_batch() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let config = Config {
+            batch_window: Some(StdDuration::from_millis(50)),
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        while network.pop_call("block-propagation").is_some() {}
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.send_status(&peer);
+        let mut flush_timers = 0;
+        while let Some(call) = network.pop_call("block-propagation") {
+            match call {
+                TestNetworkCall::SetTimerOnce {
+                    token: BATCH_FLUSH_TOKEN,
+                    ..
+                } => flush_timers += 1,
+                TestNetworkCall::Send(..) => panic!("Messages must be queued until the flush"),
+                _ => {}
+            }
+        }
+        assert_eq!(1, flush_timers);
+
+        extension.on_timeout(BATCH_FLUSH_TOKEN);
+        let mut frames = Vec::new();
+        while let Some(call) = network.pop_call("block-propagation") {
+            if let TestNetworkCall::Send(to, data) = call {
+                assert_eq!(peer, to);
+                frames.push(UntrustedRlp::new(&data).as_val::<Message>().unwrap());
+            }
+        }
+        assert_eq!(1, frames.len());
+        match &frames[0] {
+            Message::Batch(messages) => assert_eq!(2, messages.len()),
+            message => panic!("Unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn locked_sync_target_is_reached_before_retargeting() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let config = Config {
+            lock_sync_target: true,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let remote = TestBlockChainClient::new();
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let announce = |remote: &TestBlockChainClient| {
+            let remote_info = remote.chain_info();
+            extension.on_peer_status(
+                &peer,
+                remote_info.total_score,
+                remote_info.best_block_hash,
+                remote_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+            remote_info.total_score
+        };
+
+        remote.add_blocks(5, 0);
+        let first_target = announce(&remote);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(Some(first_target), extension.sync_target());
+
+        // The peer keeps advancing while we're syncing
+        remote.add_blocks(5, 0);
+        let second_target = announce(&remote);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(Some(first_target), extension.sync_target());
+        assert_eq!(vec![(peer, first_target)], extension.peers_ahead());
+
+        client.add_blocks(5, 0);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(Some(second_target), extension.sync_target());
+        assert_eq!(vec![(peer, second_target)], extension.peers_ahead());
+    }
+
+    #[test]
+    fn fork_choice_decision_is_logged_with_both_tips() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let remote = TestBlockChainClient::new_with_extra_data(vec![1]);
+        remote.add_blocks(7, 0);
+        let remote_info = remote.chain_info();
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.on_peer_status(
+            &peer,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        let log = extension.fork_choice_log();
+        assert_eq!(1, log.len());
+        assert_eq!(peer, log[0].peer);
+        assert_eq!(
+            ChainTip {
+                total_score: remote_info.total_score,
+                best_hash: remote_info.best_block_hash,
+            },
+            log[0].peer_tip
+        );
+        let local_info = client.chain_info();
+        assert_eq!(
+            ChainTip {
+                total_score: local_info.total_score,
+                best_hash: local_info.best_block_hash,
+            },
+            log[0].our_tip
+        );
+    }
+
+    #[test]
+    fn peer_with_different_genesis_is_probed_once_before_dropped() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let config = Config {
+            genesis_mismatch_retry: Some(StdDuration::from_secs(0)),
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        let send_status = || {
+            extension.on_peer_status(
+                &peer,
+                chain_info.total_score,
+                chain_info.best_block_hash,
+                H256::random(),
+                Some(NetworkId::default()),
+                Vec::new(),
+            )
+        };
+        send_status();
+        assert!(extension.requests.read().contains_key(&peer));
+        sent_messages(&network, &peer);
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(1, sent_messages(&network, &peer));
+
+        send_status();
+        assert!(!extension.requests.read().contains_key(&peer));
+    }
+
+    #[test]
+    fn outdated_status_does_not_regress_peer() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(5, 0);
+        let older = remote.chain_info();
+        remote.add_blocks(5, 0);
+        let newer = remote.chain_info();
+        for info in &[newer.clone(), older] {
+            extension.on_peer_status(
+                &peer,
+                info.total_score,
+                info.best_block_hash,
+                info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+
+        let header_downloaders = extension.header_downloaders.read();
+        assert_eq!(newer.total_score, header_downloaders[&peer].total_score());
+        assert_eq!(newer.best_block_hash, header_downloaders[&peer].best_hash());
+    }
+
+    #[test]
+    fn status_is_sent_on_request() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        sent_messages(&network, &peer);
+
+        extension.on_message(&peer, &Message::GetStatus.rlp_bytes());
+        let chain_info = client.chain_info();
+        assert_eq!(Some((chain_info.total_score, chain_info.best_block_hash)), announced_status(&network, &peer));
+
+        extension.on_message(&node_id(3486), &Message::GetStatus.rlp_bytes());
+        assert_eq!(0, sent_messages(&network, &node_id(3486)));
+    }
+
+    #[test]
+    fn blocks_below_pruning_floor_are_not_imported() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        client.set_history(Some(2));
+        let fork = TestBlockChainClient::new_with_extra_data(vec![1]);
+        fork.add_blocks(2, 0);
+        let headers: Vec<_> =
+            (0..3).map(|number| fork.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        for header in &headers[1..] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let hashes: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+        extension.body_downloader.lock().create_request();
+        extension.on_body_response(&node_id(3485), hashes.clone(), vec![Vec::new(); 2]);
+
+        for hash in &hashes {
+            assert_eq!(None, client.block_body(BlockId::Hash(*hash)));
+        }
+        // The block after the skipped one can't be imported either
+        let mut body_downloader = extension.body_downloader.lock();
+        assert!(body_downloader.is_empty());
+        assert_eq!((0, 0), body_downloader.pending_counts());
+        assert_eq!(None, body_downloader.create_request());
+    }
+
+    #[test]
+    fn low_peer_diversity_is_reported_when_all_peers_share_a_tip() {
+        let warnings = Arc::new(AtomicUsize::new(0));
+        let counter = warnings.clone();
+        let config = Config {
+            min_diverse_peers: 2,
+            on_low_peer_diversity: Some(Box::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        let chain_info = client.chain_info();
+
+        let tip = H256::random();
+        for port in 3485..3488 {
+            let peer = node_id(port);
+            extension.on_node_added(&peer, 0);
+            extension.on_peer_status(
+                &peer,
+                chain_info.total_score + U256::from(10),
+                tip,
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(1, warnings.load(Ordering::SeqCst));
+
+        let peer = node_id(3488);
+        extension.on_node_added(&peer, 0);
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(11),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(1, warnings.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn body_of_queued_block_is_served_before_imported() {
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(1, 2);
+        let genesis = remote.block_header(BlockId::Number(0)).unwrap().decode();
+        let header = remote.block_header(BlockId::Number(1)).unwrap().decode();
+        let parcels = remote.block_body(BlockId::Number(1)).unwrap().parcels();
+        let client = Arc::new(TestBlockChainClient::new());
+        client.import_header(header.rlp_bytes().to_vec()).unwrap();
+
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        extension.body_downloader.lock().add_target(&header, &genesis);
+        extension.body_downloader.lock().create_request();
+        extension.on_body_response(&node_id(3485), vec![header.hash()], vec![parcels]);
+
+        // The block is still in the queue
+        client.blocks.write().remove(&header.hash());
+        let count = || match extension.create_bodies_response(vec![header.hash()], 1024) {
+            ResponseMessage::Bodies(bodies) => bodies[0].len(),
+            _ => unreachable!(),
+        };
+        assert_eq!(2, count());
+
+        extension.new_blocks(vec![header.hash()], vec![], vec![], vec![], vec![], 0);
+        assert_eq!(0, count());
+    }
+
+    #[test]
+    fn peer_on_the_same_genesis_with_incompatible_fork_id_is_rejected() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(20, 0);
+        let config = Config {
+            fork_blocks: vec![10],
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        let chain_info = client.chain_info();
+
+        let status = |fork_id| Message::Status {
+            total_score: chain_info.total_score + U256::from(10),
+            best_hash: H256::random(),
+            genesis_hash: chain_info.genesis_hash,
+            network_id: Some(NetworkId::default()),
+            features: Vec::new(),
+            fork_id: Some(fork_id),
+        };
+        // The peer has passed #10 without forking there
+        let (diverged, compatible) = (node_id(3485), node_id(3486));
+        extension.on_node_added(&diverged, 0);
+        extension.on_node_added(&compatible, 0);
+        let diverged_fork_id = ForkId::new(chain_info.genesis_hash, &[], chain_info.best_block_number);
+        extension.on_message(&diverged, &status(diverged_fork_id).rlp_bytes());
+        let compatible_fork_id = ForkId::new(chain_info.genesis_hash, &[10], chain_info.best_block_number);
+        extension.on_message(&compatible, &status(compatible_fork_id).rlp_bytes());
+
+        let peers: Vec<_> = extension.peers_ahead().into_iter().map(|(id, _)| id).collect();
+        assert_eq!(vec![compatible], peers);
+    }
+
+    #[test]
+    fn legacy_peer_without_fork_id_is_rejected_only_if_required() {
+        for require_fork_id in vec![false, true] {
+            let client = Arc::new(TestBlockChainClient::new());
+            let config = Config {
+                require_fork_id,
+                ..Config::default()
+            };
+            let extension = Extension::new(client.clone(), config);
+            let mut network = TestNetworkClient::new();
+            network.register_extension(extension.clone());
+            let chain_info = client.chain_info();
+
+            // Older versions send only the scores and the hashes in their status
+            let peer = node_id(3485);
+            extension.on_node_added(&peer, 0);
+            let mut s = RlpStream::new_list(2);
+            s.append(&0x01u8);
+            s.begin_list(3);
+            s.append(&(chain_info.total_score + U256::from(10)));
+            s.append(&H256::random());
+            s.append(&chain_info.genesis_hash);
+            extension.on_message(&peer, &s.out());
+
+            let peers: Vec<_> = extension.peers_ahead().into_iter().map(|(id, _)| id).collect();
+            assert_eq!(!require_fork_id, peers == vec![peer]);
+        }
+    }
+
+    #[test]
+    fn sync_deadline_callback_is_called_once_if_sync_is_not_completed() {
+        let fired = Arc::new(AtomicUsize::new(0));
+        let counter = fired.clone();
+        let config = Config {
+            sync_deadline: Some(StdDuration::from_secs(0)),
+            on_sync_deadline: Some(Box::new(move || {
+                counter.fetch_add(1, Ordering::SeqCst);
+            })),
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(10),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(1, fired.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn status_broadcasts_are_coalesced_in_window() {
+        let config = Config {
+            status_broadcast_window: Some(StdDuration::from_secs(60)),
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score,
+            chain_info.best_block_hash,
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        while network.pop_call("block-propagation").is_some() {}
+
+        for _ in 0..3 {
+            client.add_blocks(1, 0);
+            let best_hash = client.chain_info().best_block_hash;
+            extension.new_blocks(vec![best_hash], vec![], vec![], vec![], vec![], 0);
+        }
+        assert_eq!(1, sent_messages(&network, &peer));
+
+        extension.on_timeout(STATUS_BROADCAST_TOKEN);
+        let chain_info = client.chain_info();
+        assert_eq!(Some((chain_info.total_score, chain_info.best_block_hash)), announced_status(&network, &peer));
+    }
+
+    #[test]
+    fn silent_peer_is_dropped_after_handshake_timeout() {
+        let config = Config {
+            handshake_timeout: Some(StdDuration::from_secs(0)),
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (silent, talking) = (node_id(3485), node_id(3486));
+        extension.on_node_added(&silent, 0);
+        extension.on_node_added(&talking, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &talking,
+            chain_info.total_score,
+            chain_info.best_block_hash,
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        assert!(!extension.requests.read().contains_key(&silent));
+        assert!(extension.requests.read().contains_key(&talking));
+        assert!(extension.pending_handshakes.lock().is_empty());
+    }
+
+    #[test]
+    fn block_without_imported_parent_is_deferred_until_parent_is_imported() {
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(2, 0);
+        let headers: Vec<_> =
+            (0..3).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        let client = Arc::new(TestBlockChainClient::new());
+        for header in &headers[1..] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        // Only the body of #2 is downloaded, while #1 arrives in another way
+        extension.body_downloader.lock().add_target(&headers[2], &headers[1]);
+        extension.body_downloader.lock().create_request();
+        extension.on_body_response(&node_id(3485), vec![headers[2].hash()], vec![Vec::new()]);
+        assert_eq!(None, client.block_body(BlockId::Hash(headers[2].hash())));
+        assert!(!extension.body_downloader.lock().is_empty());
+
+        client.import_block(remote.block(BlockId::Number(1)).unwrap().into_inner()).unwrap();
+        extension.new_blocks(vec![headers[1].hash()], vec![], vec![], vec![], vec![], 0);
+        assert!(client.block_body(BlockId::Hash(headers[2].hash())).is_some());
+        assert!(extension.body_downloader.lock().is_empty());
+    }
+
+    #[test]
+    fn peer_which_only_requests_is_flagged_as_leech() {
+        let config = Config {
+            min_leech_bytes: 1,
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (leech, fair) = (node_id(3485), node_id(3486));
+        let chain_info = client.chain_info();
+        for peer in &[leech, fair] {
+            extension.on_node_added(peer, 0);
+            extension.on_peer_status(
+                peer,
+                chain_info.total_score,
+                chain_info.best_block_hash,
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        let request = RequestMessage::Headers {
+            start_number: 0,
+            max_count: 10,
+            skip: 0,
+            reverse: false,
+        };
+        extension.on_message(&leech, &Message::Request(1, request).rlp_bytes());
+
+        let traffic = extension.peer_traffic(&leech).unwrap();
+        assert_eq!(0, traffic.served);
+        assert!(traffic.requested > 0);
+        assert_eq!(Some(0.0), traffic.fairness_ratio());
+        assert!(extension.is_leech(&leech));
+        assert_eq!(None, extension.peer_traffic(&fair).unwrap().fairness_ratio());
+        assert!(!extension.is_leech(&fair));
+    }
+
+    #[test]
+    fn announcement_includes_recent_block_hashes() {
+        let config = Config {
+            announcement_depth: 3,
+            ..Config::default()
+        };
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score,
+            chain_info.best_block_hash,
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        while network.pop_call("block-propagation").is_some() {}
+        extension.new_blocks(vec![chain_info.best_block_hash], vec![], vec![], vec![], vec![], 0);
+
+        let mut announced = None;
+        while let Some(call) = network.pop_call("block-propagation") {
+            if let TestNetworkCall::Send(id, bytes) = call {
+                if let Ok(Message::NewBlockHashes(hashes)) = UntrustedRlp::new(&bytes).as_val() {
+                    assert_eq!(peer, id);
+                    announced = Some(hashes);
+                }
+            }
+        }
+        let expected: Vec<_> = (3..6).map(|number| client.block_hash(BlockId::Number(number)).unwrap()).collect();
+        assert_eq!(Some(expected), announced);
+    }
+
+    #[test]
+    fn unavailable_bodies_are_told_apart_by_whether_they_are_pruned() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        client.set_history(Some(2));
+        let fork = TestBlockChainClient::new_with_extra_data(vec![1]);
+        fork.add_blocks(9, 0);
+        // Headers without bodies, of which the one below the pruning floor has been pruned
+        let pruned = fork.block_header(BlockId::Number(1)).unwrap();
+        let not_imported = fork.block_header(BlockId::Number(9)).unwrap();
+        client.import_header(pruned.clone().into_inner()).unwrap();
+        client.import_header(not_imported.clone().into_inner()).unwrap();
+
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score,
+            chain_info.best_block_hash,
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        while network.pop_call("block-propagation").is_some() {}
+
+        let unknown = H256::random();
+        let request =
+            RequestMessage::Bodies(vec![chain_info.best_block_hash, pruned.hash(), not_imported.hash(), unknown]);
+        extension.on_message(&peer, &Message::Request(1, request).rlp_bytes());
+
+        let messages: Vec<Message> = {
+            let mut messages = Vec::new();
+            while let Some(call) = network.pop_call("block-propagation") {
+                if let TestNetworkCall::Send(_, bytes) = call {
+                    messages.push(UntrustedRlp::new(&bytes).as_val().unwrap());
+                }
+            }
+            messages
+        };
+        assert_eq!(2, messages.len());
+        assert_eq!(
+            Message::UnavailableBodies {
+                transient: vec![not_imported.hash(), unknown],
+                permanent: vec![pruned.hash()],
+            },
+            messages[0]
+        );
+        match &messages[1] {
+            Message::Response(1, ResponseMessage::Bodies(bodies)) => assert_eq!(4, bodies.len()),
+            message => panic!("Unexpected message: {:?}", message),
+        }
+    }
+
+    #[test]
+    fn sync_phases_are_reported_in_order() {
+        let phases = Arc::new(Mutex::new(Vec::new()));
+        let recorded = phases.clone();
+        let config = Config {
+            on_phase_change: Some(Box::new(move |phase| recorded.lock().push(phase))),
+            ..Config::default()
+        };
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(1, 0);
+        let genesis = remote.block_header(BlockId::Number(0)).unwrap().decode();
+        let header = remote.block_header(BlockId::Number(1)).unwrap().decode();
+        let client = Arc::new(TestBlockChainClient::new());
+        client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let remote_info = remote.chain_info();
+        extension.on_peer_status(
+            &peer,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(SyncPhase::AncestorDiscovery, extension.sync_phase());
+
+        extension.body_downloader.lock().add_target(&header, &genesis);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        extension.on_body_response(&peer, vec![header.hash()], vec![Vec::new()]);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        extension.new_blocks(vec![header.hash()], vec![], vec![], vec![], vec![], 0);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        assert_eq!(
+            vec![SyncPhase::AncestorDiscovery, SyncPhase::BodyFill, SyncPhase::Finalization, SyncPhase::Idle],
+            *phases.lock()
+        );
+    }
+
+    #[test]
+    fn peer_which_contributed_valid_blocks_is_preferred() {
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(2, 0);
+        let headers: Vec<_> =
+            (0..3).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        let client = Arc::new(TestBlockChainClient::new());
+        for header in &headers[1..] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let proven = node_id(3485);
+        let unproven = node_id(3486);
+        let chain_info = client.chain_info();
+        for peer in &[proven, unproven] {
+            extension.on_node_added(peer, 0);
+            extension.on_peer_status(
+                peer,
+                chain_info.total_score + U256::from(100),
+                H256::random(),
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+
+        extension.body_downloader.lock().add_target(&headers[1], &headers[0]);
+        extension.body_downloader.lock().create_request();
+        extension.on_body_response(&proven, vec![headers[1].hash()], vec![Vec::new()]);
+        extension.new_blocks(vec![headers[1].hash()], vec![], vec![], vec![], vec![], 0);
+        let states = extension.peer_states();
+        assert_eq!(1, states[&proven].contributed_blocks);
+        assert_eq!(0, states[&unproven].contributed_blocks);
+        assert_eq!(states[&proven].reputation, states[&unproven].reputation);
+
+        extension.body_downloader.lock().add_target(&headers[2], &headers[1]);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let has_body_request = |peer: &NodeId| {
+            extension.requests.read()[peer].iter().any(|(_, request)| match request {
+                RequestMessage::Bodies(..) => true,
+                _ => false,
+            })
+        };
+        assert!(has_body_request(&proven));
+        assert!(!has_body_request(&unproven));
+    }
+
+    #[test]
+    fn forced_resync_downloads_headers_from_genesis() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        let chain_info = client.chain_info();
+        extension.on_node_added(&peer, 0);
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(100),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        let header_requests = || -> Vec<_> {
+            extension.requests.read()[&peer]
+                .iter()
+                .filter_map(|(_, request)| match request {
+                    RequestMessage::Headers {
+                        start_number,
+                        ..
+                    } => Some(*start_number),
+                    _ => None,
+                })
+                .collect()
+        };
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(vec![10], header_requests());
+
+        extension.force_resync_from_genesis();
+        // The genesis block is the first header of the response, followed by #1
+        assert_eq!(vec![0], header_requests());
+    }
+
+    #[test]
+    fn status_reports_imported_best_while_downloading_ahead() {
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(5, 0);
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(2, 0);
+        for number in 3..6 {
+            client.import_header(remote.block_header(BlockId::Number(number)).unwrap().into_inner()).unwrap();
+        }
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        let chain_info = client.chain_info();
+        extension.update_imported_tip(ChainTip {
+            total_score: chain_info.total_score + U256::from(100),
+            best_hash: remote.chain_info().best_block_hash,
+        });
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        sent_messages(&network, &peer);
+        extension.on_message(&peer, &Message::GetStatus.rlp_bytes());
+        assert_eq!(Some((chain_info.total_score, chain_info.best_block_hash)), announced_status(&network, &peer));
+    }
+
+    #[test]
+    fn peer_without_common_ancestor_is_dropped_after_bounded_search() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        let remote = TestBlockChainClient::new_with_extra_data(vec![1]);
+        remote.add_blocks(10, 0);
+        let config = Config {
+            max_ancestor_search_depth: 2,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        let remote_info = remote.chain_info();
+        extension.on_node_added(&peer, 0);
+        extension.on_peer_status(
+            &peer,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+
+        for &start in &[5, 4, 3] {
+            assert!(extension.header_downloaders.read().contains_key(&peer));
+            let headers =
+                (start..start + 3).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode());
+            extension.on_header_response(&peer, headers.collect());
+        }
+        assert!(!extension.header_downloaders.read().contains_key(&peer));
+    }
+
+    #[test]
+    fn message_histogram_counts_each_type_in_window() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        let chain_info = client.chain_info();
+        extension.on_node_added(&peer, 0);
+        let status = Message::Status {
+            total_score: chain_info.total_score,
+            best_hash: chain_info.best_block_hash,
+            genesis_hash: chain_info.genesis_hash,
+            network_id: Some(NetworkId::default()),
+            features: Vec::new(),
+            fork_id: None,
+        };
+        extension.on_message(&peer, &status.rlp_bytes());
+        let request = RequestMessage::Headers {
+            start_number: 0,
+            max_count: 5,
+            skip: 0,
+            reverse: false,
+        };
+        let batch = Message::Batch(vec![Message::GetStatus, Message::Request(1, request)]);
+        extension.on_message(&peer, &batch.rlp_bytes());
+
+        let histogram = extension.message_histogram();
+        assert_eq!(Some(&1), histogram.received.get("Status"));
+        assert_eq!(Some(&1), histogram.received.get("GetStatus"));
+        assert_eq!(Some(&1), histogram.received.get("RequestHeaders"));
+        assert_eq!(None, histogram.received.get("Batch"));
+        assert_eq!(Some(&2), histogram.sent.get("Status"));
+        assert_eq!(Some(&1), histogram.sent.get("Headers"));
+    }
+
+    #[test]
+    fn skeleton_segments_are_downloaded_from_idle_peers() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(300, 0);
+        let config = Config {
+            skeleton_threshold: 10,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peers = vec![node_id(3485), node_id(3486)];
+        let remote_info = remote.chain_info();
+        for peer in &peers {
+            extension.on_node_added(peer, 0);
+            extension.on_peer_status(
+                peer,
+                remote_info.total_score,
+                remote_info.best_block_hash,
+                remote_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+        let header_request = |peer: &NodeId| -> Option<(u64, u64, u64)> {
+            extension.requests.read()[peer]
+                .iter()
+                .filter_map(|(request_id, request)| match request {
+                    RequestMessage::Headers {
+                        start_number,
+                        skip,
+                        ..
+                    } => Some((*request_id, *start_number, *skip)),
+                    _ => None,
+                })
+                .next()
+        };
+        let headers = |from: u64, to: u64| -> Vec<_> {
+            (from..to + 1).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect()
+        };
+
+        // Only one of the peers is asked for the skeleton, and the other waits for its segments
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let (owner, other) = if header_request(&peers[0]).is_some() {
+            (peers[0], peers[1])
+        } else {
+            (peers[1], peers[0])
+        };
+        assert_eq!(None, header_request(&other));
+        let (request_id, _, skip) = header_request(&owner).unwrap();
+        assert_eq!(126, skip);
+        let skeleton = vec![0, 127, 254].into_iter().map(|number| headers(number, number).remove(0)).collect();
+        extension.on_peer_response(&owner, request_id, ResponseMessage::Headers(skeleton));
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let (owner_request, owner_start, _) = header_request(&owner).unwrap();
+        let (other_request, other_start, _) = header_request(&other).unwrap();
+        let mut starts = vec![owner_start, other_start];
+        starts.sort();
+        assert_eq!(vec![0, 127], starts);
+
+        extension.on_peer_response(
+            &other,
+            other_request,
+            ResponseMessage::Headers(headers(other_start, other_start + 127)),
+        );
+        extension.on_peer_response(
+            &owner,
+            owner_request,
+            ResponseMessage::Headers(headers(owner_start, owner_start + 127)),
+        );
+        let last_hash = remote.block_hash(BlockId::Number(254)).unwrap();
+        assert!(client.block_header(BlockId::Hash(last_hash)).is_some());
+        assert!(extension.reputations.read()[&other] >= 0);
+    }
+
+    #[test]
+    fn peer_without_headers_past_pivot_keeps_reputation() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        extension.on_peer_status(
+            &peer,
+            chain_info.total_score + U256::from(1),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        // The peer has reorganized to a shorter chain since its status
+        let request_id = extension.requests.read()[&peer][0].0;
+        extension.on_peer_response(&peer, request_id, ResponseMessage::Headers(Vec::new()));
+        assert!(extension.reputations.read().get(&peer).cloned().unwrap_or(0) >= 0);
+        assert!(extension.header_downloaders.read().contains_key(&peer));
+    }
+
+    #[test]
+    fn reputation_recovers_over_time() {
+        let config = Config {
+            reputation_decay: 40,
+            ..Config::default()
+        };
+        let extension = Extension::new(Arc::new(TestBlockChainClient::new()), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.on_message(&peer, &[0xff]);
+        assert_eq!(-100, extension.reputations.read()[&peer]);
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(-60, extension.reputations.read()[&peer]);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(0, extension.reputations.read()[&peer]);
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert_eq!(0, extension.reputations.read()[&peer]);
+    }
+
+    #[test]
+    fn blocks_after_the_one_rejected_by_block_filter_are_dropped() {
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(4, 0);
+        let headers: Vec<_> =
+            (0..5).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        let client = Arc::new(TestBlockChainClient::new());
+        for header in &headers[1..4] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        client.add_notify(extension.clone());
+        let rejected = headers[2].hash();
+        extension.set_block_filter(Box::new(move |header: &Header| header.hash() != rejected));
+
+        for neighbors in headers[..4].windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let hashes: Vec<_> = headers[1..4].iter().map(|header| header.hash()).collect();
+        extension.body_downloader.lock().create_request();
+        extension.on_body_response(&node_id(3485), hashes.clone(), vec![Vec::new(); 3]);
+
+        assert!(client.block_body(BlockId::Hash(hashes[0])).is_some());
+        assert_eq!(None, client.block_body(BlockId::Hash(hashes[1])));
+        assert_eq!(None, client.block_body(BlockId::Hash(hashes[2])));
+        assert!(extension.body_downloader.lock().is_empty());
+
+        // Headers building on the rejected block aren't imported
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.on_peer_status(
+            &peer,
+            remote.chain_info().total_score,
+            headers[4].hash(),
+            client.chain_info().genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.import_downloaded_headers(&peer, &peer, vec![remote.block_header(BlockId::Number(4)).unwrap()]);
+        assert_eq!(None, client.block_header(BlockId::Hash(headers[4].hash())));
+        assert!(!extension.header_downloaders.read()[&peer].is_idle());
+
+        // The blocks of another branch are still downloaded and imported
+        let fork = TestBlockChainClient::new_with_extra_data(vec![1]);
+        fork.add_blocks(2, 0);
+        let fork_headers: Vec<_> =
+            (1..3).map(|number| fork.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        for header in &fork_headers {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+        let fork_hashes: Vec<_> = fork_headers.iter().map(|header| header.hash()).collect();
+        assert_eq!(
+            Some(RequestMessage::Bodies(fork_hashes.clone())),
+            extension.body_downloader.lock().create_request()
+        );
+        extension.on_body_response(&peer, fork_hashes.clone(), vec![Vec::new(); 2]);
+        assert!(client.block_body(BlockId::Hash(fork_hashes[1])).is_some());
+        assert!(extension.body_downloader.lock().is_empty());
+    }
+
+    #[test]
+    fn bodies_requested_from_removed_peer_are_requested_again() {
+        let extension = Extension::new(Arc::new(TestBlockChainClient::new()), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let headers = chain(11);
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.send_body_request(&peer);
+        assert_eq!(None, extension.body_downloader.lock().create_request());
+
+        extension.on_node_removed(&peer);
+        let hashes = headers[1..].iter().map(|header| header.hash()).collect();
+        assert_eq!(Some(RequestMessage::Bodies(hashes)), extension.body_downloader.lock().create_request());
+    }
+
+    #[test]
+    fn reputable_peer_is_served_with_more_bodies() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(10, 0);
+        let config = Config {
+            min_body_response_bytes: 1,
+            max_body_response_bytes: 1024,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let new_peer = node_id(3485);
+        let reputable_peer = node_id(3486);
+        extension.on_node_added(&new_peer, 0);
+        extension.on_node_added(&reputable_peer, 0);
+        extension.reputations.write().insert(reputable_peer, MAX_REPUTATION);
+
+        let hashes: Vec<_> =
+            (1..11).map(|number| client.block_header(BlockId::Number(number)).unwrap().hash()).collect();
+        let count = |peer: &NodeId| match extension
+            .create_bodies_response(hashes.clone(), extension.body_response_bytes(peer))
+        {
+            ResponseMessage::Bodies(bodies) => bodies.len(),
+            _ => unreachable!(),
+        };
+        assert_eq!(1, count(&new_peer));
+        assert_eq!(10, count(&reputable_peer));
+    }
+
+    #[test]
+    fn headers_response_is_truncated_at_byte_budget() {
+        let client = Arc::new(TestBlockChainClient::new_with_extra_data(vec![0; 1024]));
+        client.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+
+        let header_bytes = client.block_header(BlockId::Number(1)).unwrap().rlp().as_raw().len();
+        let count = |max_bytes: usize| match extension.create_headers_response(1, 10, 0, false, max_bytes) {
+            ResponseMessage::Headers(headers) => headers.len(),
+            _ => unreachable!(),
+        };
+        assert_eq!(1, count(0));
+        assert_eq!(3, count(header_bytes * 3));
+        assert_eq!(10, count(header_bytes * 20));
+    }
+
+    #[test]
+    fn peer_on_other_network_is_ignored() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let chain_info = client.chain_info();
+        let total_score = chain_info.total_score + U256::from(10);
+        extension.on_peer_status(
+            &peer,
+            total_score,
+            H256::random(),
+            chain_info.genesis_hash,
+            Some("xx".into()),
+            Vec::new(),
+        );
+        assert!(extension.peers_ahead().is_empty());
+
+        extension.on_peer_status(
+            &peer,
+            total_score,
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        assert_eq!(vec![(peer, total_score)], extension.peers_ahead());
+
+        // Older versions don't send the network id, which isn't checked then
+        let legacy = node_id(3486);
+        extension.on_node_added(&legacy, 0);
+        extension.on_peer_status(&legacy, total_score, H256::random(), chain_info.genesis_hash, None, Vec::new());
+        assert_eq!(2, extension.peers_ahead().len());
+    }
+
+    #[test]
+    fn peer_of_first_version_is_sent_legacy_status() {
+        let extension = Extension::new(Arc::new(TestBlockChainClient::new()), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (legacy, current) = (node_id(3485), node_id(3486));
+        extension.on_node_added(&legacy, LEGACY_VERSION);
+        extension.on_node_added(&current, 1);
+        let mut network_ids = HashMap::new();
+        while let Some(call) = network.pop_call("block-propagation") {
+            if let TestNetworkCall::Send(id, bytes) = call {
+                if let Ok(Message::Status {
+                    network_id,
+                    ..
+                }) = UntrustedRlp::new(&bytes).as_val()
+                {
+                    network_ids.insert(id, network_id);
+                }
+            }
+        }
+        assert_eq!(Some(&None), network_ids.get(&legacy));
+        assert_eq!(Some(&Some(NetworkId::default())), network_ids.get(&current));
+    }
+
+    #[test]
+    fn pruned_body_is_served_from_archive() {
+        let archived = Arc::new(TestBlockChainClient::new());
+        archived.add_blocks(1, 2);
+        let hash = archived.block_header(BlockId::Number(1)).unwrap().hash();
+        let extension = Extension::new(Arc::new(TestBlockChainClient::new()), Config::default());
+
+        let count = || match extension.create_bodies_response(vec![hash], 1024) {
+            ResponseMessage::Bodies(bodies) => bodies[0].len(),
+            _ => unreachable!(),
+        };
+        assert_eq!(0, count());
+
+        extension.set_archive(Box::new(move |hash: &H256| archived.block_body(BlockId::Hash(*hash))));
+        assert_eq!(2, count());
+    }
+
+    #[test]
+    fn peers_ahead_are_sorted_by_score() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        let chain_info = client.chain_info();
+        let total_score = chain_info.total_score;
+
+        let (behind, ahead, far_ahead) = (node_id(3485), node_id(3486), node_id(3487));
+        extension.on_node_added(&behind, 0);
+        extension.on_node_added(&ahead, 0);
+        extension.on_node_added(&far_ahead, 0);
+        extension.on_peer_status(
+            &ahead,
+            total_score + U256::from(10),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_peer_status(
+            &behind,
+            total_score,
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_peer_status(
+            &far_ahead,
+            total_score + U256::from(20),
+            H256::random(),
+            chain_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+
+        assert_eq!(
+            vec![(far_ahead, total_score + U256::from(20)), (ahead, total_score + U256::from(10))],
+            extension.peers_ahead()
+        );
+    }
+
+    #[test]
+    fn peer_on_losing_fork_is_not_requested() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let (losing, winning) = (node_id(3485), node_id(3486));
+        extension.on_node_added(&losing, 0);
+        extension.on_node_added(&winning, 0);
+
+        let genesis_hash = client.chain_info().genesis_hash;
+        let known_hash = client.block_header(BlockId::Number(3)).unwrap().hash();
+        extension.on_peer_status(
+            &losing,
+            U256::max_value(),
+            known_hash,
+            genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_peer_status(
+            &winning,
+            U256::max_value(),
+            H256::random(),
+            genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        assert!(extension.is_on_losing_fork(&losing));
+        assert!(!extension.is_on_losing_fork(&winning));
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        assert!(extension.requests.read()[&losing].is_empty());
+        assert_eq!(1, extension.requests.read()[&winning].len());
+    }
+
+    #[test]
+    fn announced_block_ahead_of_tip_requests_connecting_headers() {
+        let client = Arc::new(TestBlockChainClient::new());
+        client.add_blocks(5, 0);
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(15, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let remote_info = remote.chain_info();
+        extension.on_peer_status(
+            &peer,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        let requests: Vec<_> = extension.requests.read()[&peer].iter().map(|(_, request)| request.clone()).collect();
+        assert_eq!(
+            vec![RequestMessage::Headers {
+                start_number: 5,
+                max_count: 128,
+                skip: 0,
+                reverse: false,
+            }],
+            requests
+        );
+    }
+
+    #[test]
+    fn peer_claiming_implausible_score_is_penalized() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        let remote_info = remote.chain_info();
+        let claimed_score = remote_info.total_score * U256::from(1000);
+        extension.on_peer_status(
+            &peer,
+            claimed_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        let request_id = extension.requests.read()[&peer][0].0;
+        let headers = (0..11).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        extension.on_peer_response(&peer, request_id, ResponseMessage::Headers(headers));
+        assert!(extension.reputations.read()[&peer] < 0);
+    }
+
+    #[test]
+    fn equal_score_fork_is_evaluated_if_configured() {
+        for evaluate_equal_score_fork in vec![false, true] {
+            let client = Arc::new(TestBlockChainClient::new());
+            client.add_blocks(5, 0);
+            let config = Config {
+                evaluate_equal_score_fork,
+                ..Config::default()
+            };
+            let extension = Extension::new(client.clone(), config);
+            let mut network = TestNetworkClient::new();
+            network.register_extension(extension.clone());
+
+            let peer = node_id(3485);
+            extension.on_node_added(&peer, 0);
+            let chain_info = client.chain_info();
+            extension.on_peer_status(
+                &peer,
+                chain_info.total_score,
+                H256::random(),
+                chain_info.genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+            extension.on_timeout(SYNC_TIMER_TOKEN);
+            assert_eq!(evaluate_equal_score_fork, !extension.requests.read()[&peer].is_empty());
+        }
+    }
+
+    #[test]
+    fn peer_with_full_inflight_window_is_not_requested() {
+        for (max_inflight_bytes, expected) in vec![(4 * 1024 * 1024, 2), (1, 1)] {
+            let client = Arc::new(TestBlockChainClient::new());
+            let config = Config {
+                max_inflight_bytes,
+                ..Config::default()
+            };
+            let extension = Extension::new(client.clone(), config);
+            let mut network = TestNetworkClient::new();
+            network.register_extension(extension.clone());
+
+            let headers = chain(11);
+            for neighbors in headers.windows(2) {
+                extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+            }
+            let peer = node_id(3485);
+            extension.on_node_added(&peer, 0);
+            let genesis_hash = client.chain_info().genesis_hash;
+            extension.on_peer_status(
+                &peer,
+                U256::max_value(),
+                H256::random(),
+                genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+
+            // A headers request is sent first, and fills the window
+            extension.on_timeout(SYNC_TIMER_TOKEN);
+            assert_eq!(expected, extension.requests.read()[&peer].len());
+        }
+    }
+
+    #[test]
+    fn aggressive_mode_requests_distinct_bodies_from_every_idle_peer() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let config = Config {
+            aggressive: true,
+            ..Config::default()
+        };
+        let extension = Extension::new(client.clone(), config);
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let headers = chain(41);
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let genesis_hash = client.chain_info().genesis_hash;
+        let peers: Vec<_> = (3485..3489).map(node_id).collect();
+        for peer in &peers {
+            extension.on_node_added(peer, 0);
+            extension.on_peer_status(
+                peer,
+                U256::max_value(),
+                H256::random(),
+                genesis_hash,
+                Some(NetworkId::default()),
+                Vec::new(),
+            );
+        }
+
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+
+        let mut requested = HashSet::new();
+        for peer in &peers {
+            let bodies: Vec<_> = extension.requests.read()[peer]
+                .iter()
+                .filter_map(|(_, request)| match request {
+                    RequestMessage::Bodies(hashes) => Some(hashes.clone()),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(1, bodies.len());
+            assert_eq!(10, bodies[0].len());
+            requested.extend(bodies[0].iter().cloned());
+        }
+        let expected: HashSet<_> = headers[1..].iter().map(|header| header.hash()).collect();
+        assert_eq!(expected, requested);
+    }
+
+    #[test]
+    fn valid_headers_batch_is_verified() {
+        assert_eq!(Ok(()), verify_headers_batch(&chain(10), VerificationLevel::Full));
+        assert_eq!(Ok(()), verify_headers_batch(&[], VerificationLevel::Full));
+    }
+
+    #[test]
+    fn headers_batch_reports_first_invalid_header() {
+        let mut headers = chain(10);
+        let parent_timestamp = headers[4].timestamp();
+        headers[5].set_timestamp(parent_timestamp);
+
+        match verify_headers_batch(&headers, VerificationLevel::Full) {
+            Err((5, BlockError::InvalidTimestamp(..))) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn looping_headers_batch_is_rejected() {
+        let mut headers = chain(5);
+        let looping_parent = headers[2].hash();
+        headers[4].set_parent_hash(looping_parent);
+        match verify_headers_batch(&headers, VerificationLevel::Full) {
+            Err((4, BlockError::InvalidParentHash(..))) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+
+        let mut headers = chain(5);
+        let duplicated = headers[3].clone();
+        headers.insert(4, duplicated);
+        match verify_headers_batch(&headers, VerificationLevel::Full) {
+            Err((4, BlockError::InvalidNumber(..))) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+    }
+
+    #[test]
+    fn headers_below_checkpoint_are_verified_lightly() {
+        let mut headers = chain(10);
+        for number in vec![3, 7] {
+            let parent_timestamp = headers[number - 1].timestamp();
+            headers[number].set_timestamp(parent_timestamp);
+        }
+
+        match verify_headers_batch(&headers, VerificationLevel::LightBelowCheckpoint(5)) {
+            Err((7, BlockError::InvalidTimestamp(..))) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(Ok(()), verify_headers_batch(&headers, VerificationLevel::LightBelowCheckpoint(8)));
+        assert_eq!(Ok(()), verify_headers_batch(&headers, VerificationLevel::None));
+    }
+
+    #[test]
+    fn unlinked_headers_batch_is_rejected_at_every_level() {
+        let mut headers = chain(10);
+        headers.remove(5);
+        for &level in &[VerificationLevel::Full, VerificationLevel::LightBelowCheckpoint(20), VerificationLevel::None] {
+            match verify_headers_batch(&headers, level) {
+                Err((5, BlockError::InvalidNumber(..))) => {}
+                result => panic!("Unexpected result: {:?}", result),
+            }
+        }
+    }
+
+    #[test]
+    fn partly_invalid_headers_response_is_not_rewarded() {
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(10, 0);
+        let remote_info = remote.chain_info();
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+        extension.on_peer_status(
+            &peer,
+            remote_info.total_score,
+            remote_info.best_block_hash,
+            remote_info.genesis_hash,
+            Some(NetworkId::default()),
+            Vec::new(),
+        );
+        extension.on_timeout(SYNC_TIMER_TOKEN);
+        let request_id = extension.requests.read()[&peer]
+            .iter()
+            .filter_map(|&(id, ref request)| match *request {
+                RequestMessage::Headers {
+                    ..
+                } => Some(id),
+                _ => None,
+            })
+            .next()
+            .unwrap();
+        let mut headers: Vec<_> =
+            (0..11).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        headers.remove(1);
+        extension.on_message(&peer, &Message::Response(request_id, ResponseMessage::Headers(headers)).rlp_bytes());
+        assert_eq!(Some(&-INVALID_MESSAGE_PENALTY), extension.reputations.read().get(&peer));
+    }
+
+    #[test]
+    fn body_downloader_recovers_from_inconsistency() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        // Targets whose headers don't exist in the chain
+        let headers = chain(3);
+        for neighbors in headers.windows(2) {
+            extension.body_downloader.lock().add_target(&neighbors[1], &neighbors[0]);
+        }
+        let hashes: Vec<_> = headers[1..].iter().map(|header| header.hash()).collect();
+        assert_eq!(Some(RequestMessage::Bodies(hashes.clone())), extension.body_downloader.lock().create_request());
+
+        extension.on_body_response(&node_id(3485), hashes, vec![Vec::new(), Vec::new()]);
+        assert_eq!(None, extension.body_downloader.lock().create_request());
+
+        extension.body_downloader.lock().add_target(&headers[1], &headers[0]);
+        assert_eq!(
+            Some(RequestMessage::Bodies(vec![headers[1].hash()])),
+            extension.body_downloader.lock().create_request()
+        );
+    }
+
+    #[test]
+    fn body_not_matching_its_header_is_requested_again() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+        let peer = node_id(3485);
+        extension.on_node_added(&peer, 0);
+
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(2, 1);
+        let headers: Vec<_> =
+            (0..2).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        extension.body_downloader.lock().add_target(&headers[1], &headers[0]);
+        let hash = headers[1].hash();
+        extension.body_downloader.lock().create_request();
+
+        // Older versions answer a body they don't have with an empty one
+        extension.on_body_response(&peer, vec![hash], vec![Vec::new()]);
+        assert_eq!(Some(&0), extension.reputations.read().get(&peer));
+        assert_eq!(Some(RequestMessage::Bodies(vec![hash])), extension.body_downloader.lock().create_request());
+
+        let other_body = remote.block_body(BlockId::Number(2)).unwrap().parcels();
+        extension.on_body_response(&peer, vec![hash], vec![other_body]);
+        assert_eq!(Some(&-INVALID_MESSAGE_PENALTY), extension.reputations.read().get(&peer));
+        assert_eq!(Some(RequestMessage::Bodies(vec![hash])), extension.body_downloader.lock().create_request());
+    }
+
+    #[test]
+    fn time_to_sync_is_estimated_from_import_rate() {
+        let window = StdDuration::from_secs(10);
+        assert_eq!(Some(StdDuration::from_secs(50)), estimate_time_to_sync(100, 20, window));
+        assert_eq!(Some(StdDuration::from_secs(0)), estimate_time_to_sync(0, 20, window));
+        assert_eq!(None, estimate_time_to_sync(100, 0, window));
+
+        let extension = Extension::new(Arc::new(TestBlockChainClient::new()), Config::default());
+        assert_eq!(None, extension.eta_to_sync());
+    }
+
+    #[test]
+    fn out_of_order_blocks_are_detected() {
+        let headers = chain(4);
+        assert!(is_child_block(&headers[1], &headers[2]));
+        assert!(!is_child_block(&headers[1], &headers[3]));
+        assert!(!is_child_block(&headers[2], &headers[1]));
+
+        let mut fork = headers[2].clone();
+        fork.set_parent_hash(H256::random());
+        assert!(!is_child_block(&headers[1], &fork));
+    }
+
+    #[test]
+    #[cfg_attr(debug_assertions, should_panic(expected = "imported out of order"))]
+    fn body_downloader_is_reset_when_blocks_are_imported_out_of_order() {
+        let remote = TestBlockChainClient::new();
+        remote.add_blocks(3, 0);
+        let headers: Vec<_> =
+            (0..4).map(|number| remote.block_header(BlockId::Number(number)).unwrap().decode()).collect();
+        let client = Arc::new(TestBlockChainClient::new());
+        for header in &headers[1..] {
+            client.import_header(header.rlp_bytes().to_vec()).unwrap();
+        }
+        let extension = Extension::new(client.clone(), Config::default());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        // The target of #2 is missing, so #3 comes right after #1
+        extension.body_downloader.lock().add_target(&headers[1], &headers[0]);
+        extension.body_downloader.lock().add_target(&headers[3], &headers[2]);
+        let hashes = vec![headers[1].hash(), headers[3].hash()];
+        extension.body_downloader.lock().create_request();
+        extension.on_body_response(&node_id(3485), hashes.clone(), vec![Vec::new(); 2]);
+
+        assert!(client.block_body(BlockId::Hash(hashes[0])).is_some());
+        assert_eq!(None, client.block_body(BlockId::Hash(hashes[1])));
+        // The downloader is rebuilt from the best block, which is #1
+        assert!(extension.body_downloader.lock().is_empty());
+    }
 }
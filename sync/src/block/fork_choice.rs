@@ -0,0 +1,37 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use primitives::{H256, U256};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ChainTip {
+    pub total_score: U256,
+    pub best_hash: H256,
+}
+
+/// Decides which chain to follow when a peer's chain differs from ours
+pub trait ForkChoice: Send + Sync {
+    fn is_better(&self, peer: &ChainTip, ours: &ChainTip) -> bool;
+}
+
+/// Follows the chain with the highest total score
+pub struct HighestScore;
+
+impl ForkChoice for HighestScore {
+    fn is_better(&self, peer: &ChainTip, ours: &ChainTip) -> bool {
+        peer.total_score > ours.total_score
+    }
+}
@@ -0,0 +1,121 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use cmerkle::skewed_merkle_root;
+use ctypes::BlockNumber;
+use primitives::H256;
+use rlp::{Decodable, DecoderError, Encodable, RlpStream, UntrustedRlp};
+
+/// Distinguishes the chains which share a genesis but have diverged at a hard fork
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ForkId {
+    /// Hash of the genesis and the forks passed so far
+    pub hash: H256,
+    /// The next fork, or 0 if none is scheduled
+    pub next: BlockNumber,
+}
+
+impl ForkId {
+    pub fn new(genesis_hash: H256, forks: &[BlockNumber], head: BlockNumber) -> Self {
+        let hashes = fork_hashes(genesis_hash, forks);
+        let passed = forks.iter().take_while(|fork| **fork <= head).count();
+        Self {
+            hash: hashes[passed],
+            next: forks.get(passed).cloned().unwrap_or(0),
+        }
+    }
+
+    /// Checks the fork id of a peer in the way of EIP-2124
+    pub fn is_compatible(&self, remote: &ForkId, genesis_hash: H256, forks: &[BlockNumber], head: BlockNumber) -> bool {
+        let passed = forks.iter().take_while(|fork| **fork <= head).count();
+        let index = match fork_hashes(genesis_hash, forks).iter().position(|hash| *hash == remote.hash) {
+            Some(index) => index,
+            None => return false,
+        };
+        if index == passed {
+            // The peer must not have announced a fork which we've passed without it
+            remote.next == 0 || head < remote.next
+        } else if index < passed {
+            // The peer is behind, and must know the next fork
+            remote.next == forks[index]
+        } else {
+            // The peer is ahead, and has passed the forks we know
+            true
+        }
+    }
+}
+
+/// Hashes after passing each fork, starting from the genesis alone
+fn fork_hashes(genesis_hash: H256, forks: &[BlockNumber]) -> Vec<H256> {
+    let mut hashes = vec![genesis_hash];
+    for fork in forks {
+        let last = *hashes.last().expect("The genesis is always there");
+        hashes.push(skewed_merkle_root(last, vec![fork.rlp_bytes()]));
+    }
+    hashes
+}
+
+impl Encodable for ForkId {
+    fn rlp_append(&self, s: &mut RlpStream) {
+        s.begin_list(2);
+        s.append(&self.hash);
+        s.append(&self.next);
+    }
+}
+
+impl Decodable for ForkId {
+    fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
+        if rlp.item_count()? != 2 {
+            return Err(DecoderError::RlpIncorrectListLen)
+        }
+        Ok(Self {
+            hash: rlp.val_at(0)?,
+            next: rlp.val_at(1)?,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn peers_on_the_same_fork_are_compatible() {
+        let genesis_hash = H256::random();
+        let forks = vec![10, 20];
+        let ours = ForkId::new(genesis_hash, &forks, 15);
+        assert_eq!(20, ours.next);
+
+        let behind = ForkId::new(genesis_hash, &forks, 5);
+        let ahead = ForkId::new(genesis_hash, &forks, 25);
+        assert!(ours.is_compatible(&ours, genesis_hash, &forks, 15));
+        assert!(ours.is_compatible(&behind, genesis_hash, &forks, 15));
+        assert!(ours.is_compatible(&ahead, genesis_hash, &forks, 15));
+    }
+
+    #[test]
+    fn peers_without_a_passed_fork_are_incompatible() {
+        let genesis_hash = H256::random();
+        let ours = ForkId::new(genesis_hash, &[10], 15);
+
+        // The peer didn't schedule the fork at 10, and has passed it
+        let stale = ForkId::new(genesis_hash, &[], 15);
+        assert!(!ours.is_compatible(&stale, genesis_hash, &[10], 15));
+        // The peer has forked at another block
+        let diverged = ForkId::new(genesis_hash, &[12], 15);
+        assert!(!ours.is_compatible(&diverged, genesis_hash, &[10], 15));
+    }
+}
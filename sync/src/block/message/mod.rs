@@ -14,9 +14,12 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+use ckey::NetworkId;
 use primitives::{H256, U256};
 use rlp::{Decodable, DecoderError, Encodable, RlpStream, UntrustedRlp};
 
+use super::fork_id::ForkId;
+
 mod request;
 mod response;
 
@@ -32,6 +35,15 @@ const MESSAGE_ID_GET_STATE_HEAD: u8 = 0x06;
 const MESSAGE_ID_STATE_HEAD: u8 = 0x07;
 const MESSAGE_ID_GET_STATE_CHUNK: u8 = 0x08;
 const MESSAGE_ID_STATE_CHUNK: u8 = 0x09;
+const MESSAGE_ID_CHUNKED: u8 = 0x0a;
+const MESSAGE_ID_GET_HEADERS_BY_HASHES: u8 = 0x0b;
+const MESSAGE_ID_HEADERS_BY_HASHES: u8 = 0x0c;
+const MESSAGE_ID_GET_SCORE_PROOF: u8 = 0x0d;
+const MESSAGE_ID_SCORE_PROOF: u8 = 0x0e;
+const MESSAGE_ID_BATCH: u8 = 0x0f;
+const MESSAGE_ID_GET_STATUS: u8 = 0x10;
+const MESSAGE_ID_NEW_BLOCK_HASHES: u8 = 0x11;
+const MESSAGE_ID_UNAVAILABLE_BODIES: u8 = 0x12;
 
 #[derive(Debug, PartialEq)]
 pub enum Message {
@@ -39,9 +51,57 @@ pub enum Message {
         total_score: U256,
         best_hash: H256,
         genesis_hash: H256,
+        /// Older versions send only the scores and the hashes, which is decoded as `None` here.
+        /// A status whose network id is `None` is encoded in the same way, without the rest
+        network_id: Option<NetworkId>,
+        /// Soft fork features supported by the sender
+        features: Vec<u32>,
+        /// Hard forks passed by the sender, which older versions don't send and are decoded as `None`
+        fork_id: Option<ForkId>,
+    },
+    /// Asks for the current status of the receiver
+    GetStatus,
+    /// Hashes of the latest blocks of the sender, oldest first
+    NewBlockHashes(Vec<H256>),
+    /// Sent before a bodies response which lacks these bodies
+    UnavailableBodies {
+        /// Not imported yet, so they may be served later
+        transient: Vec<H256>,
+        /// Pruned, so they're never served by the sender
+        permanent: Vec<H256>,
     },
     Request(u64, RequestMessage),
     Response(u64, ResponseMessage),
+    /// A part of a large message which is split to be transferred in several frames
+    Chunked {
+        id: u64,
+        seq: u64,
+        total: u64,
+        data: Vec<u8>,
+    },
+    /// Small messages to the same peer which are sent in a single frame, and can't contain another batch
+    Batch(Vec<Message>),
+}
+
+impl Message {
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            Message::Status {
+                ..
+            } => "Status",
+            Message::GetStatus => "GetStatus",
+            Message::NewBlockHashes(..) => "NewBlockHashes",
+            Message::UnavailableBodies {
+                ..
+            } => "UnavailableBodies",
+            Message::Request(_, request) => request.type_name(),
+            Message::Response(_, response) => response.type_name(),
+            Message::Chunked {
+                ..
+            } => "Chunked",
+            Message::Batch(..) => "Batch",
+        }
+    }
 }
 
 impl Encodable for Message {
@@ -51,14 +111,54 @@ impl Encodable for Message {
                 total_score,
                 best_hash,
                 genesis_hash,
+                network_id,
+                features,
+                fork_id,
             } => {
                 s.begin_list(2);
                 s.append(&MESSAGE_ID_STATUS);
 
-                s.begin_list(3);
+                let network_id = match network_id {
+                    Some(network_id) => network_id,
+                    None => {
+                        s.begin_list(3);
+                        s.append(total_score);
+                        s.append(best_hash);
+                        s.append(genesis_hash);
+                        return
+                    }
+                };
+                s.begin_list(if fork_id.is_some() {
+                    6
+                } else {
+                    5
+                });
                 s.append(total_score);
                 s.append(best_hash);
                 s.append(genesis_hash);
+                s.append(network_id);
+                s.append_list(features);
+                if let Some(fork_id) = fork_id {
+                    s.append(fork_id);
+                }
+            }
+            Message::GetStatus => {
+                s.begin_list(1);
+                s.append(&MESSAGE_ID_GET_STATUS);
+            }
+            Message::NewBlockHashes(hashes) => {
+                s.begin_list(2);
+                s.append(&MESSAGE_ID_NEW_BLOCK_HASHES);
+                s.append_list(hashes);
+            }
+            Message::UnavailableBodies {
+                transient,
+                permanent,
+            } => {
+                s.begin_list(3);
+                s.append(&MESSAGE_ID_UNAVAILABLE_BODIES);
+                s.append_list(transient);
+                s.append_list(permanent);
             }
             Message::Request(request_id, request) => {
                 s.begin_list(3);
@@ -72,6 +172,26 @@ impl Encodable for Message {
                 s.append(response_id);
                 s.append(response);
             }
+            Message::Chunked {
+                id,
+                seq,
+                total,
+                data,
+            } => {
+                s.begin_list(2);
+                s.append(&MESSAGE_ID_CHUNKED);
+
+                s.begin_list(4);
+                s.append(id);
+                s.append(seq);
+                s.append(total);
+                s.append(data);
+            }
+            Message::Batch(messages) => {
+                s.begin_list(2);
+                s.append(&MESSAGE_ID_BATCH);
+                s.append_list(messages);
+            }
         }
     }
 }
@@ -85,7 +205,19 @@ impl Decodable for Message {
             }
             let message = rlp.at(1)?;
 
-            if message.item_count()? != 3 {
+            let item_count = message.item_count()?;
+            // Older versions send only the scores and the hashes
+            if item_count == 3 {
+                return Ok(Message::Status {
+                    total_score: message.val_at(0)?,
+                    best_hash: message.val_at(1)?,
+                    genesis_hash: message.val_at(2)?,
+                    network_id: None,
+                    features: Vec::new(),
+                    fork_id: None,
+                })
+            }
+            if item_count != 5 && item_count != 6 {
                 return Err(DecoderError::RlpIncorrectListLen)
             }
 
@@ -93,7 +225,61 @@ impl Decodable for Message {
                 total_score: message.val_at(0)?,
                 best_hash: message.val_at(1)?,
                 genesis_hash: message.val_at(2)?,
+                network_id: Some(message.val_at(3)?),
+                features: message.list_at(4)?,
+                fork_id: if item_count == 6 {
+                    Some(message.val_at(5)?)
+                } else {
+                    None
+                },
+            })
+        } else if id == MESSAGE_ID_GET_STATUS {
+            if rlp.item_count()? != 1 {
+                return Err(DecoderError::RlpIncorrectListLen)
+            }
+            Ok(Message::GetStatus)
+        } else if id == MESSAGE_ID_NEW_BLOCK_HASHES {
+            if rlp.item_count()? != 2 {
+                return Err(DecoderError::RlpIncorrectListLen)
+            }
+            Ok(Message::NewBlockHashes(rlp.list_at(1)?))
+        } else if id == MESSAGE_ID_UNAVAILABLE_BODIES {
+            if rlp.item_count()? != 3 {
+                return Err(DecoderError::RlpIncorrectListLen)
+            }
+            Ok(Message::UnavailableBodies {
+                transient: rlp.list_at(1)?,
+                permanent: rlp.list_at(2)?,
             })
+        } else if id == MESSAGE_ID_CHUNKED {
+            if rlp.item_count()? != 2 {
+                return Err(DecoderError::RlpIncorrectListLen)
+            }
+            let message = rlp.at(1)?;
+
+            if message.item_count()? != 4 {
+                return Err(DecoderError::RlpIncorrectListLen)
+            }
+
+            Ok(Message::Chunked {
+                id: message.val_at(0)?,
+                seq: message.val_at(1)?,
+                total: message.val_at(2)?,
+                data: message.val_at(3)?,
+            })
+        } else if id == MESSAGE_ID_BATCH {
+            if rlp.item_count()? != 2 {
+                return Err(DecoderError::RlpIncorrectListLen)
+            }
+            let messages: Vec<Message> = rlp.list_at(1)?;
+            let is_nested = messages.iter().any(|message| match message {
+                Message::Batch(..) => true,
+                _ => false,
+            });
+            if is_nested {
+                return Err(DecoderError::Custom("Nested batch detected"))
+            }
+            Ok(Message::Batch(messages))
         } else {
             if rlp.item_count()? != 3 {
                 return Err(DecoderError::RlpIncorrectListLen)
@@ -104,10 +290,15 @@ impl Decodable for Message {
                 MESSAGE_ID_GET_HEADERS
                 | MESSAGE_ID_GET_BODIES
                 | MESSAGE_ID_GET_STATE_HEAD
-                | MESSAGE_ID_GET_STATE_CHUNK => Ok(Message::Request(request_id, RequestMessage::decode(id, &message)?)),
-                MESSAGE_ID_HEADERS | MESSAGE_ID_BODIES | MESSAGE_ID_STATE_HEAD | MESSAGE_ID_STATE_CHUNK => {
-                    Ok(Message::Response(request_id, ResponseMessage::decode(id, &message)?))
-                }
+                | MESSAGE_ID_GET_STATE_CHUNK
+                | MESSAGE_ID_GET_HEADERS_BY_HASHES
+                | MESSAGE_ID_GET_SCORE_PROOF => Ok(Message::Request(request_id, RequestMessage::decode(id, &message)?)),
+                MESSAGE_ID_HEADERS
+                | MESSAGE_ID_BODIES
+                | MESSAGE_ID_STATE_HEAD
+                | MESSAGE_ID_STATE_CHUNK
+                | MESSAGE_ID_HEADERS_BY_HASHES
+                | MESSAGE_ID_SCORE_PROOF => Ok(Message::Response(request_id, ResponseMessage::decode(id, &message)?)),
                 _ => Err(DecoderError::Custom("Unknown message id detected")),
             }
         }
@@ -124,6 +315,66 @@ mod tests {
             total_score: U256::default(),
             best_hash: H256::default(),
             genesis_hash: H256::default(),
+            network_id: Some(NetworkId::default()),
+            features: vec![1, 2],
+            fork_id: None,
+        });
+        rlp_encode_and_decode_test!(Message::Status {
+            total_score: U256::default(),
+            best_hash: H256::default(),
+            genesis_hash: H256::default(),
+            network_id: Some(NetworkId::default()),
+            features: Vec::new(),
+            fork_id: Some(ForkId {
+                hash: H256::random(),
+                next: 10,
+            }),
+        });
+    }
+
+    #[test]
+    fn legacy_status_message_rlp() {
+        let total_score = U256::from(10);
+        let best_hash = H256::random();
+        let genesis_hash = H256::random();
+        let mut s = RlpStream::new_list(2);
+        s.append(&MESSAGE_ID_STATUS);
+        s.begin_list(3);
+        s.append(&total_score);
+        s.append(&best_hash);
+        s.append(&genesis_hash);
+
+        let legacy = s.out();
+        let decoded: Message = UntrustedRlp::new(&legacy).as_val().unwrap();
+        assert_eq!(
+            Message::Status {
+                total_score,
+                best_hash,
+                genesis_hash,
+                network_id: None,
+                features: Vec::new(),
+                fork_id: None,
+            },
+            decoded
+        );
+        assert_eq!(legacy.to_vec(), decoded.rlp_bytes().to_vec());
+    }
+
+    #[test]
+    fn get_status_message_rlp() {
+        rlp_encode_and_decode_test!(Message::GetStatus);
+    }
+
+    #[test]
+    fn new_block_hashes_message_rlp() {
+        rlp_encode_and_decode_test!(Message::NewBlockHashes(vec![H256::random(), H256::random()]));
+    }
+
+    #[test]
+    fn unavailable_bodies_message_rlp() {
+        rlp_encode_and_decode_test!(Message::UnavailableBodies {
+            transient: vec![H256::random()],
+            permanent: vec![H256::random(), H256::random()],
         });
     }
 
@@ -133,6 +384,46 @@ mod tests {
         rlp_encode_and_decode_test!(Message::Request(request_id, RequestMessage::Bodies(vec![])));
     }
 
+    #[test]
+    fn request_headers_by_hashes_message_rlp() {
+        let request_id = 10;
+        rlp_encode_and_decode_test!(Message::Request(
+            request_id,
+            RequestMessage::HeadersByHashes(vec![H256::random(), H256::random()])
+        ));
+    }
+
+    #[test]
+    fn chunked_message_rlp() {
+        rlp_encode_and_decode_test!(Message::Chunked {
+            id: 1,
+            seq: 2,
+            total: 3,
+            data: vec![1, 2, 3],
+        });
+    }
+
+    #[test]
+    fn batch_message_rlp() {
+        rlp_encode_and_decode_test!(Message::Batch(vec![
+            Message::Status {
+                total_score: U256::default(),
+                best_hash: H256::default(),
+                genesis_hash: H256::default(),
+                network_id: Some(NetworkId::default()),
+                features: vec![],
+                fork_id: None,
+            },
+            Message::Request(10, RequestMessage::Bodies(vec![H256::random()])),
+        ]));
+    }
+
+    #[test]
+    fn nested_batch_is_rejected() {
+        let nested = Message::Batch(vec![Message::Batch(vec![Message::Request(10, RequestMessage::Bodies(vec![]))])]);
+        assert!(UntrustedRlp::new(&nested.rlp_bytes()).as_val::<Message>().is_err());
+    }
+
     #[test]
     fn request_state_head_rlp() {
         let request_id = 10;
@@ -20,9 +20,13 @@ use rlp::{DecoderError, Encodable, RlpStream, UntrustedRlp};
 
 #[derive(Clone, Debug, PartialEq)]
 pub enum RequestMessage {
+    /// Headers from the start number, skipping `skip` headers between each of them,
+    /// in descending order if `reverse` is set
     Headers {
         start_number: BlockNumber,
         max_count: u64,
+        skip: u64,
+        reverse: bool,
     },
     Bodies(Vec<H256>),
     StateHead(H256),
@@ -30,6 +34,10 @@ pub enum RequestMessage {
         block_hash: H256,
         tree_root: H256,
     },
+    /// Headers of the given blocks, which don't need to be contiguous
+    HeadersByHashes(Vec<H256>),
+    /// Headers proving the total score of the given best block
+    ScoreProof(H256),
 }
 
 impl Encodable for RequestMessage {
@@ -38,10 +46,22 @@ impl Encodable for RequestMessage {
             RequestMessage::Headers {
                 start_number,
                 max_count,
+                skip,
+                reverse,
             } => {
-                s.begin_list(2);
+                // Older versions can decode contiguous requests in ascending order
+                let is_contiguous = *skip == 0 && !*reverse;
+                s.begin_list(if is_contiguous {
+                    2
+                } else {
+                    4
+                });
                 s.append(start_number);
                 s.append(max_count);
+                if !is_contiguous {
+                    s.append(skip);
+                    s.append(reverse);
+                }
             }
             RequestMessage::Bodies(hashes) => {
                 s.append_list(hashes);
@@ -58,6 +78,13 @@ impl Encodable for RequestMessage {
                 s.append(block_hash);
                 s.append(tree_root);
             }
+            RequestMessage::HeadersByHashes(hashes) => {
+                s.append_list(hashes);
+            }
+            RequestMessage::ScoreProof(best_hash) => {
+                s.begin_list(1);
+                s.append(best_hash);
+            }
         };
     }
 }
@@ -73,19 +100,47 @@ impl RequestMessage {
             RequestMessage::StateChunk {
                 ..
             } => super::MESSAGE_ID_GET_STATE_CHUNK,
+            RequestMessage::HeadersByHashes(..) => super::MESSAGE_ID_GET_HEADERS_BY_HASHES,
+            RequestMessage::ScoreProof(..) => super::MESSAGE_ID_GET_SCORE_PROOF,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            RequestMessage::Headers {
+                ..
+            } => "RequestHeaders",
+            RequestMessage::Bodies(..) => "RequestBodies",
+            RequestMessage::StateHead(..) => "RequestStateHead",
+            RequestMessage::StateChunk {
+                ..
+            } => "RequestStateChunk",
+            RequestMessage::HeadersByHashes(..) => "RequestHeadersByHashes",
+            RequestMessage::ScoreProof(..) => "RequestScoreProof",
         }
     }
 
     pub fn decode(id: u8, rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
         let message = match id {
             super::MESSAGE_ID_GET_HEADERS => {
-                if rlp.item_count()? != 2 {
+                let item_count = rlp.item_count()?;
+                if item_count == 2 {
+                    RequestMessage::Headers {
+                        start_number: rlp.val_at(0)?,
+                        max_count: rlp.val_at(1)?,
+                        skip: 0,
+                        reverse: false,
+                    }
+                } else if item_count == 4 {
+                    RequestMessage::Headers {
+                        start_number: rlp.val_at(0)?,
+                        max_count: rlp.val_at(1)?,
+                        skip: rlp.val_at(2)?,
+                        reverse: rlp.val_at(3)?,
+                    }
+                } else {
                     return Err(DecoderError::RlpIncorrectListLen)
                 }
-                RequestMessage::Headers {
-                    start_number: rlp.val_at(0)?,
-                    max_count: rlp.val_at(1)?,
-                }
             }
             super::MESSAGE_ID_GET_BODIES => RequestMessage::Bodies(rlp.as_list()?),
             super::MESSAGE_ID_GET_STATE_HEAD => {
@@ -103,6 +158,13 @@ impl RequestMessage {
                     tree_root: rlp.val_at(1)?,
                 }
             }
+            super::MESSAGE_ID_GET_HEADERS_BY_HASHES => RequestMessage::HeadersByHashes(rlp.as_list()?),
+            super::MESSAGE_ID_GET_SCORE_PROOF => {
+                if rlp.item_count()? != 1 {
+                    return Err(DecoderError::RlpIncorrectListLen)
+                }
+                RequestMessage::ScoreProof(rlp.val_at(0)?)
+            }
             _ => return Err(DecoderError::Custom("Unknown message id detected")),
         };
 
@@ -113,7 +175,7 @@ impl RequestMessage {
 #[cfg(test)]
 mod tests {
     use primitives::H256;
-    use rlp::{Encodable, UntrustedRlp};
+    use rlp::{Encodable, RlpStream, UntrustedRlp};
 
     use super::RequestMessage;
 
@@ -127,10 +189,28 @@ mod tests {
         let message = RequestMessage::Headers {
             start_number: 100,
             max_count: 100,
+            skip: 2,
+            reverse: true,
         };
         assert_eq!(message, decode_bytes(message.message_id(), message.rlp_bytes().as_ref()));
     }
 
+    #[test]
+    fn legacy_request_headers_message_is_decoded() {
+        let mut s = RlpStream::new_list(2);
+        s.append(&100u64);
+        s.append(&10u64);
+        let bytes = s.out().to_vec();
+        let message = RequestMessage::Headers {
+            start_number: 100,
+            max_count: 10,
+            skip: 0,
+            reverse: false,
+        };
+        assert_eq!(message, decode_bytes(message.message_id(), &bytes));
+        assert_eq!(bytes, message.rlp_bytes().to_vec());
+    }
+
     #[test]
     fn request_bodies_message_rlp() {
         let message = RequestMessage::Bodies(vec![H256::default()]);
@@ -151,4 +231,16 @@ mod tests {
         };
         assert_eq!(message, decode_bytes(message.message_id(), message.rlp_bytes().as_ref()));
     }
+
+    #[test]
+    fn request_score_proof_message_rlp() {
+        let message = RequestMessage::ScoreProof(H256::random());
+        assert_eq!(message, decode_bytes(message.message_id(), message.rlp_bytes().as_ref()));
+    }
+
+    #[test]
+    fn request_headers_by_hashes_message_rlp() {
+        let message = RequestMessage::HeadersByHashes(vec![H256::default(), H256::random()]);
+        assert_eq!(message, decode_bytes(message.message_id(), message.rlp_bytes().as_ref()));
+    }
 }
@@ -23,6 +23,9 @@ pub enum ResponseMessage {
     Bodies(Vec<Vec<UnverifiedParcel>>),
     StateHead(Vec<u8>),
     StateChunk(Vec<u8>),
+    HeadersByHashes(Vec<Header>),
+    /// Headers from a block the requester is expected to know up to the requested best block
+    ScoreProof(Vec<Header>),
 }
 
 impl Encodable for ResponseMessage {
@@ -45,6 +48,12 @@ impl Encodable for ResponseMessage {
                 s.begin_list(1);
                 s.append(bytes);
             }
+            ResponseMessage::HeadersByHashes(headers) => {
+                s.append_list(headers);
+            }
+            ResponseMessage::ScoreProof(headers) => {
+                s.append_list(headers);
+            }
         };
     }
 }
@@ -60,6 +69,19 @@ impl ResponseMessage {
             ResponseMessage::StateChunk {
                 ..
             } => super::MESSAGE_ID_STATE_CHUNK,
+            ResponseMessage::HeadersByHashes(..) => super::MESSAGE_ID_HEADERS_BY_HASHES,
+            ResponseMessage::ScoreProof(..) => super::MESSAGE_ID_SCORE_PROOF,
+        }
+    }
+
+    pub fn type_name(&self) -> &'static str {
+        match self {
+            ResponseMessage::Headers(..) => "Headers",
+            ResponseMessage::Bodies(..) => "Bodies",
+            ResponseMessage::StateHead(..) => "StateHead",
+            ResponseMessage::StateChunk(..) => "StateChunk",
+            ResponseMessage::HeadersByHashes(..) => "HeadersByHashes",
+            ResponseMessage::ScoreProof(..) => "ScoreProof",
         }
     }
 
@@ -85,6 +107,8 @@ impl ResponseMessage {
                 }
                 ResponseMessage::StateChunk(rlp.val_at(0)?)
             }
+            super::MESSAGE_ID_HEADERS_BY_HASHES => ResponseMessage::HeadersByHashes(rlp.as_list()?),
+            super::MESSAGE_ID_SCORE_PROOF => ResponseMessage::ScoreProof(rlp.as_list()?),
             _ => return Err(DecoderError::Custom("Unknown message id detected")),
         };
 
@@ -127,6 +151,30 @@ mod tests {
         assert_eq!(message, decode_bytes(message.message_id(), message.rlp_bytes().as_ref()));
     }
 
+    #[test]
+    fn headers_by_hashes_message_rlp() {
+        let mut header = Header::default();
+        header.set_number(10);
+        let headers = vec![Header::default(), header];
+        headers.iter().for_each(|header| {
+            header.hash();
+        });
+
+        let message = ResponseMessage::HeadersByHashes(headers);
+        assert_eq!(message, decode_bytes(message.message_id(), message.rlp_bytes().as_ref()));
+    }
+
+    #[test]
+    fn score_proof_message_rlp() {
+        let headers = vec![Header::default()];
+        headers.iter().for_each(|header| {
+            header.hash();
+        });
+
+        let message = ResponseMessage::ScoreProof(headers);
+        assert_eq!(message, decode_bytes(message.message_id(), message.rlp_bytes().as_ref()));
+    }
+
     #[test]
     fn state_chunk_message_rlp() {
         let message = ResponseMessage::StateChunk(vec![]);
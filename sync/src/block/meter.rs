@@ -0,0 +1,151 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, Instant};
+
+/// Measures the transferred bytes per second over a sliding window
+pub struct BandwidthMeter {
+    window: Duration,
+    records: VecDeque<(Instant, usize)>,
+    total_bytes: usize,
+}
+
+impl BandwidthMeter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            records: VecDeque::new(),
+            total_bytes: 0,
+        }
+    }
+
+    pub fn record(&mut self, bytes: usize, now: Instant) {
+        self.remove_expired(now);
+        self.records.push_back((now, bytes));
+        self.total_bytes += bytes;
+    }
+
+    /// Bytes per second
+    pub fn rate(&mut self, now: Instant) -> u64 {
+        let window_secs = ::std::cmp::max(self.window.as_secs(), 1);
+        self.total(now) as u64 / window_secs
+    }
+
+    /// Bytes recorded in the window
+    pub fn total(&mut self, now: Instant) -> usize {
+        self.remove_expired(now);
+        self.total_bytes
+    }
+
+    pub fn window(&self) -> Duration {
+        self.window
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        while let Some(&(time, bytes)) = self.records.front() {
+            if now.duration_since(time) < self.window {
+                break
+            }
+            self.records.pop_front();
+            self.total_bytes -= bytes;
+        }
+    }
+}
+
+/// Counts the messages of each type over a sliding window
+pub struct MessageCounter {
+    window: Duration,
+    records: VecDeque<(Instant, &'static str)>,
+    counts: HashMap<&'static str, usize>,
+}
+
+impl MessageCounter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            records: VecDeque::new(),
+            counts: HashMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, type_name: &'static str, now: Instant) {
+        self.remove_expired(now);
+        self.records.push_back((now, type_name));
+        *self.counts.entry(type_name).or_insert(0) += 1;
+    }
+
+    /// Messages of each type recorded in the window
+    pub fn counts(&mut self, now: Instant) -> HashMap<&'static str, usize> {
+        self.remove_expired(now);
+        self.counts.clone()
+    }
+
+    fn remove_expired(&mut self, now: Instant) {
+        while let Some(&(time, type_name)) = self.records.front() {
+            if now.duration_since(time) < self.window {
+                break
+            }
+            self.records.pop_front();
+            let is_last = {
+                let count = self.counts.get_mut(type_name).expect("Recorded type must be counted");
+                *count -= 1;
+                *count == 0
+            };
+            if is_last {
+                self.counts.remove(type_name);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use super::{BandwidthMeter, MessageCounter};
+
+    #[test]
+    fn rate_over_window() {
+        let mut meter = BandwidthMeter::new(Duration::from_secs(10));
+        let now = Instant::now();
+        assert_eq!(0, meter.rate(now));
+
+        meter.record(1000, now);
+        meter.record(2000, now + Duration::from_secs(1));
+        assert_eq!(300, meter.rate(now + Duration::from_secs(2)));
+
+        assert_eq!(200, meter.rate(now + Duration::from_millis(10_500)));
+        assert_eq!(0, meter.rate(now + Duration::from_secs(11)));
+    }
+
+    #[test]
+    fn message_counts_over_window() {
+        let mut counter = MessageCounter::new(Duration::from_secs(10));
+        let now = Instant::now();
+        counter.record("Status", now);
+        counter.record("Headers", now + Duration::from_secs(1));
+        counter.record("Headers", now + Duration::from_secs(2));
+
+        let counts = counter.counts(now + Duration::from_secs(3));
+        assert_eq!(Some(&1), counts.get("Status"));
+        assert_eq!(Some(&2), counts.get("Headers"));
+
+        let counts = counter.counts(now + Duration::from_millis(11_500));
+        assert_eq!(None, counts.get("Status"));
+        assert_eq!(Some(&1), counts.get("Headers"));
+    }
+}
@@ -14,8 +14,24 @@
 // You should have received a copy of the GNU Affero General Public License
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
+mod chunk;
+mod config;
 mod downloader;
 mod extension;
+mod fork_choice;
+mod fork_id;
 mod message;
+mod meter;
+mod seal;
+#[cfg(test)]
+mod testing;
 
-pub use self::extension::Extension as BlockSyncExtension;
+pub use self::config::{Config as BlockSyncConfig, VerificationLevel as BlockVerificationLevel};
+pub use self::extension::{
+    Extension as BlockSyncExtension, ForkChoiceEntry, MessageHistogram, PeerState, PeerTraffic, RequestLogEntry,
+    StallReason as BlockSyncStallReason, SyncMetrics as BlockSyncMetrics, SyncPhase as BlockSyncPhase,
+    SyncStatus as BlockSyncStatus,
+};
+pub use self::fork_choice::{ChainTip, ForkChoice, HighestScore};
+pub use self::fork_id::ForkId;
+pub use self::seal::SealVerifier;
@@ -0,0 +1,175 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+
+use ccore::{BlockError, Header};
+use parking_lot::Mutex;
+
+/// Verifies seals of downloaded headers before they are imported
+pub trait SealVerifier: Send + Sync {
+    fn verify_seal(&self, header: &Header) -> Result<(), BlockError>;
+    /// Checks that the score of the header is what the engine expects after the parent
+    fn verify_score(&self, header: &Header, parent: &Header) -> Result<(), BlockError>;
+}
+
+struct Job {
+    headers: Vec<Header>,
+    offset: usize,
+    result: Sender<Option<(usize, BlockError)>>,
+}
+
+/// Threads verifying seals, which are spawned once and kept while the pool lives
+pub struct SealVerifierPool {
+    jobs: Mutex<Option<Sender<Job>>>,
+    workers: Vec<JoinHandle<()>>,
+}
+
+impl SealVerifierPool {
+    pub fn new(verifier: Arc<SealVerifier>, size: usize) -> Self {
+        let (sender, receiver) = channel::<Job>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let workers = (0..::std::cmp::max(size, 1))
+            .map(|index| {
+                let verifier = Arc::clone(&verifier);
+                let receiver = Arc::clone(&receiver);
+                thread::Builder::new()
+                    .name(format!("Seal Verifier #{}", index))
+                    .spawn(move || work(&*verifier, &receiver))
+                    .expect("Seal verifier thread must be spawned")
+            })
+            .collect();
+        Self {
+            jobs: Mutex::new(Some(sender)),
+            workers,
+        }
+    }
+
+    /// Verifies the seals in chunks on the workers, and returns the index of the first header with an invalid seal.
+    /// A chunk whose verification panicked is taken as invalid from its first header
+    pub fn verify(&self, headers: &[Header]) -> Result<(), (usize, BlockError)> {
+        if headers.is_empty() {
+            return Ok(())
+        }
+        let length = (headers.len() + self.workers.len() - 1) / self.workers.len();
+        let results: Vec<_> = {
+            let jobs = self.jobs.lock();
+            let jobs = jobs.as_ref().expect("Pool is closed only when dropped");
+            headers
+                .chunks(length)
+                .enumerate()
+                .map(|(chunk_index, chunk)| {
+                    let offset = chunk_index * length;
+                    let (sender, receiver) = channel();
+                    // The result is received as an error if the job is dropped
+                    let _ = jobs.send(Job {
+                        headers: chunk.to_vec(),
+                        offset,
+                        result: sender,
+                    });
+                    (offset, receiver)
+                })
+                .collect()
+        };
+
+        // The first error found belongs to the earliest header
+        for (offset, receiver) in results {
+            match receiver.recv() {
+                Ok(None) => {}
+                Ok(Some(error)) => return Err(error),
+                Err(_) => return Err((offset, BlockError::InvalidSeal)),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for SealVerifierPool {
+    fn drop(&mut self) {
+        // Workers stop once the queue is closed
+        self.jobs.lock().take();
+        for worker in self.workers.drain(..) {
+            let _ = worker.join();
+        }
+    }
+}
+
+fn work(verifier: &SealVerifier, jobs: &Mutex<Receiver<Job>>) {
+    loop {
+        let job = match jobs.lock().recv() {
+            Ok(job) => job,
+            Err(_) => return,
+        };
+        let result = panic::catch_unwind(AssertUnwindSafe(|| {
+            job.headers
+                .iter()
+                .enumerate()
+                .filter_map(|(index, header)| verifier.verify_seal(header).err().map(|err| (job.offset + index, err)))
+                .next()
+        }));
+        let result = result.unwrap_or_else(|_| {
+            cwarn!(SYNC, "Seal verification of headers from #{} panicked", job.offset);
+            Some((job.offset, BlockError::InvalidSeal))
+        });
+        // The caller may have stopped waiting after an earlier error
+        let _ = job.result.send(result);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ccore::{BlockError, Header};
+
+    use super::{SealVerifier, SealVerifierPool};
+
+    struct PanickingVerifier;
+
+    impl SealVerifier for PanickingVerifier {
+        fn verify_seal(&self, header: &Header) -> Result<(), BlockError> {
+            if header.number() == 5 {
+                panic!("Verifier bug");
+            }
+            Ok(())
+        }
+
+        fn verify_score(&self, _header: &Header, _parent: &Header) -> Result<(), BlockError> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn panicking_verification_fails_its_chunk_and_keeps_the_pool() {
+        let pool = SealVerifierPool::new(Arc::new(PanickingVerifier), 2);
+        let headers: Vec<_> = (0..8)
+            .map(|number| {
+                let mut header = Header::default();
+                header.set_number(number);
+                header
+            })
+            .collect();
+
+        match pool.verify(&headers) {
+            Err((4, BlockError::InvalidSeal)) => {}
+            result => panic!("Unexpected result: {:?}", result),
+        }
+        assert_eq!(Ok(()), pool.verify(&headers[..5]));
+    }
+}
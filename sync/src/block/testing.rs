@@ -0,0 +1,128 @@
+// Copyright 2018 Kodebox, Inc.
+// This file is part of CodeChain.
+//
+// This program is free software: you can redistribute it and/or modify
+// it under the terms of the GNU Affero General Public License as
+// published by the Free Software Foundation, either version 3 of the
+// License, or (at your option) any later version.
+//
+// This program is distributed in the hope that it will be useful,
+// but WITHOUT ANY WARRANTY; without even the implied warranty of
+// MERCHANTABILITY or FITNESS FOR A PARTICULAR PURPOSE.  See the
+// GNU Affero General Public License for more details.
+//
+// You should have received a copy of the GNU Affero General Public License
+// along with this program.  If not, see <https://www.gnu.org/licenses/>.
+
+//! In-process network of sync extensions for end-to-end tests
+
+use std::sync::Arc;
+
+use ccore::TestBlockChainClient;
+use cnetwork::{NetworkExtension, NodeId, SocketAddr, TestNetworkCall, TestNetworkClient};
+
+use super::config::Config;
+use super::extension::{Extension, SYNC_TIMER_TOKEN};
+
+const MAX_DELIVERY_ROUNDS: usize = 1000;
+
+pub struct Node {
+    pub id: NodeId,
+    pub client: Arc<TestBlockChainClient>,
+    pub extension: Arc<Extension>,
+    network: TestNetworkClient,
+}
+
+/// Nodes connected to each other over an in-memory message bus
+pub struct TestNetwork {
+    nodes: Vec<Node>,
+}
+
+impl TestNetwork {
+    /// Creates a fully connected network with a node for each of the given chain heights
+    pub fn new(heights: &[usize]) -> Self {
+        let nodes: Vec<_> = heights
+            .iter()
+            .enumerate()
+            .map(|(index, height)| {
+                let client = Arc::new(TestBlockChainClient::new());
+                client.add_blocks(*height, 0);
+                let extension = Extension::new(client.clone(), Config::default());
+                client.add_notify(extension.clone());
+                let mut network = TestNetworkClient::new();
+                network.register_extension(extension.clone());
+                Node {
+                    id: SocketAddr::v4(127, 0, 0, 1, 3485 + index as u16).into(),
+                    client,
+                    extension,
+                    network,
+                }
+            })
+            .collect();
+
+        let test_network = Self {
+            nodes,
+        };
+        for node in &test_network.nodes {
+            for peer in test_network.nodes.iter().filter(|peer| peer.id != node.id) {
+                node.network.add_node(node.extension.name(), peer.id);
+            }
+        }
+        test_network.deliver_messages();
+        test_network
+    }
+
+    pub fn node(&self, index: usize) -> &Node {
+        &self.nodes[index]
+    }
+
+    /// Fires the sync timer of every node and delivers all the messages it causes
+    pub fn tick(&self) {
+        for node in &self.nodes {
+            node.network.call_timeout(node.extension.name(), SYNC_TIMER_TOKEN);
+        }
+        self.deliver_messages();
+    }
+
+    fn deliver_messages(&self) {
+        for _ in 0..MAX_DELIVERY_ROUNDS {
+            let mut delivered = false;
+            for node in &self.nodes {
+                while let Some(call) = node.network.pop_call(node.extension.name()) {
+                    if let TestNetworkCall::Send(to, message) = call {
+                        let peer = self.nodes.iter().find(|peer| peer.id == to).expect("Peer must be in the network");
+                        peer.network.send_message(peer.extension.name(), node.id, &message);
+                        delivered = true;
+                    }
+                }
+            }
+            if !delivered {
+                return
+            }
+        }
+        panic!("Messages are still exchanged after {} rounds", MAX_DELIVERY_ROUNDS);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use ccore::ChainInfo;
+
+    use super::TestNetwork;
+
+    #[test]
+    fn node_at_genesis_catches_up() {
+        let network = TestNetwork::new(&[0, 10]);
+        let (behind, ahead) = (network.node(0), network.node(1));
+        assert_ne!(behind.client.chain_info().best_block_hash, ahead.client.chain_info().best_block_hash);
+
+        for _ in 0..5 {
+            network.tick();
+        }
+
+        let chain_info = behind.client.chain_info();
+        assert_eq!(10, chain_info.best_block_number);
+        assert_eq!(ahead.client.chain_info().best_block_hash, chain_info.best_block_hash);
+        assert_eq!(ahead.client.chain_info().total_score, chain_info.total_score);
+    }
+}
@@ -17,6 +17,7 @@
 extern crate parking_lot;
 
 extern crate codechain_core as ccore;
+extern crate codechain_key as ckey;
 extern crate codechain_merkle as cmerkle;
 #[macro_use]
 extern crate codechain_logger as clogger;
@@ -27,6 +28,7 @@ extern crate codechain_types as ctypes;
 extern crate kvdb;
 #[macro_use]
 extern crate log;
+extern crate lru_cache;
 extern crate primitives;
 extern crate rand;
 #[cfg_attr(test, macro_use)]
@@ -38,9 +40,10 @@ mod block;
 mod parcel;
 mod snapshot;
 
-pub use self::block::BlockSyncExtension;
+pub use self::block::{
+    BlockSyncConfig, BlockSyncExtension, BlockSyncMetrics, BlockSyncPhase, BlockSyncStallReason, BlockSyncStatus,
+    BlockVerificationLevel, ChainTip, ForkChoice, ForkChoiceEntry, ForkId, HighestScore, MessageHistogram, PeerState,
+    PeerTraffic, RequestLogEntry, SealVerifier,
+};
 pub use self::parcel::ParcelSyncExtension;
 pub use self::snapshot::SnapshotService;
-
-#[cfg(test)]
-extern crate codechain_key as ckey;
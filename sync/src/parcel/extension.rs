@@ -29,6 +29,7 @@ use super::message::Message;
 const BROADCAST_TIMER_TOKEN: TimerToken = 0;
 const BROADCAST_TIMER_INTERVAL: i64 = 1000;
 const MAX_HISTORY_SIZE: usize = 100;
+const MAX_REQUESTED_PARCELS: usize = 1024;
 
 struct Peer {
     history_set: HashSet<H256>,
@@ -125,6 +126,7 @@ impl NetworkExtension for Extension {
                         cwarn!(SYNC_PARCEL, "Message from {} but it's already removed", token);
                     }
                 }
+                Message::GetParcels(hashes) => self.on_get_parcels(token, hashes),
             }
         } else {
             cwarn!(SYNC_PARCEL, "Invalid message from peer {}", token);
@@ -145,6 +147,29 @@ impl Extension {
         api.as_ref().expect("Api must exist").send(token, &message.rlp_bytes());
     }
 
+    /// Parcels which aren't in the pool are left out of the response, and so are the hashes beyond the limit
+    fn on_get_parcels(&self, token: &NodeId, hashes: Vec<H256>) {
+        if hashes.len() > MAX_REQUESTED_PARCELS {
+            cdebug!(
+                SYNC_PARCEL,
+                "Only {} of {} parcels requested by {} are served",
+                MAX_REQUESTED_PARCELS,
+                hashes.len(),
+                token
+            );
+        }
+        let hashes: HashSet<_> = hashes.into_iter().take(MAX_REQUESTED_PARCELS).collect();
+        let parcels: Vec<_> = self
+            .client
+            .ready_parcels()
+            .into_iter()
+            .filter(|parcel| hashes.contains(&parcel.hash()))
+            .map(|signed| signed.deconstruct().0)
+            .collect();
+        cdebug!(SYNC_PARCEL, "Send {} of {} requested parcels to {}", parcels.len(), hashes.len(), token);
+        self.send_message(token, Message::Parcels(parcels));
+    }
+
     fn random_broadcast(&self) {
         let parcels = self.client.ready_parcels();
         if parcels.is_empty() {
@@ -171,3 +196,65 @@ impl Extension {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+
+    use ccore::TestBlockChainClient;
+    use cnetwork::{NetworkExtension, NodeId, SocketAddr, TestNetworkCall, TestNetworkClient};
+    use primitives::H256;
+    use rlp::{Encodable, UntrustedRlp};
+
+    use super::super::message::Message;
+    use super::{Extension, MAX_REQUESTED_PARCELS};
+
+    #[test]
+    fn requested_parcel_is_served_from_pool() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let hash = client.insert_parcel_to_pool();
+        let extension = Extension::new(client.clone());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer: NodeId = SocketAddr::v4(127, 0, 0, 1, 3485).into();
+        extension.on_node_added(&peer, 0);
+        extension.on_message(&peer, &Message::GetParcels(vec![hash, H256::random()]).rlp_bytes());
+
+        let mut served = None;
+        while let Some(call) = network.pop_call("parcel-propagation") {
+            if let TestNetworkCall::Send(id, bytes) = call {
+                if let Ok(Message::Parcels(parcels)) = UntrustedRlp::new(&bytes).as_val() {
+                    assert_eq!(peer, id);
+                    served = Some(parcels.iter().map(|parcel| parcel.hash()).collect::<Vec<_>>());
+                }
+            }
+        }
+        assert_eq!(Some(vec![hash]), served);
+    }
+
+    #[test]
+    fn parcels_beyond_request_limit_are_not_served() {
+        let client = Arc::new(TestBlockChainClient::new());
+        let hash = client.insert_parcel_to_pool();
+        let extension = Extension::new(client.clone());
+        let mut network = TestNetworkClient::new();
+        network.register_extension(extension.clone());
+
+        let peer: NodeId = SocketAddr::v4(127, 0, 0, 1, 3485).into();
+        extension.on_node_added(&peer, 0);
+        let mut hashes: Vec<_> = (0..MAX_REQUESTED_PARCELS).map(|_| H256::random()).collect();
+        hashes.push(hash);
+        extension.on_message(&peer, &Message::GetParcels(hashes).rlp_bytes());
+
+        let mut served = None;
+        while let Some(call) = network.pop_call("parcel-propagation") {
+            if let TestNetworkCall::Send(_, bytes) = call {
+                if let Ok(Message::Parcels(parcels)) = UntrustedRlp::new(&bytes).as_val() {
+                    served = Some(parcels.len());
+                }
+            }
+        }
+        assert_eq!(Some(0), served);
+    }
+}
@@ -15,33 +15,73 @@
 // along with this program.  If not, see <https://www.gnu.org/licenses/>.
 
 use ccore::UnverifiedParcel;
+use primitives::H256;
 use rlp::{Decodable, DecoderError, Encodable, RlpStream, UntrustedRlp};
 
+const MESSAGE_ID_GET_PARCELS: u8 = 0x02;
+
 #[derive(Debug, PartialEq)]
 pub enum Message {
     Parcels(Vec<UnverifiedParcel>),
+    /// Asks for the parcels in the peer's pool, which are sent back in `Parcels`
+    GetParcels(Vec<H256>),
 }
 
 impl Encodable for Message {
     fn rlp_append(&self, s: &mut RlpStream) {
         match self {
-            &Message::Parcels(ref parcels) => s.append_list(parcels),
+            // Parcels are sent as a bare list as before, so older versions can decode them
+            &Message::Parcels(ref parcels) => {
+                s.append_list(parcels);
+            }
+            &Message::GetParcels(ref hashes) => {
+                s.begin_list(2);
+                s.append(&MESSAGE_ID_GET_PARCELS);
+                s.append_list(hashes);
+            }
         };
     }
 }
 
 impl Decodable for Message {
     fn decode(rlp: &UntrustedRlp) -> Result<Self, DecoderError> {
-        Ok(Message::Parcels(rlp.as_list()?))
+        // Parcels are lists, so only the other messages start with a message id
+        let is_parcels = rlp.item_count()? == 0 || rlp.at(0)?.is_list();
+        if is_parcels {
+            return Ok(Message::Parcels(rlp.as_list()?))
+        }
+        if rlp.item_count()? != 2 {
+            return Err(DecoderError::RlpIncorrectListLen)
+        }
+        match rlp.val_at(0)? {
+            MESSAGE_ID_GET_PARCELS => Ok(Message::GetParcels(rlp.list_at(1)?)),
+            _ => Err(DecoderError::Custom("Unknown message id detected")),
+        }
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use ccore::UnverifiedParcel;
+    use primitives::H256;
+    use rlp::{self, Encodable};
+
     use super::Message;
 
     #[test]
     fn parcels_message_rlp() {
         rlp_encode_and_decode_test!(Message::Parcels(Vec::new()));
     }
+
+    #[test]
+    fn parcels_message_is_encoded_as_before() {
+        let encoded = Message::Parcels(Vec::new()).rlp_bytes();
+        let legacy: Vec<UnverifiedParcel> = Vec::new();
+        assert_eq!(rlp::encode_list::<UnverifiedParcel, _>(&legacy).to_vec(), encoded.to_vec());
+    }
+
+    #[test]
+    fn get_parcels_message_rlp() {
+        rlp_encode_and_decode_test!(Message::GetParcels(vec![H256::random(), H256::random()]));
+    }
 }